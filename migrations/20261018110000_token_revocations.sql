@@ -0,0 +1,16 @@
+-- Denylist for access tokens. Single tokens are revoked by jti, whole users
+-- by a cut-off timestamp compared against the time the token was issued.
+
+CREATE TABLE IF NOT EXISTS revoked_access_tokens (
+  jti TEXT PRIMARY KEY,
+  user_id TEXT,
+  expires_at TIMESTAMPTZ NOT NULL,
+  revoked_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS revoked_access_tokens_expires_at_idx ON revoked_access_tokens (expires_at);
+
+CREATE TABLE IF NOT EXISTS user_token_revocations (
+  user_id TEXT PRIMARY KEY,
+  revoked_before TIMESTAMPTZ NOT NULL
+);
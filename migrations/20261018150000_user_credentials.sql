@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS user_credentials (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- base64url, as sent by the authenticator
+  credential_id TEXT UNIQUE NOT NULL,
+  name TEXT NOT NULL,
+  -- serialized passkey: public key, algorithm, flags
+  passkey TEXT NOT NULL,
+  sign_count BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS user_credentials_user_id_idx ON user_credentials (user_id);
+
+-- server side state of a ceremony in progress, consumed by its finish step
+CREATE TABLE IF NOT EXISTS webauthn_challenges (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  ceremony TEXT NOT NULL,
+  state TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS webauthn_challenges_user_id_idx ON webauthn_challenges (user_id);
@@ -0,0 +1,43 @@
+CREATE TYPE "oauth_client_type" AS ENUM ('confidential', 'public');
+
+CREATE TABLE IF NOT EXISTS oauth_clients (
+  -- the client_id
+  id TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  client_type oauth_client_type NOT NULL,
+  -- confidential clients only
+  secret_hash TEXT,
+  redirect_uris TEXT[] NOT NULL DEFAULT '{}',
+  scopes TEXT[] NOT NULL DEFAULT '{}',
+  grant_types TEXT[] NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS oauth_authorization_codes (
+  id TEXT PRIMARY KEY,
+  code_hash TEXT UNIQUE NOT NULL,
+  client_id TEXT NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+  user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  code_challenge TEXT,
+  -- refresh tokens obtained with the code, revoked if the code is replayed
+  refresh_family_id TEXT,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  used_at TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS oauth_consents (
+  user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  client_id TEXT NOT NULL REFERENCES oauth_clients(id) ON DELETE CASCADE,
+  scopes TEXT[] NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, client_id)
+);
+
+-- refresh tokens handed to a client instead of our own frontend
+ALTER TABLE refresh_tokens
+  ADD COLUMN IF NOT EXISTS client_id TEXT REFERENCES oauth_clients(id) ON DELETE CASCADE,
+  ADD COLUMN IF NOT EXISTS scope TEXT;
@@ -0,0 +1,7 @@
+-- when the user actually signed in, carried over on every rotation of the family
+ALTER TABLE refresh_tokens
+  ADD COLUMN IF NOT EXISTS auth_time TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+ALTER TABLE oauth_authorization_codes
+  ADD COLUMN IF NOT EXISTS nonce TEXT,
+  ADD COLUMN IF NOT EXISTS auth_time TIMESTAMPTZ NOT NULL DEFAULT NOW();
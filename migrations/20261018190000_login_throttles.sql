@@ -0,0 +1,11 @@
+-- failed logins, counted per account and per client ip
+CREATE TABLE IF NOT EXISTS login_throttles (
+  -- 'account' or 'ip'
+  kind TEXT NOT NULL,
+  -- user id, or the submitted identifier when no such user exists
+  key TEXT NOT NULL,
+  failures INTEGER NOT NULL DEFAULT 0,
+  last_failure_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  blocked_until TIMESTAMPTZ,
+  PRIMARY KEY (kind, key)
+);
@@ -0,0 +1,11 @@
+-- token buckets of the postgres rate limit store, shared by every instance
+CREATE TABLE IF NOT EXISTS rate_limit_buckets (
+  -- limit name and the ip, user id or custom key it's counted for
+  key TEXT PRIMARY KEY,
+  tokens DOUBLE PRECISION NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  -- past this the bucket is full again and can be dropped
+  full_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS rate_limit_buckets_full_at_idx ON rate_limit_buckets (full_at);
@@ -0,0 +1,42 @@
+-- roles, named like the values of user_role for the role every user has
+CREATE TABLE IF NOT EXISTS roles (
+  name TEXT PRIMARY KEY,
+  description TEXT NOT NULL DEFAULT '',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- `<resource>:<action>`, checked by RequirePermission
+CREATE TABLE IF NOT EXISTS permissions (
+  name TEXT PRIMARY KEY,
+  description TEXT NOT NULL DEFAULT '',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+  role TEXT NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+  permission TEXT NOT NULL REFERENCES permissions (name) ON DELETE CASCADE,
+  PRIMARY KEY (role, permission)
+);
+
+-- roles held on top of users.role
+CREATE TABLE IF NOT EXISTS user_roles (
+  user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  role TEXT NOT NULL REFERENCES roles (name) ON DELETE CASCADE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, role)
+);
+
+INSERT INTO roles (name, description) VALUES
+  ('USER', 'Every account'),
+  ('ADMIN', 'Administrators')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO permissions (name, description) VALUES
+  ('tokens:revoke', 'Revoke access tokens and sessions of any user'),
+  ('users:unlock', 'Lift login lockouts'),
+  ('oauth_clients:manage', 'Register, list and delete OAuth clients')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role, permission)
+SELECT 'ADMIN', name FROM permissions
+ON CONFLICT (role, permission) DO NOTHING;
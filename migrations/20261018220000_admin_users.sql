@@ -0,0 +1,30 @@
+ALTER TABLE users
+  -- set while an admin keeps the account from logging in
+  ADD COLUMN IF NOT EXISTS suspended_at TIMESTAMPTZ,
+  -- NULL while suspended means until lifted by an admin
+  ADD COLUMN IF NOT EXISTS suspended_until TIMESTAMPTZ;
+
+-- who did what to whom, written by every admin action
+CREATE TABLE IF NOT EXISTS admin_actions (
+  id TEXT PRIMARY KEY,
+  admin_id TEXT NOT NULL,
+  -- user the action was about, kept after the user is deleted
+  target_id TEXT,
+  action TEXT NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  metadata JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS admin_actions_target_id_idx ON admin_actions (target_id, created_at);
+
+INSERT INTO permissions (name, description) VALUES
+  ('users:read', 'List and view any user'),
+  ('users:manage', 'Change roles, suspend, delete and force password resets')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role, permission) VALUES
+  ('ADMIN', 'users:read'),
+  ('ADMIN', 'users:manage')
+ON CONFLICT (role, permission) DO NOTHING;
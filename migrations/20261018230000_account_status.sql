@@ -0,0 +1,15 @@
+CREATE TYPE "account_status" AS ENUM (
+  'ACTIVE',
+  -- until `suspended_until`, or until lifted when it's not set
+  'SUSPENDED',
+  'BANNED',
+  -- signed up, but can't log in before verifying the email address
+  'PENDING_VERIFICATION'
+);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS status account_status NOT NULL DEFAULT 'ACTIVE';
+
+-- suspensions were told by suspended_at alone so far
+UPDATE users SET status = 'SUSPENDED' WHERE suspended_at IS NOT NULL;
+UPDATE users SET suspended_until = NULL WHERE status = 'ACTIVE';
+ALTER TABLE users DROP COLUMN IF EXISTS suspended_at;
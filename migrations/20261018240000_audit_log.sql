@@ -0,0 +1,52 @@
+-- who did what to whom, from logins to admin actions
+CREATE TABLE IF NOT EXISTS audit_events (
+  id TEXT PRIMARY KEY,
+  -- user id of whoever acted, NULL for anonymous requests
+  actor_id TEXT,
+  -- user the event was about, kept after the user is deleted
+  target_id TEXT,
+  event_type TEXT NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  -- 'success' or 'failure', or 'pending' for a password login
+  -- that still waits for its second factor
+  outcome TEXT NOT NULL,
+  metadata JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- admin actions were kept on their own so far
+INSERT INTO audit_events
+  (id, actor_id, target_id, event_type, ip, user_agent, outcome, metadata, created_at)
+SELECT id, admin_id, target_id, action, ip, user_agent, 'success', metadata, created_at
+FROM admin_actions;
+
+DROP TABLE IF EXISTS admin_actions;
+
+-- the audit log is append-only, rows can't be changed or removed once written
+CREATE OR REPLACE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_change
+  BEFORE UPDATE OR DELETE ON audit_events
+  FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
+
+CREATE TRIGGER audit_events_no_truncate
+  BEFORE TRUNCATE ON audit_events
+  FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
+
+CREATE INDEX IF NOT EXISTS audit_events_target_id_idx ON audit_events (target_id, created_at);
+CREATE INDEX IF NOT EXISTS audit_events_actor_id_idx ON audit_events (actor_id, created_at);
+CREATE INDEX IF NOT EXISTS audit_events_event_type_idx ON audit_events (event_type, created_at);
+CREATE INDEX IF NOT EXISTS audit_events_created_at_idx ON audit_events (created_at);
+
+INSERT INTO permissions (name, description) VALUES
+  ('audit:read', 'Search the audit log')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role, permission) VALUES
+  ('ADMIN', 'audit:read')
+ON CONFLICT (role, permission) DO NOTHING;
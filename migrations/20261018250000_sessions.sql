@@ -0,0 +1,16 @@
+-- A sign in of a user on one device. Its refresh tokens are the family
+-- with the same id, its access tokens carry the id as `sid`
+CREATE TABLE IF NOT EXISTS sessions (
+  id TEXT PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  user_agent TEXT,
+  ip TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  -- last refresh, or the sign in itself
+  last_used_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  -- access tokens of the session are refused from then on
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions (user_id);
+CREATE INDEX IF NOT EXISTS sessions_revoked_at_idx ON sessions (revoked_at);
@@ -0,0 +1,9 @@
+-- profile of the user, every field optional
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS display_name TEXT,
+  ADD COLUMN IF NOT EXISTS avatar_url TEXT,
+  ADD COLUMN IF NOT EXISTS bio TEXT,
+  -- BCP 47 tag like `en-US`
+  ADD COLUMN IF NOT EXISTS locale TEXT,
+  -- IANA name like `Europe/Berlin`
+  ADD COLUMN IF NOT EXISTS timezone TEXT;
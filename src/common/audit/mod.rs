@@ -0,0 +1,228 @@
+use actix_web::{HttpRequest, http::header};
+use chrono::{DateTime, Utc};
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::common::errors::api_error::ApiError;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Success,
+    Failure,
+    // a step that needs another one to succeed, like a password before the second factor
+    Pending,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Pending => "pending",
+        }
+    }
+}
+
+pub struct AuditEvent<'a> {
+    pub event_type: &'a str,
+    // None for anonymous requests
+    pub actor_id: Option<&'a str>,
+    pub target_id: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub outcome: Outcome,
+    pub metadata: serde_json::Value,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct AuditRecord {
+    pub id: String,
+    pub actor_id: Option<String>,
+    pub target_id: Option<String>,
+    pub event_type: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    pub metadata: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct AuditFilter {
+    // actor or target
+    pub user_id: Option<String>,
+    pub event_type: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+pub fn user_agent(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+}
+
+async fn insert(executor: impl PgExecutor<'_>, event: &AuditEvent<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "
+			INSERT INTO audit_events
+				(id, actor_id, target_id, event_type, ip, user_agent, outcome, metadata)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(event.actor_id)
+    .bind(event.target_id)
+    .bind(event.event_type)
+    .bind(event.ip)
+    .bind(event.user_agent)
+    .bind(event.outcome.as_str())
+    .bind(&event.metadata)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+// Appends to `audit_events`, which refuses updates and deletes
+pub struct AuditLogger {
+    pool: PgPool,
+}
+
+impl AuditLogger {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // the audited action already happened, failing to write it down shouldn't fail the request
+    pub async fn record(&self, event: AuditEvent<'_>) {
+        if let Err(e) = insert(&self.pool, &event).await {
+            eprintln!("[AUDIT]: cannot record {}: {e}", event.event_type);
+        }
+    }
+
+    // written in the transaction of the change it records, both or neither are kept
+    pub async fn record_in(
+        &self,
+        executor: impl PgExecutor<'_>,
+        event: AuditEvent<'_>,
+    ) -> Result<(), ApiError> {
+        insert(executor, &event).await?;
+        Ok(())
+    }
+
+    // newest first, with the total count of matching events
+    pub async fn search(
+        &self,
+        filter: &AuditFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<AuditRecord>, i64), ApiError> {
+        let filters = "
+			WHERE ($1::text IS NULL OR actor_id = $1 OR target_id = $1)
+				AND ($2::text IS NULL OR event_type = $2)
+				AND ($3::timestamptz IS NULL OR created_at >= $3)
+				AND ($4::timestamptz IS NULL OR created_at < $4)
+		";
+
+        let total =
+            sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM audit_events {filters}"))
+                .bind(&filter.user_id)
+                .bind(&filter.event_type)
+                .bind(filter.from)
+                .bind(filter.to)
+                .fetch_one(&self.pool)
+                .await?;
+
+        let events = sqlx::query_as::<_, AuditRecord>(&format!(
+            "
+				SELECT id, actor_id, target_id, event_type, ip, user_agent, outcome, metadata, created_at
+				FROM audit_events
+				{filters}
+				ORDER BY created_at DESC, id
+				LIMIT $5 OFFSET $6
+			"
+        ))
+        .bind(&filter.user_id)
+        .bind(&filter.event_type)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok((events, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    async fn recorded(pool: &PgPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM audit_events")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn recorded_events_cannot_be_changed_or_removed(pool: PgPool) {
+        let audit = AuditLogger::new(pool.clone());
+        audit
+            .record(AuditEvent {
+                event_type: "auth.login",
+                actor_id: Some("user"),
+                target_id: Some("user"),
+                ip: Some("127.0.0.1"),
+                user_agent: None,
+                outcome: Outcome::Success,
+                metadata: json!({}),
+            })
+            .await;
+        assert_eq!(recorded(&pool).await, 1);
+
+        for statement in [
+            "UPDATE audit_events SET outcome = 'failure'",
+            "DELETE FROM audit_events",
+            "TRUNCATE audit_events",
+        ] {
+            let error = sqlx::query(statement).execute(&pool).await.unwrap_err();
+            assert!(error.to_string().contains("append-only"), "{statement}");
+        }
+
+        let outcome = sqlx::query_scalar::<_, String>("SELECT outcome FROM audit_events")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(outcome, "success");
+    }
+
+    #[sqlx::test]
+    async fn event_of_a_rolled_back_change_is_not_kept(pool: PgPool) {
+        let audit = AuditLogger::new(pool.clone());
+
+        let mut tx = pool.begin().await.unwrap();
+        audit
+            .record_in(
+                &mut *tx,
+                AuditEvent {
+                    event_type: "admin.user.deleted",
+                    actor_id: Some("admin"),
+                    target_id: Some("user"),
+                    ip: None,
+                    user_agent: None,
+                    outcome: Outcome::Success,
+                    metadata: json!({}),
+                },
+            )
+            .await
+            .unwrap();
+        tx.rollback().await.unwrap();
+
+        assert_eq!(recorded(&pool).await, 0);
+    }
+}
@@ -3,7 +3,7 @@ use sqlx::{PgPool, migrate};
 pub async fn create_db_pool(database_url: &str) -> PgPool {
     PgPool::connect(database_url)
         .await
-        .expect(&"Cannot connect to database {database_url}".to_owned())
+        .unwrap_or_else(|e| panic!("Cannot connect to database {database_url}: {e}"))
 }
 
 pub async fn run_migrations(pool: &PgPool) {
@@ -1,4 +1,7 @@
-use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use actix_web::{
+    HttpResponse, ResponseError,
+    http::{StatusCode, header},
+};
 use sqlx::postgres::PgDatabaseError;
 use std::{collections::HashMap, fmt};
 use validator::ValidationErrors;
@@ -16,7 +19,12 @@ pub enum ApiError {
     UniqueViolation { field: String },
     NotFound(String),
     Unauthorized(String),
+    // unknown user or wrong password, told apart nowhere so accounts can't be enumerated
+    InvalidCredentials,
+    Forbidden(String),
     Validation(ValidationErrors), // errors
+    // seconds until the client may try again
+    TooManyRequests { message: String, retry_after: u64 },
     InternalServer(String),
     Other(String),
 }
@@ -28,7 +36,7 @@ fn extract_field_from_constraint(constraint: &str) -> String {
         return captures[1].to_string();
     }
 
-    return "field".to_string();
+    "field".to_string()
 }
 
 impl fmt::Display for ApiError {
@@ -40,7 +48,10 @@ impl fmt::Display for ApiError {
             }
             ApiError::NotFound(msg) => write!(f, "{}", msg),
             ApiError::Unauthorized(msg) => write!(f, "{}", msg),
+            ApiError::InvalidCredentials => write!(f, "Invalid username, email or password"),
+            ApiError::Forbidden(msg) => write!(f, "{}", msg),
             ApiError::Validation(_) => write!(f, "Validation Error"),
+            ApiError::TooManyRequests { message, .. } => write!(f, "{}", message),
 
             // DATABASE / BACKEND ERRORS
             ApiError::InternalServer(e) => write!(f, "Database error: {e}"),
@@ -54,8 +65,10 @@ impl ResponseError for ApiError {
         match self {
             ApiError::UniqueViolation { .. } => StatusCode::CONFLICT,
             ApiError::NotFound(_) => StatusCode::NOT_FOUND,
-            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Unauthorized(_) | ApiError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
             ApiError::Validation(..) => StatusCode::BAD_REQUEST,
+            ApiError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
 
             ApiError::InternalServer(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::Other(_) => StatusCode::BAD_REQUEST,
@@ -70,7 +83,7 @@ impl ResponseError for ApiError {
 
                 for (field, field_errors) in errors.field_errors() {
                     let messages: Vec<String> = field_errors
-                        .into_iter()
+                        .iter()
                         .map(|e| {
                             e.message
                                 .as_ref()
@@ -93,7 +106,12 @@ impl ResponseError for ApiError {
             details,
         };
 
-        HttpResponse::build(status).json(body)
+        let mut response = HttpResponse::build(status);
+        if let ApiError::TooManyRequests { retry_after, .. } = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response.json(body)
     }
 }
 
@@ -108,11 +126,11 @@ impl From<sqlx::Error> for ApiError {
         let code = pg_err.code();
 
         // check for unique violation
-        if code == "23505" {
-            if let Some(constraint) = pg_err.constraint() {
-                let field = extract_field_from_constraint(constraint);
-                return ApiError::UniqueViolation { field };
-            }
+        if code == "23505"
+            && let Some(constraint) = pg_err.constraint()
+        {
+            let field = extract_field_from_constraint(constraint);
+            return ApiError::UniqueViolation { field };
         }
 
         ApiError::InternalServer(e.to_string())
@@ -0,0 +1,128 @@
+use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+use futures_util::future::BoxFuture;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::Mailbox,
+    transport::smtp::authentication::Credentials,
+};
+
+use crate::common::config::{MailConfig, MailTransport};
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), String>>;
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Result<Self, String> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|e| format!("Invalid SMTP host {host}: {e}"))?
+            .port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from
+                .parse()
+                .map_err(|e| format!("Invalid MAIL_FROM {from}: {e}"))?,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let to: Mailbox = email
+                .to
+                .parse()
+                .map_err(|e| format!("Invalid recipient {}: {e}", email.to))?;
+
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(email.subject)
+                .body(email.body)
+                .map_err(|e| e.to_string())?;
+
+            self.transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+// Writes emails to a file, or to stdout when no file is given.
+// Meant for development and tests, where links are read from the output.
+pub struct LogMailer {
+    path: Option<PathBuf>,
+}
+
+impl LogMailer {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path }
+    }
+}
+
+impl Mailer for LogMailer {
+    fn send(&self, email: Email) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let entry = format!(
+                "To: {}\nSubject: {}\n\n{}\n---\n",
+                email.to, email.subject, email.body
+            );
+
+            match &self.path {
+                Some(path) => OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(entry.as_bytes()))
+                    .map_err(|e| format!("Cannot write email to {}: {e}", path.display())),
+                None => {
+                    println!("[MAILER]: {entry}");
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+pub fn create_mailer(config: &MailConfig) -> Result<Box<dyn Mailer>, String> {
+    match &config.transport {
+        MailTransport::Log { path } => Ok(Box::new(LogMailer::new(path.clone()))),
+        MailTransport::Smtp {
+            host,
+            port,
+            username,
+            password,
+        } => {
+            let credentials = username.clone().zip(password.clone());
+            Ok(Box::new(SmtpMailer::new(
+                host,
+                *port,
+                credentials,
+                &config.from,
+            )?))
+        }
+    }
+}
@@ -1,11 +1,41 @@
 
 
 use sqlx::PgPool;
+use webauthn_rs::Webauthn;
 
+use crate::{
+	common::{
+		audit::AuditLogger, config::Config, mailer::Mailer, password_hasher::PasswordHashers,
+		rate_limit::RateLimitStore,
+	},
+	entities::auth::{
+		account_status::AccountStatusCache, keys::KeyStore, revocation::RevocationList,
+		social::providers::OAuthProviders,
+	},
+};
+
+pub mod audit;
+pub mod config;
 pub mod database;
 pub mod errors;
+pub mod mailer;
+pub mod password_hasher;
+pub mod rate_limit;
+pub mod request;
+#[cfg(test)]
+pub mod testing;
+pub mod tokens;
 
 pub struct AppState {
 	pub pool: PgPool,
-	pub is_production: bool,
+	pub config: Config,
+	pub keys: KeyStore,
+	pub revocations: RevocationList,
+	pub mailer: Box<dyn Mailer>,
+	pub webauthn: Webauthn,
+	pub oauth: OAuthProviders,
+	pub rate_limits: Box<dyn RateLimitStore>,
+	pub passwords: PasswordHashers,
+	pub account_statuses: AccountStatusCache,
+	pub audit: AuditLogger,
 }
\ No newline at end of file
@@ -0,0 +1,373 @@
+use std::sync::Arc;
+
+use actix_web::web;
+use argon2::{
+    Algorithm, Argon2, Params, Version,
+    password_hash::{self, PasswordHash, PasswordVerifier, SaltString},
+};
+use bcrypt::HashParts;
+use rand::rngs::OsRng;
+
+use crate::common::{
+    config::{PasswordHashAlgorithm, PasswordHashingConfig},
+    errors::api_error::ApiError,
+};
+
+pub trait PasswordHasher: Send + Sync {
+    fn algorithm(&self) -> PasswordHashAlgorithm;
+    // whether the stored hash was made by this algorithm
+    fn handles(&self, hash: &str) -> bool;
+    // PHC string of the password
+    fn hash(&self, password: &str) -> Result<String, String>;
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, String>;
+    // made by this algorithm, but with other parameters or in an older format
+    fn is_outdated(&self, hash: &str) -> bool;
+}
+
+// PHC strings `$bcrypt$v=98$r=<cost>$<salt>$<checksum>`, with the salt and checksum
+// in bcrypt's own base64. Hashes from before PHC strings are in `$2b$<cost>$...`
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+const BCRYPT_PREFIX: &str = "$bcrypt$v=98$r=";
+const BCRYPT_CHECKSUM_LENGTH: usize = 31;
+
+impl BcryptHasher {
+    pub fn new(cost: u32) -> Self {
+        Self { cost }
+    }
+
+    // the format the bcrypt crate reads
+    fn to_modular(hash: &str) -> Option<String> {
+        let Some(rest) = hash.strip_prefix(BCRYPT_PREFIX) else {
+            return Some(hash.to_string());
+        };
+
+        let mut parts = rest.split('$');
+        let (cost, salt, checksum) = (parts.next()?, parts.next()?, parts.next()?);
+        let cost: u32 = cost.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(format!("$2b${cost:02}${salt}{checksum}"))
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn algorithm(&self) -> PasswordHashAlgorithm {
+        PasswordHashAlgorithm::Bcrypt
+    }
+
+    fn handles(&self, hash: &str) -> bool {
+        hash.starts_with(BCRYPT_PREFIX) || hash.starts_with("$2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, String> {
+        let parts = bcrypt::hash_with_result(password, self.cost).map_err(|e| e.to_string())?;
+        let modular = parts.to_string();
+        let checksum = &modular[modular.len() - BCRYPT_CHECKSUM_LENGTH..];
+
+        Ok(format!(
+            "{BCRYPT_PREFIX}{}${}${checksum}",
+            parts.get_cost(),
+            parts.get_salt()
+        ))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, String> {
+        let modular = Self::to_modular(hash).ok_or("Unreadable bcrypt hash")?;
+        bcrypt::verify(password, &modular).map_err(|e| e.to_string())
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        if !hash.starts_with(BCRYPT_PREFIX) {
+            return true;
+        }
+
+        Self::to_modular(hash)
+            .and_then(|modular| modular.parse::<HashParts>().ok())
+            .is_none_or(|parts| parts.get_cost() != self.cost)
+    }
+}
+
+pub struct Argon2Hasher {
+    params: Params,
+}
+
+impl Argon2Hasher {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, String> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|e| format!("Invalid argon2 parameters: {e}"))?;
+
+        Ok(Self { params })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+}
+
+impl PasswordHasher for Argon2Hasher {
+    fn algorithm(&self) -> PasswordHashAlgorithm {
+        PasswordHashAlgorithm::Argon2id
+    }
+
+    fn handles(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        password_hash::PasswordHasher::hash_password(&self.argon2(), password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    // the parameters are taken from the hash, not from our own
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, String> {
+        let hash = PasswordHash::new(hash).map_err(|e| e.to_string())?;
+
+        match self.argon2().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || Params::try_from(&hash).map_or(true, |params| {
+                (params.m_cost(), params.t_cost(), params.p_cost())
+                    != (
+                        self.params.m_cost(),
+                        self.params.t_cost(),
+                        self.params.p_cost(),
+                    )
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    // valid, but should be hashed again with the current algorithm
+    Outdated,
+}
+
+#[derive(Clone)]
+struct KnownHasher {
+    hasher: Arc<dyn PasswordHasher>,
+    // hash of no user's password, made with the hasher's own parameters
+    dummy: Arc<str>,
+}
+
+impl KnownHasher {
+    fn new(hasher: Arc<dyn PasswordHasher>) -> Result<Self, String> {
+        let dummy = hasher.hash("dummy password of no user")?.into();
+        Ok(Self { hasher, dummy })
+    }
+}
+
+// Hashes with the configured algorithm and verifies with whichever made the hash.
+// The work runs on the blocking pool, it takes long enough to stall a worker
+#[derive(Clone)]
+pub struct PasswordHashers {
+    current: KnownHasher,
+    others: Vec<KnownHasher>,
+    // users that don't exist are verified against the dummy of this algorithm,
+    // the one stored hashes are in, so they take as long as real ones
+    stored_algorithm: PasswordHashAlgorithm,
+}
+
+impl PasswordHashers {
+    pub fn new(
+        current: Arc<dyn PasswordHasher>,
+        others: Vec<Arc<dyn PasswordHasher>>,
+        stored_algorithm: PasswordHashAlgorithm,
+    ) -> Result<Self, String> {
+        let hashers = Self {
+            current: KnownHasher::new(current)?,
+            others: others
+                .into_iter()
+                .map(KnownHasher::new)
+                .collect::<Result<_, _>>()?,
+            stored_algorithm,
+        };
+        if hashers.stored().is_none() {
+            return Err(format!("No hasher for {stored_algorithm:?} is configured"));
+        }
+
+        Ok(hashers)
+    }
+
+    fn stored(&self) -> Option<&KnownHasher> {
+        std::iter::once(&self.current)
+            .chain(&self.others)
+            .find(|known| known.hasher.algorithm() == self.stored_algorithm)
+    }
+
+    fn verify_now(&self, password: &str, hash: &str) -> Result<Verification, String> {
+        let (hasher, is_current) = if self.current.hasher.handles(hash) {
+            (&self.current.hasher, true)
+        } else {
+            let known = self
+                .others
+                .iter()
+                .find(|known| known.hasher.handles(hash))
+                .ok_or("Unknown password hash format")?;
+            (&known.hasher, false)
+        };
+
+        Ok(match hasher.verify(password, hash)? {
+            false => Verification::Invalid,
+            true if !is_current || hasher.is_outdated(hash) => Verification::Outdated,
+            true => Verification::Valid,
+        })
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, ApiError> {
+        let (hashers, password) = (self.clone(), password.to_owned());
+        blocking(move || hashers.current.hasher.hash(&password)).await
+    }
+
+    pub async fn verify(&self, password: &str, hash: &str) -> Result<Verification, ApiError> {
+        let (hashers, password, hash) = (self.clone(), password.to_owned(), hash.to_owned());
+        blocking(move || hashers.verify_now(&password, &hash)).await
+    }
+
+    pub async fn verify_dummy(&self, password: &str) {
+        let Some(stored) = self.stored().cloned() else {
+            return;
+        };
+
+        let password = password.to_owned();
+        let _ = blocking(move || stored.hasher.verify(&password, &stored.dummy)).await;
+    }
+}
+
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, ApiError> {
+    web::block(work)
+        .await
+        .map_err(|e| ApiError::InternalServer(e.to_string()))?
+        .map_err(|e| ApiError::InternalServer(format!("Password hashing failed: {e}")))
+}
+
+pub fn create_password_hashers(config: &PasswordHashingConfig) -> Result<PasswordHashers, String> {
+    let bcrypt: Arc<dyn PasswordHasher> = Arc::new(BcryptHasher::new(config.bcrypt_cost));
+    let argon2: Arc<dyn PasswordHasher> = Arc::new(Argon2Hasher::new(
+        config.argon2_memory_kib,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+    )?);
+
+    let (current, other) = match config.algorithm {
+        PasswordHashAlgorithm::Bcrypt => (bcrypt, argon2),
+        PasswordHashAlgorithm::Argon2id => (argon2, bcrypt),
+    };
+    PasswordHashers::new(current, vec![other], config.stored_algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::common::config::PasswordHashingConfig;
+
+    fn config(algorithm: PasswordHashAlgorithm) -> PasswordHashingConfig {
+        PasswordHashingConfig {
+            algorithm,
+            stored_algorithm: algorithm,
+            bcrypt_cost: 4,
+            argon2_memory_kib: 64,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+        }
+    }
+
+    // notes every hash it verifies against, in the bcrypt format of the time
+    struct Recording {
+        inner: BcryptHasher,
+        verified: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PasswordHasher for Recording {
+        fn algorithm(&self) -> PasswordHashAlgorithm {
+            self.inner.algorithm()
+        }
+
+        fn handles(&self, hash: &str) -> bool {
+            self.inner.handles(hash)
+        }
+
+        fn hash(&self, password: &str) -> Result<String, String> {
+            self.inner.hash(password)
+        }
+
+        fn verify(&self, password: &str, hash: &str) -> Result<bool, String> {
+            let modular = BcryptHasher::to_modular(hash).ok_or("Invalid bcrypt hash")?;
+            self.verified.lock().unwrap().push(modular);
+            self.inner.verify(password, hash)
+        }
+
+        fn is_outdated(&self, hash: &str) -> bool {
+            self.inner.is_outdated(hash)
+        }
+    }
+
+    // unknown users have to cost as much as real ones
+    #[test]
+    fn unknown_user_is_verified_at_the_cost_of_real_passwords() {
+        for algorithm in [
+            PasswordHashAlgorithm::Bcrypt,
+            PasswordHashAlgorithm::Argon2id,
+        ] {
+            let hashers = create_password_hashers(&config(algorithm)).unwrap();
+            let stored = hashers.stored().unwrap();
+            let real = stored.hasher.hash("secret12").unwrap();
+
+            assert_eq!(stored.hasher.algorithm(), algorithm);
+            assert!(stored.hasher.handles(&stored.dummy));
+            assert!(!stored.hasher.is_outdated(&stored.dummy));
+            assert!(!stored.hasher.is_outdated(&real));
+        }
+    }
+
+    #[actix_web::test]
+    async fn unknown_user_costs_as_much_as_a_legacy_bcrypt_user() {
+        let verified = Arc::new(Mutex::new(Vec::new()));
+        let bcrypt = Arc::new(Recording {
+            inner: BcryptHasher::new(4),
+            verified: verified.clone(),
+        });
+        let argon2 = Argon2Hasher::new(64, 1, 1).unwrap();
+        let hashers = PasswordHashers::new(
+            Arc::new(argon2),
+            vec![bcrypt],
+            PasswordHashAlgorithm::Bcrypt,
+        )
+        .unwrap();
+        let legacy = bcrypt::hash("secret12", 4).unwrap();
+
+        hashers.verify("secret13", &legacy).await.unwrap();
+        hashers.verify_dummy("secret13").await;
+
+        // one bcrypt verification each, with the same version and cost
+        let verified = verified.lock().unwrap();
+        assert_eq!(verified.len(), 2);
+        assert_eq!(verified[0][..7], legacy[..7]);
+        assert_eq!(verified[1][..7], legacy[..7]);
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use sqlx::PgPool;
+
+use crate::common::config::RateLimitStoreKind;
+
+// stale buckets are swept every this many requests
+const SWEEP_EVERY: u64 = 1000;
+
+// `capacity` requests, refilled evenly over `period` seconds
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: u64,
+}
+
+impl Limit {
+    fn refill_rate(&self) -> f64 {
+        f64::from(self.capacity) / self.period as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // seconds until the bucket is full again
+    pub reset_after: u64,
+    // seconds until the next request is allowed, 0 when allowed
+    pub retry_after: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: DateTime<Utc>,
+}
+
+impl Bucket {
+    fn full(limit: Limit, now: DateTime<Utc>) -> Self {
+        Self {
+            tokens: f64::from(limit.capacity),
+            updated_at: now,
+        }
+    }
+
+    fn refilled(&self, limit: Limit, now: DateTime<Utc>) -> f64 {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        (self.tokens + elapsed * limit.refill_rate()).min(f64::from(limit.capacity))
+    }
+
+    // refills for the time passed, then takes a token if there is one
+    fn take(&mut self, limit: Limit, now: DateTime<Utc>) -> Decision {
+        let tokens = self.refilled(limit, now);
+        let allowed = tokens >= 1.0;
+
+        self.tokens = if allowed { tokens - 1.0 } else { tokens };
+        self.updated_at = now;
+
+        let rate = limit.refill_rate();
+        Decision {
+            allowed,
+            limit: limit.capacity,
+            remaining: self.tokens.floor() as u32,
+            reset_after: ((f64::from(limit.capacity) - self.tokens) / rate).ceil() as u64,
+            retry_after: if allowed {
+                0
+            } else {
+                ((1.0 - self.tokens) / rate).ceil() as u64
+            },
+        }
+    }
+}
+
+pub trait RateLimitStore: Send + Sync {
+    fn take(&self, key: String, limit: Limit) -> BoxFuture<'_, Result<Decision, String>>;
+}
+
+// Buckets of this process only, every instance limits on its own
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, (Bucket, Limit)>>,
+    requests: AtomicU64,
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take(&self, key: String, limit: Limit) -> BoxFuture<'_, Result<Decision, String>> {
+        Box::pin(async move {
+            let now = Utc::now();
+            let mut buckets = self
+                .buckets
+                .lock()
+                .map_err(|_| "Rate limit buckets are poisoned".to_string())?;
+
+            // a full bucket is the same as no bucket
+            if self
+                .requests
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(SWEEP_EVERY)
+            {
+                buckets.retain(|_, (bucket, limit)| {
+                    bucket.refilled(*limit, now) < f64::from(limit.capacity)
+                });
+            }
+
+            let (bucket, _) = buckets
+                .entry(key)
+                .or_insert_with(|| (Bucket::full(limit, now), limit));
+
+            Ok(bucket.take(limit, now))
+        })
+    }
+}
+
+// Buckets shared by every instance using the database,
+// timed by the database clock so instances don't have to agree on theirs
+pub struct PostgresStore {
+    pool: PgPool,
+    requests: AtomicU64,
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    async fn take_bucket(&self, key: &str, limit: Limit) -> Result<Decision, sqlx::Error> {
+        if self
+            .requests
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(SWEEP_EVERY)
+        {
+            sqlx::query("DELETE FROM rate_limit_buckets WHERE full_at < NOW()")
+                .execute(&self.pool)
+                .await?;
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "
+				INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+				VALUES ($1, $2, NOW(), NOW())
+				ON CONFLICT (key) DO NOTHING
+			",
+        )
+        .bind(key)
+        .bind(f64::from(limit.capacity))
+        .execute(&mut *tx)
+        .await?;
+
+        let (tokens, updated_at, now) = sqlx::query_as::<_, (f64, DateTime<Utc>, DateTime<Utc>)>(
+            "SELECT tokens, updated_at, NOW() FROM rate_limit_buckets WHERE key = $1 FOR UPDATE",
+        )
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let mut bucket = Bucket { tokens, updated_at };
+        let decision = bucket.take(limit, now);
+
+        sqlx::query(
+            "
+				UPDATE rate_limit_buckets
+				SET tokens = $2, updated_at = $3, full_at = $3 + make_interval(secs => $4)
+				WHERE key = $1
+			",
+        )
+        .bind(key)
+        .bind(bucket.tokens)
+        .bind(bucket.updated_at)
+        .bind(decision.reset_after as f64)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(decision)
+    }
+}
+
+impl RateLimitStore for PostgresStore {
+    fn take(&self, key: String, limit: Limit) -> BoxFuture<'_, Result<Decision, String>> {
+        Box::pin(async move {
+            self.take_bucket(&key, limit)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+pub fn create_rate_limit_store(kind: RateLimitStoreKind, pool: &PgPool) -> Box<dyn RateLimitStore> {
+    match kind {
+        RateLimitStoreKind::Memory => Box::new(MemoryStore::default()),
+        RateLimitStoreKind::Postgres => Box::new(PostgresStore::new(pool.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    // refills half a token a second, the numbers stay exact
+    const LIMIT: Limit = Limit {
+        capacity: 4,
+        period: 8,
+    };
+
+    #[test]
+    fn bucket_drains_and_refills() {
+        let start = Utc::now();
+        let mut bucket = Bucket::full(LIMIT, start);
+
+        let first = bucket.take(LIMIT, start);
+        assert!(first.allowed);
+        assert_eq!(
+            (first.remaining, first.reset_after, first.retry_after),
+            (3, 2, 0)
+        );
+
+        for _ in 0..3 {
+            assert!(bucket.take(LIMIT, start).allowed);
+        }
+        let denied = bucket.take(LIMIT, start);
+        assert!(!denied.allowed);
+        assert_eq!(
+            (denied.remaining, denied.reset_after, denied.retry_after),
+            (0, 8, 2)
+        );
+
+        // half a token is not enough, the wait is for the other half
+        let later = start + Duration::seconds(1);
+        let denied = bucket.take(LIMIT, later);
+        assert!(!denied.allowed);
+        assert_eq!((denied.reset_after, denied.retry_after), (7, 1));
+
+        assert!(bucket.take(LIMIT, later + Duration::seconds(1)).allowed);
+    }
+
+    #[test]
+    fn bucket_refills_up_to_its_capacity() {
+        let start = Utc::now();
+        let mut bucket = Bucket::full(LIMIT, start);
+        for _ in 0..4 {
+            bucket.take(LIMIT, start);
+        }
+
+        let decision = bucket.take(LIMIT, start + Duration::hours(1));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 3);
+    }
+
+    #[sqlx::test]
+    async fn postgres_buckets_are_shared_between_instances(pool: PgPool) {
+        let limit = Limit {
+            capacity: 2,
+            period: 60 * 60,
+        };
+        let first = PostgresStore::new(pool.clone());
+        let second = PostgresStore::new(pool);
+
+        assert!(
+            first
+                .take("ip:10.0.0.1".into(), limit)
+                .await
+                .unwrap()
+                .allowed
+        );
+        assert!(
+            second
+                .take("ip:10.0.0.1".into(), limit)
+                .await
+                .unwrap()
+                .allowed
+        );
+
+        let denied = first.take("ip:10.0.0.1".into(), limit).await.unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, 30 * 60);
+
+        assert!(
+            second
+                .take("ip:10.0.0.2".into(), limit)
+                .await
+                .unwrap()
+                .allowed
+        );
+    }
+}
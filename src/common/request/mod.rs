@@ -0,0 +1,16 @@
+use actix_web::HttpRequest;
+
+use crate::common::config::Config;
+
+// Address the request came from. Forwarded headers are set by the client
+// unless a proxy in front of us overwrites them, so they're opt-in
+pub fn client_ip(req: &HttpRequest, config: &Config) -> String {
+    let info = req.connection_info();
+    let address = if config.trust_proxy_headers {
+        info.realip_remote_addr()
+    } else {
+        info.peer_addr()
+    };
+
+    address.unwrap_or("unknown").to_string()
+}
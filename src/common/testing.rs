@@ -0,0 +1,176 @@
+use actix_web::{FromRequest, HttpMessage, HttpResponse, body::to_bytes, test, web};
+use chrono::Utc;
+use jsonwebtoken::Algorithm;
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        AppState,
+        audit::AuditLogger,
+        config::{
+            AccountStatusConfig, AppEnv, Config, EmailVerificationConfig, EmailVerificationPolicy,
+            JwtConfig, LoginThrottleConfig, MailConfig, MailTransport, PasswordHashAlgorithm,
+            PasswordHashingConfig, PasswordPolicyConfig, RateLimitStoreKind, TokenConfig,
+            WebAuthnConfig,
+        },
+        mailer::LogMailer,
+        password_hasher::create_password_hashers,
+        rate_limit::create_rate_limit_store,
+    },
+    entities::auth::{
+        account_status::AccountStatusCache, keys::KeyStore, passkeys::create_webauthn,
+        revocation::RevocationList, social::providers::OAuthProviders,
+    },
+    models::{auth::Claims, user::User},
+};
+
+// The defaults of `Config::load` without any env, with cheap password hashing
+pub fn config() -> Config {
+    Config {
+        app_env: AppEnv::Development,
+        database_url: String::new(),
+        bind_address: "127.0.0.1:8080".into(),
+        frontend_url: "http://localhost:3000".into(),
+        public_url: "http://127.0.0.1:8080".into(),
+        trust_proxy_headers: false,
+        tokens: TokenConfig {
+            access_ttl: 15 * 60,
+            refresh_ttl: 30 * 24 * 60 * 60,
+            password_reset_ttl: 30 * 60,
+        },
+        jwt: JwtConfig {
+            algorithm: Algorithm::HS256,
+            kid: "test".into(),
+            secret: None,
+            private_key_path: None,
+            public_key_paths: Vec::new(),
+        },
+        mail: MailConfig {
+            transport: MailTransport::Log { path: None },
+            from: "no-reply@localhost".into(),
+        },
+        email_verification: EmailVerificationConfig {
+            policy: EmailVerificationPolicy::Optional,
+            ttl: 24 * 60 * 60,
+        },
+        webauthn: WebAuthnConfig {
+            rp_id: "localhost".into(),
+            rp_origin: "http://localhost:3000".into(),
+            rp_name: "rust-backend".into(),
+        },
+        oauth_providers: Vec::new(),
+        login_throttle: LoginThrottleConfig {
+            backoff_after: 3,
+            backoff_base: 1,
+            account_lockout_after: 10,
+            ip_lockout_after: 50,
+            lockout_duration: 15 * 60,
+            failure_window: 60 * 60,
+        },
+        rate_limit_store: RateLimitStoreKind::Memory,
+        password_policy: PasswordPolicyConfig {
+            min_length: 8,
+            max_length: 72,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            breached_list: None,
+        },
+        password_hashing: PasswordHashingConfig {
+            algorithm: PasswordHashAlgorithm::Argon2id,
+            stored_algorithm: PasswordHashAlgorithm::Argon2id,
+            bcrypt_cost: 4,
+            argon2_memory_kib: 64,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+        },
+        account_status: AccountStatusConfig {
+            per_request: false,
+            cache_ttl: 30,
+        },
+    }
+}
+
+// State of a test against the migrated database `#[sqlx::test]` hands out
+pub fn app_state(pool: PgPool, config: Config) -> AppState {
+    AppState {
+        keys: KeyStore::hmac(&config.jwt.kid, Uuid::new_v4().as_bytes()),
+        revocations: RevocationList::new(config.tokens.access_ttl),
+        mailer: Box::new(LogMailer::new(None)),
+        webauthn: create_webauthn(&config.webauthn).unwrap(),
+        oauth: OAuthProviders::new(&config.oauth_providers).unwrap(),
+        rate_limits: create_rate_limit_store(config.rate_limit_store, &pool),
+        passwords: create_password_hashers(&config.password_hashing).unwrap(),
+        account_statuses: AccountStatusCache::new(config.account_status.cache_ttl),
+        audit: AuditLogger::new(pool.clone()),
+        pool,
+        config,
+    }
+}
+
+pub async fn insert_user(app_state: &AppState, username: &str, email: &str) -> User {
+    let password_hash = app_state.passwords.hash("secret12").await.unwrap();
+
+    sqlx::query_as::<_, User>(
+        "
+			INSERT INTO users (id, username, email, password, email_verified_at)
+			VALUES ($1, $2, $3, $4, NOW())
+			RETURNING id, username, email, role, email_verified_at, status, suspended_until,
+				display_name, avatar_url, bio, locale, timezone
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(username)
+    .bind(email)
+    .bind(password_hash)
+    .fetch_one(&app_state.pool)
+    .await
+    .unwrap()
+}
+
+// what JwtAuth would hand a handler for the user
+pub async fn signed_in(user: &User) -> web::ReqData<Claims> {
+    let now = Utc::now().timestamp() as usize;
+    with_claims(Claims {
+        sub: user.id.clone(),
+        role: user.role,
+        is_premium: false,
+        email_verified: user.is_email_verified(),
+        jti: Uuid::new_v4().to_string(),
+        iat: now,
+        exp: now + 15 * 60,
+        auth_time: Some(now),
+        client_id: None,
+        scope: None,
+        perms: Vec::new(),
+        sid: None,
+    })
+    .await
+}
+
+// what JwtAuth hands a handler for a token with `claims`
+pub async fn with_claims(claims: Claims) -> web::ReqData<Claims> {
+    let req = test::TestRequest::default().to_http_request();
+    req.extensions_mut().insert(claims);
+
+    web::ReqData::<Claims>::extract(&req).await.unwrap()
+}
+
+// outcomes of the audited `event_type` events, oldest first
+pub async fn audit_outcomes(pool: &PgPool, event_type: &str) -> Vec<String> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT outcome FROM audit_events WHERE event_type = $1 ORDER BY created_at",
+    )
+    .bind(event_type)
+    .fetch_all(pool)
+    .await
+    .unwrap()
+}
+
+pub async fn json_body(response: HttpResponse) -> Value {
+    let body = to_bytes(response.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
@@ -0,0 +1,100 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use validator::Validate;
+
+use crate::{
+    common::audit::AuditRecord,
+    models::{auth::UserRole, user::AccountStatus},
+};
+
+#[derive(serde::Deserialize, Validate)]
+pub struct RevokeTokenDto {
+    #[validate(length(min = 1, message = "Token id must not be empty"))]
+    pub jti: String,
+
+    pub user_id: Option<String>,
+}
+
+#[derive(serde::Deserialize, Validate)]
+pub struct ListUsersQuery {
+    #[validate(range(min = 1, message = "Page starts at 1"))]
+    pub page: Option<i64>,
+
+    #[validate(range(min = 1, max = 100, message = "Page size must be between 1 and 100"))]
+    pub per_page: Option<i64>,
+
+    pub role: Option<UserRole>,
+
+    pub status: Option<AccountStatus>,
+
+    // part of the username or email
+    #[validate(length(max = 255, message = "Search must be at most 255 characters long"))]
+    pub search: Option<String>,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+pub struct AdminUserResponse {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub role: UserRole,
+    pub status: AccountStatus,
+    pub suspended_until: Option<DateTime<Utc>>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(serde::Serialize)]
+pub struct UserListResponse {
+    pub users: Vec<AdminUserResponse>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChangeRoleDto {
+    pub role: UserRole,
+}
+
+#[derive(serde::Deserialize, Validate)]
+pub struct SuspendUserDto {
+    // suspended until lifted when not set
+    pub until: Option<DateTime<Utc>>,
+
+    #[validate(length(max = 500, message = "Reason must be at most 500 characters long"))]
+    pub reason: Option<String>,
+}
+
+#[derive(serde::Deserialize, Validate)]
+pub struct BanUserDto {
+    #[validate(length(max = 500, message = "Reason must be at most 500 characters long"))]
+    pub reason: Option<String>,
+}
+
+#[derive(serde::Deserialize, Validate)]
+pub struct ListAuditEventsQuery {
+    #[validate(range(min = 1, message = "Page starts at 1"))]
+    pub page: Option<i64>,
+
+    #[validate(range(min = 1, max = 100, message = "Page size must be between 1 and 100"))]
+    pub per_page: Option<i64>,
+
+    // events the user did or that were done to them
+    pub user_id: Option<String>,
+
+    pub event_type: Option<String>,
+
+    // inclusive
+    pub from: Option<DateTime<Utc>>,
+
+    // exclusive
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+pub struct AuditEventListResponse {
+    pub events: Vec<AuditRecord>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
@@ -0,0 +1,955 @@
+pub mod dto;
+
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::Utc;
+use serde_json::json;
+use sqlx::PgExecutor;
+use validator::Validate;
+
+use crate::{
+    common::{
+        AppState,
+        audit::{self, AuditEvent, AuditFilter, Outcome},
+        errors::api_error::ApiError,
+        request::client_ip,
+        tokens::generate_opaque_token,
+    },
+    entities::{
+        admin::dto::{
+            AdminUserResponse, AuditEventListResponse, BanUserDto, ChangeRoleDto,
+            ListAuditEventsQuery, ListUsersQuery, RevokeTokenDto, SuspendUserDto, UserListResponse,
+        },
+        auth::{password_reset::send_reset_link, revoke_all_sessions, throttle},
+        user::find_user_by_id,
+    },
+    models::auth::{Claims, UserRole},
+};
+
+const DEFAULT_PER_PAGE: i64 = 20;
+
+const ADMIN_USER_COLUMNS: &str =
+    "id, username, email, role, status, suspended_until, email_verified_at, created_at";
+
+// every admin action is recorded as done by the admin to `target_id`,
+// in the transaction of the change when there is one
+pub async fn audit_action(
+    executor: impl PgExecutor<'_>,
+    app_state: &AppState,
+    req: &HttpRequest,
+    claims: &Claims,
+    event_type: &str,
+    target_id: Option<&str>,
+    metadata: serde_json::Value,
+) -> Result<(), ApiError> {
+    let ip = client_ip(req, &app_state.config);
+
+    app_state
+        .audit
+        .record_in(
+            executor,
+            AuditEvent {
+                event_type,
+                actor_id: Some(&claims.sub),
+                target_id,
+                ip: Some(&ip),
+                user_agent: audit::user_agent(req),
+                outcome: Outcome::Success,
+                metadata,
+            },
+        )
+        .await
+}
+
+// role, suspension and deletion of their own account are left to other admins,
+// so nobody locks the last admin out by accident
+fn reject_self(claims: &Claims, user_id: &str, action: &str) -> Result<(), ApiError> {
+    if claims.sub == user_id {
+        return Err(ApiError::Forbidden(format!(
+            "Admins can't {action} their own account"
+        )));
+    }
+
+    Ok(())
+}
+
+async fn find_admin_user(
+    app_state: &AppState,
+    user_id: &str,
+) -> Result<AdminUserResponse, ApiError> {
+    sqlx::query_as::<_, AdminUserResponse>(&format!(
+        "SELECT {ADMIN_USER_COLUMNS} FROM users WHERE id = $1"
+    ))
+    .bind(user_id)
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("User not found".into()))
+}
+
+// so `%` and `_` in the search are matched as themselves
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+
+    format!("%{escaped}%")
+}
+
+pub async fn list_users(
+    query: web::Query<ListUsersQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    query.validate().map_err(ApiError::Validation)?;
+
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    let search = query
+        .search
+        .as_deref()
+        .map(str::trim)
+        .filter(|search| !search.is_empty())
+        .map(like_pattern);
+
+    let filters = "
+			WHERE ($1::user_role IS NULL OR role = $1)
+				AND ($2::account_status IS NULL OR status = $2)
+				AND ($3::text IS NULL OR username ILIKE $3 OR email ILIKE $3)
+		";
+
+    let total = sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM users {filters}"))
+        .bind(query.role)
+        .bind(query.status)
+        .bind(&search)
+        .fetch_one(&app_state.pool)
+        .await?;
+
+    let users = sqlx::query_as::<_, AdminUserResponse>(&format!(
+        "
+			SELECT {ADMIN_USER_COLUMNS}
+			FROM users
+			{filters}
+			ORDER BY created_at DESC NULLS LAST, id
+			LIMIT $4 OFFSET $5
+		"
+    ))
+    .bind(query.role)
+    .bind(query.status)
+    .bind(&search)
+    .bind(per_page)
+    .bind((page - 1).saturating_mul(per_page))
+    .fetch_all(&app_state.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(UserListResponse {
+        users,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+pub async fn list_audit_events(
+    query: web::Query<ListAuditEventsQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    query.validate().map_err(ApiError::Validation)?;
+
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    let query = query.into_inner();
+
+    let (events, total) = app_state
+        .audit
+        .search(
+            &AuditFilter {
+                user_id: query.user_id,
+                event_type: query.event_type,
+                from: query.from,
+                to: query.to,
+            },
+            per_page,
+            (page - 1).saturating_mul(per_page),
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok().json(AuditEventListResponse {
+        events,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+pub async fn get_user(
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = find_admin_user(&app_state, &path.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn change_role(
+    req: HttpRequest,
+    path: web::Path<String>,
+    dto: web::Json<ChangeRoleDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    reject_self(&claims, &user_id, "change the role of")?;
+
+    let mut tx = app_state.pool.begin().await?;
+
+    let previous =
+        sqlx::query_scalar::<_, UserRole>("SELECT role FROM users WHERE id = $1 FOR UPDATE")
+            .bind(&user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("User not found".into()))?;
+
+    let user = sqlx::query_as::<_, AdminUserResponse>(&format!(
+        "UPDATE users SET role = $2 WHERE id = $1 RETURNING {ADMIN_USER_COLUMNS}"
+    ))
+    .bind(&user_id)
+    .bind(dto.role)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.role_changed",
+        Some(&user_id),
+        json!({ "from": previous, "to": dto.role }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    // tokens carry the role, the next refresh picks up the new one
+    app_state
+        .revocations
+        .revoke_user(&app_state.pool, &user_id)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn suspend_user(
+    req: HttpRequest,
+    path: web::Path<String>,
+    dto: web::Json<SuspendUserDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    dto.validate().map_err(ApiError::Validation)?;
+
+    let user_id = path.into_inner();
+    reject_self(&claims, &user_id, "suspend")?;
+
+    if dto.until.is_some_and(|until| until <= Utc::now()) {
+        return Err(ApiError::Other("Suspension must end in the future".into()));
+    }
+
+    let mut tx = app_state.pool.begin().await?;
+
+    let user = sqlx::query_as::<_, AdminUserResponse>(&format!(
+        "
+			UPDATE users
+			SET status = 'SUSPENDED', suspended_until = $2
+			WHERE id = $1
+			RETURNING {ADMIN_USER_COLUMNS}
+		"
+    ))
+    .bind(&user_id)
+    .bind(dto.until)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("User not found".into()))?;
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.suspended",
+        Some(&user_id),
+        json!({ "until": dto.until, "reason": dto.reason }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    // new tokens are refused from now on, the ones out there are ended here
+    app_state.account_statuses.forget(&user_id);
+    revoke_all_sessions(&app_state, &user_id).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn ban_user(
+    req: HttpRequest,
+    path: web::Path<String>,
+    dto: web::Json<BanUserDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    dto.validate().map_err(ApiError::Validation)?;
+
+    let user_id = path.into_inner();
+    reject_self(&claims, &user_id, "ban")?;
+
+    let mut tx = app_state.pool.begin().await?;
+
+    let user = sqlx::query_as::<_, AdminUserResponse>(&format!(
+        "
+			UPDATE users
+			SET status = 'BANNED', suspended_until = NULL
+			WHERE id = $1
+			RETURNING {ADMIN_USER_COLUMNS}
+		"
+    ))
+    .bind(&user_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("User not found".into()))?;
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.banned",
+        Some(&user_id),
+        json!({ "reason": dto.reason }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    app_state.account_statuses.forget(&user_id);
+    revoke_all_sessions(&app_state, &user_id).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+// lifts suspensions and bans alike, accounts pending verification stay pending
+pub async fn unsuspend_user(
+    req: HttpRequest,
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    let mut tx = app_state.pool.begin().await?;
+
+    let user = sqlx::query_as::<_, AdminUserResponse>(&format!(
+        "
+			UPDATE users
+			SET status = CASE
+					WHEN status IN ('SUSPENDED', 'BANNED') THEN 'ACTIVE'
+					ELSE status
+				END,
+				suspended_until = NULL
+			WHERE id = $1
+			RETURNING {ADMIN_USER_COLUMNS}
+		"
+    ))
+    .bind(&user_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| ApiError::NotFound("User not found".into()))?;
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.unsuspended",
+        Some(&user_id),
+        json!({}),
+    )
+    .await?;
+    tx.commit().await?;
+
+    app_state.account_statuses.forget(&user_id);
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn delete_user(
+    req: HttpRequest,
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    reject_self(&claims, &user_id, "delete")?;
+
+    let user = find_user_by_id(&user_id, &app_state.pool).await?;
+
+    // access tokens outlive the row, they have to be denied before it's gone
+    revoke_all_sessions(&app_state, &user_id).await?;
+
+    let mut tx = app_state.pool.begin().await?;
+
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.deleted",
+        Some(&user_id),
+        json!({ "username": user.username, "email": user.email }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    app_state.account_statuses.forget(&user_id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// The current password stops working and the user is mailed a reset link,
+// every session is signed out
+pub async fn force_password_reset(
+    req: HttpRequest,
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    let user = find_user_by_id(&user_id, &app_state.pool).await?;
+
+    // a hash of a password nobody knows, so logins fail like any wrong password
+    let unusable = app_state.passwords.hash(&generate_opaque_token()).await?;
+
+    let mut tx = app_state.pool.begin().await?;
+    sqlx::query("UPDATE users SET password = $2 WHERE id = $1")
+        .bind(&user_id)
+        .bind(unusable)
+        .execute(&mut *tx)
+        .await?;
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.password_reset_forced",
+        Some(&user_id),
+        json!({}),
+    )
+    .await?;
+    tx.commit().await?;
+
+    revoke_all_sessions(&app_state, &user_id).await?;
+    send_reset_link(&app_state, &user_id, user.email).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn revoke_token(
+    req: HttpRequest,
+    dto: web::Json<RevokeTokenDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    dto.validate().map_err(ApiError::Validation)?;
+
+    app_state
+        .revocations
+        .revoke_token(&app_state.pool, &dto.jti, dto.user_id.as_deref())
+        .await?;
+
+    audit_action(
+        &app_state.pool,
+        &app_state,
+        &req,
+        &claims,
+        "admin.token.revoked",
+        dto.user_id.as_deref(),
+        json!({ "jti": dto.jti }),
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn revoke_user_tokens(
+    req: HttpRequest,
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+
+    // making sure the user exists, so a typo doesn't silently succeed
+    find_user_by_id(&user_id, &app_state.pool).await?;
+
+    revoke_all_sessions(&app_state, &user_id).await?;
+
+    audit_action(
+        &app_state.pool,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.sessions_revoked",
+        Some(&user_id),
+        json!({}),
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// lifts a lockout before it runs out, the ips involved stay throttled
+pub async fn unlock_user(
+    req: HttpRequest,
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+
+    find_user_by_id(&user_id, &app_state.pool).await?;
+
+    throttle::clear_account(&app_state, &user_id).await?;
+
+    audit_action(
+        &app_state.pool,
+        &app_state,
+        &req,
+        &claims,
+        "admin.user.unlocked",
+        Some(&user_id),
+        json!({}),
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        App,
+        http::{StatusCode, header},
+        test,
+    };
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        common::{password_hasher::Verification, testing},
+        entities::auth::{guards::role_guard::RoleGuard, jwt, middlewares::jwt_auth::JwtAuth},
+        models::user::{AccountStatus, User},
+    };
+
+    fn query(
+        page: Option<i64>,
+        per_page: Option<i64>,
+        role: Option<UserRole>,
+        status: Option<AccountStatus>,
+        search: Option<&str>,
+    ) -> web::Query<ListUsersQuery> {
+        web::Query(ListUsersQuery {
+            page,
+            per_page,
+            role,
+            status,
+            search: search.map(str::to_owned),
+        })
+    }
+
+    async fn usernames(
+        app_state: &web::Data<AppState>,
+        query: web::Query<ListUsersQuery>,
+    ) -> Vec<String> {
+        let response = list_users(query, app_state.clone()).await.unwrap();
+        testing::json_body(response).await["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["username"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    async fn make_admin(app_state: &AppState, user: &mut User) {
+        sqlx::query("UPDATE users SET role = 'ADMIN' WHERE id = $1")
+            .bind(&user.id)
+            .execute(&app_state.pool)
+            .await
+            .unwrap();
+        user.role = UserRole::Admin;
+    }
+
+    // actions recorded about the user, oldest first
+    async fn recorded_actions(pool: &PgPool, target_id: &str) -> Vec<String> {
+        sqlx::query_scalar::<_, String>(
+            "SELECT event_type FROM audit_events WHERE target_id = $1 ORDER BY created_at",
+        )
+        .bind(target_id)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    fn request() -> HttpRequest {
+        test::TestRequest::default().to_http_request()
+    }
+
+    async fn suspend(app_state: &web::Data<AppState>, admin: &User, user: &User) {
+        suspend_user(
+            request(),
+            web::Path::from(user.id.clone()),
+            web::Json(SuspendUserDto {
+                until: None,
+                reason: Some("spam".into()),
+            }),
+            testing::signed_in(admin).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn listing_pages_filters_and_searches(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let mut admin = testing::insert_user(&app_state, "admin", "admin@example.com").await;
+        make_admin(&app_state, &mut admin).await;
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        testing::insert_user(&app_state, "bob_1", "bob@example.com").await;
+        suspend(&app_state, &admin, &alice).await;
+
+        let first = list_users(query(Some(1), Some(2), None, None, None), app_state.clone())
+            .await
+            .unwrap();
+        let first = testing::json_body(first).await;
+        assert_eq!(first["total"], 3);
+        assert_eq!(first["users"].as_array().unwrap().len(), 2);
+        let second = usernames(&app_state, query(Some(2), Some(2), None, None, None)).await;
+        assert_eq!(second.len(), 1);
+
+        let admins = query(None, None, Some(UserRole::Admin), None, None);
+        assert_eq!(usernames(&app_state, admins).await, ["admin"]);
+        let suspended = query(None, None, None, Some(AccountStatus::Suspended), None);
+        assert_eq!(usernames(&app_state, suspended).await, ["alice"]);
+
+        // matched case-insensitively against username and email, `_` only as itself
+        let found = usernames(&app_state, query(None, None, None, None, Some("BOB@"))).await;
+        assert_eq!(found, ["bob_1"]);
+        let found = usernames(&app_state, query(None, None, None, None, Some("b_b"))).await;
+        assert!(found.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn role_change_is_recorded_and_ends_the_sessions(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let mut admin = testing::insert_user(&app_state, "admin", "admin@example.com").await;
+        make_admin(&app_state, &mut admin).await;
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let response = change_role(
+            request(),
+            web::Path::from(alice.id.clone()),
+            web::Json(ChangeRoleDto {
+                role: UserRole::Admin,
+            }),
+            testing::signed_in(&admin).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(testing::json_body(response).await["role"], "Admin");
+
+        let metadata = sqlx::query_scalar::<_, serde_json::Value>(
+            "SELECT metadata FROM audit_events WHERE target_id = $1 AND actor_id = $2",
+        )
+        .bind(&alice.id)
+        .bind(&admin.id)
+        .fetch_one(&app_state.pool)
+        .await
+        .unwrap();
+        assert_eq!(metadata, json!({ "from": "User", "to": "Admin" }));
+
+        let revoked = sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM user_token_revocations WHERE user_id = $1",
+        )
+        .bind(&alice.id)
+        .fetch_one(&app_state.pool)
+        .await
+        .unwrap();
+        assert_eq!(revoked, 1);
+    }
+
+    #[sqlx::test]
+    async fn admins_cannot_change_their_own_role(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let mut admin = testing::insert_user(&app_state, "admin", "admin@example.com").await;
+        make_admin(&app_state, &mut admin).await;
+
+        let result = change_role(
+            request(),
+            web::Path::from(admin.id.clone()),
+            web::Json(ChangeRoleDto {
+                role: UserRole::User,
+            }),
+            testing::signed_in(&admin).await,
+            app_state.clone(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+        assert!(
+            recorded_actions(&app_state.pool, &admin.id)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[sqlx::test]
+    async fn suspended_user_gets_no_tokens_until_unsuspended(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let mut admin = testing::insert_user(&app_state, "admin", "admin@example.com").await;
+        make_admin(&app_state, &mut admin).await;
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        suspend(&app_state, &admin, &alice).await;
+        let alice = find_user_by_id(&alice.id, &app_state.pool).await.unwrap();
+        assert!(matches!(
+            jwt::generate_tokens(&app_state, &request(), &alice).await,
+            Err(ApiError::Forbidden(_))
+        ));
+
+        unsuspend_user(
+            request(),
+            web::Path::from(alice.id.clone()),
+            testing::signed_in(&admin).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        let alice = find_user_by_id(&alice.id, &app_state.pool).await.unwrap();
+        assert!(
+            jwt::generate_tokens(&app_state, &request(), &alice)
+                .await
+                .is_ok()
+        );
+
+        assert_eq!(
+            recorded_actions(&app_state.pool, &alice.id).await,
+            ["admin.user.suspended", "admin.user.unsuspended"]
+        );
+    }
+
+    #[sqlx::test]
+    async fn suspension_runs_out_on_its_own(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        sqlx::query(
+            "
+				UPDATE users
+				SET status = 'SUSPENDED', suspended_until = NOW() - INTERVAL '1 day'
+				WHERE id = $1
+			",
+        )
+        .bind(&alice.id)
+        .execute(&app_state.pool)
+        .await
+        .unwrap();
+
+        let alice = find_user_by_id(&alice.id, &app_state.pool).await.unwrap();
+        assert!(
+            jwt::generate_tokens(&app_state, &request(), &alice)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[sqlx::test]
+    async fn deleted_user_is_gone_but_the_action_stays(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let mut admin = testing::insert_user(&app_state, "admin", "admin@example.com").await;
+        make_admin(&app_state, &mut admin).await;
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let response = delete_user(
+            request(),
+            web::Path::from(alice.id.clone()),
+            testing::signed_in(&admin).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        assert!(matches!(
+            find_user_by_id(&alice.id, &app_state.pool).await,
+            Err(ApiError::NotFound(_))
+        ));
+        assert_eq!(
+            recorded_actions(&app_state.pool, &alice.id).await,
+            ["admin.user.deleted"]
+        );
+    }
+
+    #[sqlx::test]
+    async fn forced_reset_retires_the_password_and_sends_a_link(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let mut admin = testing::insert_user(&app_state, "admin", "admin@example.com").await;
+        make_admin(&app_state, &mut admin).await;
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        force_password_reset(
+            request(),
+            web::Path::from(alice.id.clone()),
+            testing::signed_in(&admin).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+
+        let (password, reset_links) = sqlx::query_as::<_, (String, i64)>(
+            "
+				SELECT password, (SELECT COUNT(*) FROM password_reset_tokens WHERE user_id = $1)
+				FROM users
+				WHERE id = $1
+			",
+        )
+        .bind(&alice.id)
+        .fetch_one(&app_state.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            app_state
+                .passwords
+                .verify("secret12", &password)
+                .await
+                .unwrap(),
+            Verification::Invalid
+        );
+        assert_eq!(reset_links, 1);
+        assert_eq!(
+            recorded_actions(&app_state.pool, &alice.id).await,
+            ["admin.user.password_reset_forced"]
+        );
+    }
+
+    #[sqlx::test]
+    async fn audit_events_are_filtered_by_user_type_and_time(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+
+        // (actor, target, type, hours ago)
+        for (actor, target, event_type, hours_ago) in [
+            (Some("alice"), Some("alice"), "auth.login", 30),
+            (Some("alice"), Some("alice"), "auth.login", 2),
+            (Some("admin"), Some("alice"), "admin.user.suspended", 1),
+            (Some("bob"), Some("bob"), "auth.login", 1),
+        ] {
+            sqlx::query(
+                "
+					INSERT INTO audit_events (id, actor_id, target_id, event_type, outcome, created_at)
+					VALUES ($1, $2, $3, $4, 'success', NOW() - make_interval(hours => $5))
+				",
+            )
+            .bind(Uuid::new_v4().to_string())
+            .bind(actor)
+            .bind(target)
+            .bind(event_type)
+            .bind(hours_ago)
+            .execute(&app_state.pool)
+            .await
+            .unwrap();
+        }
+
+        let search = |user_id: Option<&str>, event_type: Option<&str>, from_hours: Option<i64>| {
+            let app_state = app_state.clone();
+            let query = ListAuditEventsQuery {
+                page: None,
+                per_page: None,
+                user_id: user_id.map(str::to_owned),
+                event_type: event_type.map(str::to_owned),
+                from: from_hours.map(|hours| Utc::now() - chrono::Duration::hours(hours)),
+                to: None,
+            };
+            async move {
+                let response = list_audit_events(web::Query(query), app_state)
+                    .await
+                    .unwrap();
+                let body = testing::json_body(response).await;
+                let events = body["events"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|event| event["event_type"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>();
+                (body["total"].as_i64().unwrap(), events)
+            }
+        };
+
+        // as actor or as target, newest first
+        let (total, events) = search(Some("alice"), None, None).await;
+        assert_eq!(total, 3);
+        assert_eq!(events, ["admin.user.suspended", "auth.login", "auth.login"]);
+
+        let (total, _) = search(None, Some("auth.login"), None).await;
+        assert_eq!(total, 3);
+
+        let (total, events) = search(Some("alice"), Some("auth.login"), Some(24)).await;
+        assert_eq!(total, 1);
+        assert_eq!(events, ["auth.login"]);
+    }
+
+    #[sqlx::test]
+    async fn admin_area_is_forbidden_to_other_roles(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let mut admin = testing::insert_user(&app_state, "admin", "admin@example.com").await;
+        make_admin(&app_state, &mut admin).await;
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let app = test::init_service(
+            App::new().app_data(app_state.clone()).service(
+                web::scope("/admin")
+                    .wrap(RoleGuard {
+                        required_role: UserRole::Admin,
+                    })
+                    .wrap(JwtAuth)
+                    .route("/users", web::get().to(list_users)),
+            ),
+        )
+        .await;
+
+        for (user, status) in [(&alice, StatusCode::FORBIDDEN), (&admin, StatusCode::OK)] {
+            let token = jwt::create_access_token(
+                &app_state,
+                user,
+                &jwt::SessionRef {
+                    id: &Uuid::new_v4().to_string(),
+                    auth_time: Utc::now(),
+                },
+            )
+            .await
+            .unwrap();
+            let req = test::TestRequest::get()
+                .uri("/admin/users")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .to_request();
+
+            let response = test::try_call_service(&app, req).await;
+            let actual = match response {
+                Ok(response) => response.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            assert_eq!(actual, status);
+        }
+    }
+}
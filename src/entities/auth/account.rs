@@ -0,0 +1,301 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use validator::Validate;
+
+use crate::{
+    common::{
+        AppState, config::EmailVerificationPolicy, errors::api_error::ApiError, mailer::Email,
+        password_hasher::Verification,
+    },
+    entities::{
+        auth::{
+            dto::{AuthResponse, ChangeEmailDto, ChangePasswordDto},
+            email_verification::send_verification_email,
+            jwt, password_policy, revoke_all_sessions,
+        },
+        user::find_user_with_password_by_id,
+    },
+    models::{
+        auth::Claims,
+        user::{User, UserWithPassword},
+    },
+};
+
+pub(crate) async fn check_current_password(
+    app_state: &AppState,
+    user_id: &str,
+    password: &str,
+) -> Result<UserWithPassword, ApiError> {
+    let user = find_user_with_password_by_id(user_id, &app_state.pool).await?;
+
+    let verification = app_state.passwords.verify(password, &user.password).await?;
+    if verification == Verification::Invalid {
+        return Err(ApiError::Other("Incorrect Password".into()));
+    }
+
+    Ok(user)
+}
+
+// every other session is ended, the caller gets a fresh session in the response
+async fn restart_sessions(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user: User,
+) -> Result<HttpResponse, ApiError> {
+    revoke_all_sessions(app_state, &user.id).await?;
+
+    if app_state.config.email_verification.policy == EmailVerificationPolicy::BlockLogin
+        && !user.is_email_verified()
+    {
+        return Ok(HttpResponse::Ok()
+            .cookie(jwt::build_refresh_removal_cookie(app_state))
+            .json(serde_json::json!({
+                "user": user,
+                "email_verification_required": true,
+            })));
+    }
+
+    let tokens = jwt::generate_tokens(app_state, req, &user).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(jwt::build_refresh_cookie(tokens.refresh_token, app_state))
+        .json(AuthResponse {
+            user,
+            access_token: tokens.access_token,
+        }))
+}
+
+pub async fn change_password(
+    req: HttpRequest,
+    dto: web::Json<ChangePasswordDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = check_current_password(&app_state, &claims.sub, &dto.current_password).await?;
+    password_policy::check(
+        &app_state,
+        "new_password",
+        &dto.new_password,
+        &user.username,
+        &user.email,
+    )
+    .await?;
+
+    // hash password
+    let password_hash = app_state.passwords.hash(&dto.new_password).await?;
+
+    sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+        .bind(&password_hash)
+        .bind(&user.id)
+        .execute(&app_state.pool)
+        .await?;
+
+    restart_sessions(&app_state, &req, User::from(user)).await
+}
+
+pub async fn change_email(
+    req: HttpRequest,
+    dto: web::Json<ChangeEmailDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    dto.validate().map_err(ApiError::Validation)?;
+
+    let old = check_current_password(&app_state, &claims.sub, &dto.current_password).await?;
+    if old.email == dto.new_email {
+        return Err(ApiError::Other(
+            "New email is the same as the current one".into(),
+        ));
+    }
+
+    // the new address has to be verified again
+    let user = sqlx::query_as::<_, User>(
+        "
+			UPDATE users
+			SET email = $1, email_verified_at = NULL
+			WHERE id = $2
+			RETURNING id, username, email, role, email_verified_at, status, suspended_until,
+				display_name, avatar_url, bio, locale, timezone
+		",
+    )
+    .bind(&dto.new_email)
+    .bind(&old.id)
+    .fetch_one(&app_state.pool)
+    .await?;
+
+    if let Err(e) = send_verification_email(&app_state, &user.id, &user.email).await {
+        eprintln!("[EMAIL VERIFICATION]: {e}");
+    }
+
+    // the old address learns about the change, in case it wasn't its owner
+    let notice = app_state
+        .mailer
+        .send(Email {
+            to: old.email,
+            subject: "Your email address was changed".to_string(),
+            body: format!(
+                "The email address of your account {} was changed to {}.\n\nIf you didn't do this, reset your password and contact support.",
+                user.username, user.email
+            ),
+        })
+        .await;
+    if let Err(e) = notice {
+        eprintln!("[CHANGE EMAIL]: Cannot notify the old address: {e}");
+    }
+
+    restart_sessions(&app_state, &req, user).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        common::testing,
+        entities::{
+            auth::{dto::ChangePasswordDto, refresh_store},
+            user::find_user_by_id,
+        },
+    };
+
+    async fn password_hash(app_state: &AppState, user_id: &str) -> String {
+        sqlx::query_scalar("SELECT password FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&app_state.pool)
+            .await
+            .unwrap()
+    }
+
+    fn change_password_dto(current_password: &str) -> web::Json<ChangePasswordDto> {
+        web::Json(ChangePasswordDto {
+            current_password: current_password.into(),
+            new_password: "secret34".into(),
+        })
+    }
+
+    fn change_email_dto(current_password: &str) -> web::Json<ChangeEmailDto> {
+        web::Json(ChangeEmailDto {
+            current_password: current_password.into(),
+            new_email: "alice@example.org".into(),
+        })
+    }
+
+    #[sqlx::test]
+    async fn wrong_current_password_changes_nothing(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let before = password_hash(&app_state, &user.id).await;
+
+        let result = change_password(
+            TestRequest::default().to_http_request(),
+            change_password_dto("wrong123"),
+            testing::signed_in(&user).await,
+            app_state.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::Other(_))));
+        assert_eq!(password_hash(&app_state, &user.id).await, before);
+
+        let result = change_email(
+            TestRequest::default().to_http_request(),
+            change_email_dto("wrong123"),
+            testing::signed_in(&user).await,
+            app_state.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::Other(_))));
+        let email: String = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+            .bind(&user.id)
+            .fetch_one(&app_state.pool)
+            .await
+            .unwrap();
+        assert_eq!(email, "alice@example.com");
+    }
+
+    #[sqlx::test]
+    async fn password_change_ends_the_other_sessions(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let ttl = app_state.config.tokens.refresh_ttl;
+        let other_device =
+            refresh_store::issue(&app_state.pool, &Uuid::new_v4().to_string(), &user.id, ttl)
+                .await
+                .unwrap();
+        let before = password_hash(&app_state, &user.id).await;
+
+        let response = change_password(
+            TestRequest::default().to_http_request(),
+            change_password_dto("secret12"),
+            testing::signed_in(&user).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_ne!(password_hash(&app_state, &user.id).await, before);
+
+        assert!(
+            refresh_store::rotate(&app_state.pool, &other_device.token, None, ttl)
+                .await
+                .is_err()
+        );
+        let access_revoked: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM user_token_revocations WHERE user_id = $1)",
+        )
+        .bind(&user.id)
+        .fetch_one(&app_state.pool)
+        .await
+        .unwrap();
+        assert!(access_revoked);
+
+        // the caller carries on with the session it got back
+        let refresh_cookie = response
+            .cookies()
+            .find(|cookie| cookie.name() == "refresh_token")
+            .unwrap();
+        assert!(
+            refresh_store::rotate(&app_state.pool, refresh_cookie.value(), None, ttl)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[sqlx::test]
+    async fn email_change_asks_for_verification_again(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let ttl = app_state.config.tokens.refresh_ttl;
+        let other_device =
+            refresh_store::issue(&app_state.pool, &Uuid::new_v4().to_string(), &user.id, ttl)
+                .await
+                .unwrap();
+
+        change_email(
+            TestRequest::default().to_http_request(),
+            change_email_dto("secret12"),
+            testing::signed_in(&user).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+
+        let changed = find_user_by_id(&user.id, &app_state.pool).await.unwrap();
+        assert_eq!(changed.email, "alice@example.org");
+        assert!(!changed.is_email_verified());
+
+        let sent_to: String =
+            sqlx::query_scalar("SELECT email FROM email_verification_tokens WHERE user_id = $1")
+                .bind(&user.id)
+                .fetch_one(&app_state.pool)
+                .await
+                .unwrap();
+        assert_eq!(sent_to, "alice@example.org");
+
+        assert!(
+            refresh_store::rotate(&app_state.pool, &other_device.token, None, ttl)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use crate::{common::errors::api_error::ApiError, models::user::AccountStatus};
+
+// entries past their ttl are only dropped once the cache grows this big
+const SWEEP_ABOVE: usize = 10_000;
+
+// Fails for accounts that can't be used right now, with the reason as message
+pub fn ensure_active(
+    status: AccountStatus,
+    suspended_until: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    match status {
+        AccountStatus::Active => Ok(()),
+        // suspensions with an end run out on their own
+        AccountStatus::Suspended => match suspended_until {
+            Some(until) if until <= Utc::now() => Ok(()),
+            Some(until) => Err(ApiError::Forbidden(format!(
+                "Account is suspended until {}",
+                until.to_rfc3339()
+            ))),
+            None => Err(ApiError::Forbidden("Account is suspended".into())),
+        },
+        AccountStatus::Banned => Err(ApiError::Forbidden("Account is banned".into())),
+        AccountStatus::PendingVerification => Err(ApiError::Forbidden(
+            "Account is pending email verification".into(),
+        )),
+    }
+}
+
+#[derive(sqlx::FromRow, Clone, Copy)]
+struct StatusRow {
+    status: AccountStatus,
+    suspended_until: Option<DateTime<Utc>>,
+}
+
+struct Cached {
+    // None for deleted accounts
+    row: Option<StatusRow>,
+    fetched_at: Instant,
+}
+
+// Statuses looked up for requests with an access token. Changes made by this
+// instance apply right away, the ones of other instances after at most the ttl
+pub struct AccountStatusCache {
+    entries: RwLock<HashMap<String, Cached>>,
+    ttl: Duration,
+}
+
+impl AccountStatusCache {
+    pub fn new(ttl: u64) -> Self {
+        Self {
+            entries: RwLock::default(),
+            ttl: Duration::from_secs(ttl),
+        }
+    }
+
+    fn cached(&self, user_id: &str) -> Option<Option<StatusRow>> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(user_id)
+            .filter(|cached| cached.fetched_at.elapsed() < self.ttl)
+            .map(|cached| cached.row)
+    }
+
+    pub async fn check(&self, pool: &PgPool, user_id: &str) -> Result<(), ApiError> {
+        let row = match self.cached(user_id) {
+            Some(row) => row,
+            None => {
+                let row = sqlx::query_as::<_, StatusRow>(
+                    "SELECT status, suspended_until FROM users WHERE id = $1",
+                )
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?;
+
+                let mut entries = self.entries.write().unwrap();
+                if entries.len() >= SWEEP_ABOVE {
+                    entries.retain(|_, cached| cached.fetched_at.elapsed() < self.ttl);
+                }
+                entries.insert(
+                    user_id.to_string(),
+                    Cached {
+                        row,
+                        fetched_at: Instant::now(),
+                    },
+                );
+
+                row
+            }
+        };
+
+        let row = row.ok_or_else(|| ApiError::Unauthorized("Account no longer exists".into()))?;
+        ensure_active(row.status, row.suspended_until)
+    }
+
+    pub fn forget(&self, user_id: &str) {
+        self.entries.write().unwrap().remove(user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    fn is_forbidden(result: Result<(), ApiError>) -> bool {
+        matches!(result, Err(ApiError::Forbidden(_)))
+    }
+
+    #[test]
+    fn active_account_passes() {
+        assert!(ensure_active(AccountStatus::Active, None).is_ok());
+    }
+
+    #[test]
+    fn suspension_holds_until_it_runs_out() {
+        let ahead = Utc::now() + Duration::hours(1);
+        let past = Utc::now() - Duration::seconds(1);
+
+        assert!(is_forbidden(ensure_active(AccountStatus::Suspended, None)));
+        assert!(is_forbidden(ensure_active(
+            AccountStatus::Suspended,
+            Some(ahead)
+        )));
+        assert!(ensure_active(AccountStatus::Suspended, Some(past)).is_ok());
+    }
+
+    #[test]
+    fn banned_and_unverified_accounts_are_rejected() {
+        assert!(is_forbidden(ensure_active(AccountStatus::Banned, None)));
+        assert!(is_forbidden(ensure_active(
+            AccountStatus::PendingVerification,
+            None
+        )));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::models::user::User;
+use chrono::{DateTime, Utc};
 use validator::Validate;
 
 #[derive(serde::Deserialize, Validate)]
@@ -6,7 +7,7 @@ pub struct CreateUserDto {
     #[validate(length(min = 3, max = 20, message = "Name length must be more than 3 chars, but less than 20 chars"))]
     pub username: String,
 
-		#[validate(length(min = 6, message = "Password length must be more than 6 chars"))]
+    // checked against the password policy
     pub password: String,
 
 		#[validate(email(message = "Incorrect email"))]
@@ -31,3 +32,139 @@ pub struct Tokens {
     pub access_token: String,
     pub refresh_token: String,
 }
+
+#[derive(serde::Deserialize)]
+pub struct VerifyEmailDto {
+    pub token: String,
+}
+
+#[derive(serde::Deserialize, Validate)]
+pub struct ResendVerificationDto {
+    #[validate(email(message = "Incorrect email"))]
+    pub email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ForgotPasswordDto {
+    pub username_or_email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetPasswordDto {
+    pub token: String,
+
+    // checked against the password policy
+    pub new_password: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChangePasswordDto {
+    pub current_password: String,
+
+    // checked against the password policy
+    pub new_password: String,
+}
+
+#[derive(serde::Deserialize, Validate)]
+pub struct ChangeEmailDto {
+    pub current_password: String,
+
+    #[validate(email(message = "Incorrect email"))]
+    pub new_email: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct MfaVerifyDto {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TotpCodeDto {
+    pub code: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct TotpEnrollmentResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyRegistrationStartDto {
+    pub current_password: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyRegistrationDto {
+    pub challenge_id: String,
+    pub name: Option<String>,
+    pub credential: webauthn_rs::prelude::RegisterPublicKeyCredential,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyLoginStartDto {
+    pub username_or_email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyLoginDto {
+    pub challenge_id: String,
+    pub credential: webauthn_rs::prelude::PublicKeyCredential,
+}
+
+#[derive(serde::Serialize)]
+pub struct PasskeyChallengeResponse<T> {
+    pub challenge_id: String,
+    pub options: T,
+}
+
+#[derive(serde::Deserialize)]
+pub struct OAuthCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct OAuthLinkRequiredResponse {
+    pub link_required: bool,
+    pub link_token: String,
+    pub provider: String,
+    pub email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct OAuthLinkStartDto {
+    pub current_password: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ConfirmOAuthLinkDto {
+    pub link_token: String,
+    pub password: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    // browser and platform read from the user agent
+    pub device: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    // the session of the token making the request
+    pub current: bool,
+}
@@ -0,0 +1,331 @@
+use actix_web::{HttpResponse, rt, web};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    common::{
+        AppState,
+        errors::api_error::ApiError,
+        mailer::Email,
+        tokens::{generate_opaque_token, hash_token},
+    },
+    entities::auth::dto::{ResendVerificationDto, VerifyEmailDto},
+    models::user::User,
+};
+
+#[derive(sqlx::FromRow)]
+struct UsedToken {
+    user_id: String,
+    email: String,
+}
+
+// creates a single-use token bound to `email` and mails the link to it
+pub async fn send_verification_email(
+    app_state: &AppState,
+    user_id: &str,
+    email: &str,
+) -> Result<(), ApiError> {
+    let token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::seconds(app_state.config.email_verification.ttl);
+
+    sqlx::query(
+        "
+			INSERT INTO email_verification_tokens (id, user_id, email, token_hash, expires_at)
+			VALUES ($1, $2, $3, $4, $5)
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(email)
+    .bind(hash_token(&token))
+    .bind(expires_at)
+    .execute(&app_state.pool)
+    .await?;
+
+    let link = format!(
+        "{}/verify-email?token={}",
+        app_state.config.frontend_url, token
+    );
+
+    app_state
+        .mailer
+        .send(Email {
+            to: email.to_owned(),
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Open the link below to confirm your email address:\n\n{link}\n\nIf you didn't create an account, ignore this email."
+            ),
+        })
+        .await
+        .map_err(|e| ApiError::InternalServer(format!("Cannot send verification email: {e}")))
+}
+
+pub async fn verify_email(
+    dto: web::Json<VerifyEmailDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = app_state.pool.begin().await?;
+
+    // burning the token first, so it can't be used twice even concurrently
+    let used = sqlx::query_as::<_, UsedToken>(
+        "
+			UPDATE email_verification_tokens
+			SET used_at = NOW()
+			WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+			RETURNING user_id, email
+		",
+    )
+    .bind(hash_token(&dto.token))
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| ApiError::Other("Invalid or expired verification token".into()))?;
+
+    // the address could have been changed after the link was sent
+    let user = sqlx::query_as::<_, User>(
+        "
+			UPDATE users
+			SET email_verified_at = COALESCE(email_verified_at, NOW()),
+				status = CASE WHEN status = 'PENDING_VERIFICATION' THEN 'ACTIVE' ELSE status END
+			WHERE id = $1 AND email = $2
+			RETURNING id, username, email, role, email_verified_at, status, suspended_until,
+				display_name, avatar_url, bio, locale, timezone
+		",
+    )
+    .bind(&used.user_id)
+    .bind(&used.email)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| ApiError::Other("Invalid or expired verification token".into()))?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+// always answers the same, so it can't be used to find out which emails are registered
+pub async fn resend_verification_email(
+    dto: web::Json<ResendVerificationDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    dto.validate().map_err(ApiError::Validation)?;
+
+    let user = sqlx::query_as::<_, User>(
+        "
+			SELECT id, username, email, role, email_verified_at, status, suspended_until,
+				display_name, avatar_url, bio, locale, timezone
+			FROM users
+			WHERE email = $1 AND email_verified_at IS NULL
+		",
+    )
+    .bind(&dto.email)
+    .fetch_optional(&app_state.pool)
+    .await?;
+
+    // sent in the background, so the response time doesn't tell either
+    if let Some(user) = user {
+        let app_state = app_state.clone();
+
+        rt::spawn(async move {
+            if let Err(e) = send_verification_email(&app_state, &user.id, &user.email).await {
+                eprintln!("[EMAIL VERIFICATION]: {e}");
+            }
+        });
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use actix_web::test::TestRequest;
+    use sqlx::PgPool;
+    use tokio::task::LocalSet;
+
+    use super::*;
+    use crate::{
+        common::{
+            config::{Config, EmailVerificationPolicy},
+            mailer::LogMailer,
+            testing,
+        },
+        entities::auth::{self, dto::CreateUserDto, dto::LoginDto},
+    };
+
+    // the emails go to a file, where the tests pick the links up
+    fn app_state(pool: PgPool, config: Config) -> (web::Data<AppState>, PathBuf) {
+        let outbox = std::env::temp_dir().join(format!("outbox-{}.log", Uuid::new_v4()));
+        let app_state = AppState {
+            mailer: Box::new(LogMailer::new(Some(outbox.clone()))),
+            ..testing::app_state(pool, config)
+        };
+        (web::Data::new(app_state), outbox)
+    }
+
+    fn last_token(outbox: &Path) -> String {
+        let sent = fs::read_to_string(outbox).unwrap();
+        let (_, token) = sent.rsplit_once("/verify-email?token=").unwrap();
+        token.lines().next().unwrap().to_owned()
+    }
+
+    async fn register(app_state: &web::Data<AppState>) -> String {
+        auth::register(
+            TestRequest::default().to_http_request(),
+            web::Json(CreateUserDto {
+                username: "alice".into(),
+                password: "secret12".into(),
+                email: "alice@example.com".into(),
+            }),
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+
+        sqlx::query_scalar("SELECT id FROM users WHERE username = 'alice'")
+            .fetch_one(&app_state.pool)
+            .await
+            .unwrap()
+    }
+
+    async fn verify(
+        app_state: &web::Data<AppState>,
+        token: &str,
+    ) -> Result<HttpResponse, ApiError> {
+        verify_email(
+            web::Json(VerifyEmailDto {
+                token: token.to_owned(),
+            }),
+            app_state.clone(),
+        )
+        .await
+    }
+
+    async fn is_verified(app_state: &AppState, user_id: &str) -> bool {
+        sqlx::query_scalar("SELECT email_verified_at IS NOT NULL FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&app_state.pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn registration_sends_a_hashed_token(pool: PgPool) {
+        let (app_state, outbox) = app_state(pool, testing::config());
+        let user_id = register(&app_state).await;
+
+        let sent = fs::read_to_string(&outbox).unwrap();
+        assert!(sent.starts_with("To: alice@example.com\n"));
+        assert!(sent.contains("http://localhost:3000/verify-email?token="));
+
+        let token = last_token(&outbox);
+        let stored: String = sqlx::query_scalar(
+            "SELECT token_hash FROM email_verification_tokens WHERE user_id = $1",
+        )
+        .bind(&user_id)
+        .fetch_one(&app_state.pool)
+        .await
+        .unwrap();
+        assert_eq!(stored, hash_token(&token));
+        assert!(!is_verified(&app_state, &user_id).await);
+    }
+
+    #[sqlx::test]
+    async fn token_verifies_the_email_once(pool: PgPool) {
+        let (app_state, outbox) = app_state(pool, testing::config());
+        let user_id = register(&app_state).await;
+        let token = last_token(&outbox);
+
+        verify(&app_state, &token).await.unwrap();
+        assert!(is_verified(&app_state, &user_id).await);
+
+        assert!(matches!(
+            verify(&app_state, &token).await,
+            Err(ApiError::Other(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn expired_token_is_rejected(pool: PgPool) {
+        let (app_state, outbox) = app_state(pool, testing::config());
+        let user_id = register(&app_state).await;
+        sqlx::query(
+            "UPDATE email_verification_tokens SET expires_at = NOW() - INTERVAL '1 second'",
+        )
+        .execute(&app_state.pool)
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            verify(&app_state, &last_token(&outbox)).await,
+            Err(ApiError::Other(_))
+        ));
+        assert!(!is_verified(&app_state, &user_id).await);
+    }
+
+    #[sqlx::test]
+    async fn resend_mails_only_unverified_addresses(pool: PgPool) {
+        let (app_state, outbox) = app_state(pool, testing::config());
+        register(&app_state).await;
+        testing::insert_user(&app_state, "bob", "bob@example.com").await;
+
+        // the emails are sent from tasks on the local set, as actix would run them
+        LocalSet::new()
+            .run_until(async {
+                for email in ["alice@example.com", "bob@example.com", "nobody@example.com"] {
+                    let response = resend_verification_email(
+                        web::Json(ResendVerificationDto {
+                            email: email.into(),
+                        }),
+                        app_state.clone(),
+                    )
+                    .await
+                    .unwrap();
+                    assert_eq!(response.status(), 202);
+                }
+
+                let mut sent = 0;
+                for _ in 0..50 {
+                    sent = fs::read_to_string(&outbox).unwrap().matches("To: ").count();
+                    if sent == 2 {
+                        break;
+                    }
+                    rt::time::sleep(Duration::milliseconds(20).to_std().unwrap()).await;
+                }
+                assert_eq!(sent, 2);
+            })
+            .await;
+        assert!(
+            !fs::read_to_string(&outbox)
+                .unwrap()
+                .contains("bob@example.com")
+        );
+    }
+
+    #[sqlx::test]
+    async fn block_login_refuses_unverified_accounts(pool: PgPool) {
+        let mut config = testing::config();
+        config.email_verification.policy = EmailVerificationPolicy::BlockLogin;
+        let (app_state, outbox) = app_state(pool, config);
+        register(&app_state).await;
+
+        let login = || {
+            auth::login(
+                TestRequest::default().to_http_request(),
+                web::Json(LoginDto {
+                    username_or_email: "alice".into(),
+                    password: "secret12".into(),
+                }),
+                app_state.clone(),
+            )
+        };
+        assert!(matches!(login().await, Err(ApiError::Forbidden(_))));
+
+        verify(&app_state, &last_token(&outbox)).await.unwrap();
+        assert_eq!(login().await.unwrap().status(), 200);
+    }
+}
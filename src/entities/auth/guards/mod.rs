@@ -1 +1,2 @@
-pub mod role_guard;
\ No newline at end of file
+pub mod permission_guard;
+pub mod role_guard;
@@ -0,0 +1,124 @@
+use std::rc::Rc;
+
+use actix_web::{
+    Error, HttpMessage,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+};
+use futures_util::future::{LocalBoxFuture, Ready, ok};
+
+use crate::{common::errors::api_error::ApiError, models::auth::Claims};
+
+// Rejects with 403 instead of the 404 of a failed route guard. JwtAuth has to
+// run before it, a request without claims is rejected with 401
+pub struct RequirePermission(pub &'static str);
+
+fn check(permission: &str, claims: Option<&Claims>) -> Result<(), ApiError> {
+    let Some(claims) = claims else {
+        return Err(ApiError::Unauthorized("Authentication required".into()));
+    };
+
+    if !claims.has_permission(permission) {
+        return Err(ApiError::Forbidden(format!(
+            "Missing the {permission} permission"
+        )));
+    }
+
+    Ok(())
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Transform = RequirePermissionMiddleware<S>;
+    type Error = Error;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: self.0,
+        })
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let checked = check(self.permission, req.extensions().get::<Claims>());
+
+        Box::pin(async move {
+            checked?;
+            svc.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{entities::auth::permissions, models::auth::UserRole};
+
+    fn claims(perms: &[&str]) -> Claims {
+        Claims {
+            sub: "user".into(),
+            role: UserRole::User,
+            is_premium: false,
+            email_verified: true,
+            jti: "jti".into(),
+            iat: 0,
+            exp: 0,
+            auth_time: None,
+            client_id: None,
+            scope: None,
+            perms: perms.iter().map(|perm| perm.to_string()).collect(),
+            sid: None,
+        }
+    }
+
+    #[test]
+    fn request_without_claims_is_unauthorized() {
+        assert!(matches!(
+            check(permissions::USERS_UNLOCK, None),
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn missing_permission_is_forbidden() {
+        let claims = claims(&[permissions::USERS_UNLOCK]);
+
+        assert!(matches!(
+            check(permissions::OAUTH_CLIENTS_MANAGE, Some(&claims)),
+            Err(ApiError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn granted_permission_passes() {
+        let claims = claims(&[permissions::TOKENS_REVOKE, permissions::USERS_UNLOCK]);
+
+        assert!(check(permissions::USERS_UNLOCK, Some(&claims)).is_ok());
+    }
+}
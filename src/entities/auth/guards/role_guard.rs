@@ -1,20 +1,121 @@
-use actix_web::guard::{Guard, GuardContext};
+use std::rc::Rc;
 
-use crate::models::auth::{Claims, UserRole};
+use actix_web::{
+    Error, HttpMessage,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+};
+use futures_util::future::{LocalBoxFuture, Ready, ok};
 
+use crate::{
+    common::errors::api_error::ApiError,
+    models::auth::{Claims, UserRole},
+};
+
+// Lets only one role through and answers 403 to the others. JwtAuth has to
+// run before it, a request without claims is rejected with 401
 pub struct RoleGuard {
-	pub required_role: UserRole,
+    pub required_role: UserRole,
+}
+
+fn check(required_role: UserRole, claims: Option<&Claims>) -> Result<(), ApiError> {
+    let Some(claims) = claims else {
+        return Err(ApiError::Unauthorized("Authentication required".into()));
+    };
+
+    if claims.role != required_role {
+        return Err(ApiError::Forbidden("Insufficient role".into()));
+    }
+
+    Ok(())
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RoleGuard
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Transform = RoleGuardMiddleware<S>;
+    type Error = Error;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RoleGuardMiddleware {
+            service: Rc::new(service),
+            required_role: self.required_role,
+        })
+    }
+}
+
+pub struct RoleGuardMiddleware<S> {
+    service: Rc<S>,
+    required_role: UserRole,
 }
 
-impl Guard for RoleGuard {
-    fn check(&self, ctx: &GuardContext) -> bool {
-        let exts = ctx.req_data();
+impl<S, B> Service<ServiceRequest> for RoleGuardMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
-        match exts.get::<Claims>() {
-            Some(value) => {
-							return value.role == self.required_role;
-						},
-            None => false,
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let checked = check(self.required_role, req.extensions().get::<Claims>());
+
+        Box::pin(async move {
+            checked?;
+            svc.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(role: UserRole) -> Claims {
+        Claims {
+            sub: "user".into(),
+            role,
+            is_premium: false,
+            email_verified: true,
+            jti: "jti".into(),
+            iat: 0,
+            exp: 0,
+            auth_time: None,
+            client_id: None,
+            scope: None,
+            perms: Vec::new(),
+            sid: None,
         }
     }
+
+    #[test]
+    fn request_without_claims_is_unauthorized() {
+        assert!(matches!(
+            check(UserRole::Admin, None),
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn other_role_is_forbidden() {
+        assert!(matches!(
+            check(UserRole::Admin, Some(&claims(UserRole::User))),
+            Err(ApiError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn required_role_passes() {
+        assert!(check(UserRole::Admin, Some(&claims(UserRole::Admin))).is_ok());
+    }
 }
@@ -1,11 +1,25 @@
 use crate::{
-	entities::auth::{
-			constants::{ACCESS_TOKEN_EXPIRATION, REFRESH_TOKEN_EXPIRATION},
-			dto::{Tokens},
+	entities::{
+			auth::{
+					account_status,
+					dto::Tokens,
+					keys::KeyStore,
+					permissions,
+					record_auth_event,
+					refresh_store,
+					sessions,
+			},
+			user::find_user_by_id,
+	},
+	common::{
+			AppState,
+			audit::Outcome,
+			config::EmailVerificationPolicy,
+			errors::api_error::ApiError,
 	},
-	common::{AppState, errors::api_error::ApiError},
 	models::{
 			auth::{Claims, UserRole},
+			user::User,
 	},
 };
 use actix_web::{
@@ -13,68 +27,143 @@ use actix_web::{
 	cookie::{Cookie, SameSite, time},
 	web,
 };
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use uuid::Uuid;
+
+// the sign in access tokens are issued for, both kept across refreshes
+pub struct SessionRef<'a> {
+	pub id: &'a str,
+	pub auth_time: DateTime<Utc>,
+}
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_jwt(
+	keys: &KeyStore,
 	user_id: &str,
 	is_premium: &bool,
 	role: &UserRole,
+	email_verified: bool,
+	session: &SessionRef,
+	perms: Vec<String>,
 	expires_after: i64,
 ) -> Result<String, String> {
-	use chrono::Utc;
-
-	let secret = std::env::var("JWT_SECRET").unwrap_or("roscript-backend".to_string());
-
+	let now = Utc::now().timestamp();
 	let claims = Claims {
 			sub: user_id.to_owned(),
 			role: *role,
 			is_premium: *is_premium,
-			exp: (Utc::now().timestamp() + expires_after) as usize, // two weeks
+			email_verified,
+			// v7 carries the issue time to the millisecond, see RevocationList::is_revoked
+			jti: Uuid::now_v7().to_string(),
+			iat: now as usize,
+			exp: (now + expires_after) as usize,
+			auth_time: Some(session.auth_time.timestamp() as usize),
+			client_id: None,
+			scope: None,
+			perms,
+			sid: Some(session.id.to_owned()),
 	};
 
-	match encode(
-			&Header::default(),
-			&claims,
-			&EncodingKey::from_secret(secret.as_bytes()),
-	) {
+	match keys.sign(&claims) {
 			Ok(token) => Ok(token),
 			Err(e) => Err(format!("Ошибка при попытке создания токена, {:?}", e)),
 	}
 }
 
-pub fn verify_jwt(token: &str) -> Option<Claims> {
-	let secret = std::env::var("JWT_SECRET").unwrap_or("roscript-backend".to_string());
+pub fn verify_jwt(keys: &KeyStore, token: &str) -> Option<Claims> {
+	keys.verify::<Claims>(token)
+}
+
+// access token for the user, shaped by the email verification policy
+pub async fn create_access_token(
+	app_state: &AppState,
+	user: &User,
+	session: &SessionRef<'_>,
+) -> Result<String, ApiError> {
+	// every way of signing in ends here, suspended and banned accounts included.
+	// OAuth clients get theirs from create_client_access_token, the grants check
+	// the account status before
+	account_status::ensure_active(user.status, user.suspended_until)?;
 
-	let validation = Validation::default();
+	let email_verified = user.is_email_verified();
 
-	decode::<Claims>(
-			token,
-			&DecodingKey::from_secret(secret.as_bytes()),
-			&validation,
+	let (role, limited) = match app_state.config.email_verification.policy {
+			EmailVerificationPolicy::BlockLogin if !email_verified => {
+					return Err(ApiError::Forbidden("Email address is not verified".into()));
+			}
+			// unverified accounts never get more than the basic role
+			EmailVerificationPolicy::LimitClaims if !email_verified => (UserRole::User, true),
+			_ => (user.role, false),
+	};
+	let perms = permissions::resolve(&app_state.pool, &user.id, role, !limited).await?;
+
+	create_jwt(
+			&app_state.keys,
+			&user.id,
+			&false,
+			&role,
+			email_verified,
+			session,
+			perms,
+			app_state.config.tokens.access_ttl,
 	)
-	.map(|data| data.claims)
-	.ok()
+	.map_err(|e| ApiError::Other(format!("Error when trying to generate access token {:?}", e)))
 }
 
-pub fn generate_tokens(
-	user_id: &str,
-	is_premium: &bool,
-	role: &UserRole,
+// Access token for an OAuth client, acting for the user or, without one, on its own behalf.
+// What it allows is described by the scope, never by the user's role
+pub fn create_client_access_token(
+	app_state: &AppState,
+	client_id: &str,
+	user: Option<&User>,
+	scope: &str,
+) -> Result<String, ApiError> {
+	let now = Utc::now().timestamp();
+	let claims = Claims {
+			sub: user.map_or_else(|| client_id.to_owned(), |user| user.id.clone()),
+			role: UserRole::User,
+			is_premium: false,
+			email_verified: user.is_some_and(User::is_email_verified),
+			jti: Uuid::now_v7().to_string(),
+			iat: now as usize,
+			exp: (now + app_state.config.tokens.access_ttl) as usize,
+			auth_time: None,
+			client_id: Some(client_id.to_owned()),
+			scope: Some(scope.to_owned()),
+			perms: Vec::new(),
+			sid: None,
+	};
+
+	app_state
+			.keys
+			.sign(&claims)
+			.map_err(|e| ApiError::Other(format!("Error when trying to generate access token {:?}", e)))
+}
+
+pub async fn generate_tokens(
+	app_state: &AppState,
+	req: &HttpRequest,
+	user: &User,
 ) -> Result<Tokens, ApiError> {
-	let access_token =
-			create_jwt(user_id, is_premium, role, ACCESS_TOKEN_EXPIRATION).map_err(|e| {
-					ApiError::Other(format!(
-							"Error when trying to generate access token {:?}",
-							e
-					))
-			})?;
-	let refresh_token =
-			create_jwt(user_id, is_premium, role, REFRESH_TOKEN_EXPIRATION).map_err(|e| {
-					ApiError::Other(format!(
-							"Error when trying to generate refresh token {:?}",
-							e
-					))
-			})?;
+	// every login is a new session, its refresh tokens being a family of the same id
+	let session_id = Uuid::new_v4().to_string();
+	let session = SessionRef {
+			id: &session_id,
+			auth_time: Utc::now(),
+	};
+	let access_token = create_access_token(app_state, user, &session).await?;
+
+	// refresh tokens are opaque and tracked by the store
+	let refresh_token = refresh_store::issue(
+			&app_state.pool,
+			&session_id,
+			&user.id,
+			app_state.config.tokens.refresh_ttl,
+	)
+	.await?
+	.token;
+	sessions::record_use(app_state, req, &session_id, &user.id).await?;
 
 	Ok(Tokens {
 			access_token,
@@ -82,7 +171,23 @@ pub fn generate_tokens(
 	})
 }
 
-pub fn refresh_token(
+pub fn build_refresh_cookie(refresh_token: String, app_state: &AppState) -> Cookie<'static> {
+	Cookie::build("refresh_token", refresh_token)
+			.http_only(true)
+			.secure(app_state.config.is_production())
+			.path("/")
+			.same_site(SameSite::Strict)
+			.max_age(time::Duration::seconds(app_state.config.tokens.refresh_ttl))
+			.finish()
+}
+
+pub fn build_refresh_removal_cookie(app_state: &AppState) -> Cookie<'static> {
+	let mut cookie = build_refresh_cookie(String::new(), app_state);
+	cookie.make_removal();
+	cookie
+}
+
+pub async fn refresh_token(
 	req: HttpRequest,
 	app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, ApiError> {
@@ -90,25 +195,57 @@ pub fn refresh_token(
 	let cookie = req
 			.cookie("refresh_token")
 			.ok_or_else(|| ApiError::Unauthorized("Refresh token cookie not found".into()))?;
-	let refresh_token = cookie.value();
-
-	// getting claims if refresh token is valid
-	let claims = verify_jwt(refresh_token)
-			.ok_or_else(|| ApiError::Unauthorized("Invalid Refresh Token".into()))?;
 
-	// if no drop (no api errors above)
-	// then generate new tokens
-	let new_tokens = generate_tokens(&claims.sub, &claims.is_premium, &claims.role)?;
+	// rotating refresh token, reused tokens revoke the whole family
+	let rotated = match refresh_store::rotate(
+			&app_state.pool,
+			cookie.value(),
+			None,
+			app_state.config.tokens.refresh_ttl,
+	)
+	.await
+	{
+			Ok(rotated) => rotated,
+			Err(e) => {
+					let metadata = json!({ "reason": e.to_string() });
+					record_refresh(&app_state, &req, None, Outcome::Failure, metadata).await;
+					return Err(e);
+			}
+	};
 
-	let new_refresh_cookie = Cookie::build("refresh_token", new_tokens.refresh_token)
-			.http_only(true)
-			.secure(app_state.is_production)
-			.path("/")
-			.same_site(SameSite::Strict)
-			.max_age(time::Duration::days(30))
-			.finish();
+	// role and permissions are taken from database so changes apply on the next refresh
+	let user = find_user_by_id(&rotated.user_id, &app_state.pool).await?;
+	let session = SessionRef {
+			id: &rotated.family_id,
+			auth_time: rotated.auth_time,
+	};
+	let access_token = match create_access_token(&app_state, &user, &session).await {
+			Ok(access_token) => access_token,
+			Err(e) => {
+					let metadata = json!({ "reason": e.to_string() });
+					record_refresh(&app_state, &req, Some(&user.id), Outcome::Failure, metadata).await;
+					return Err(e);
+			}
+	};
+	sessions::record_use(&app_state, &req, &rotated.family_id, &user.id).await?;
+	record_refresh(&app_state, &req, Some(&user.id), Outcome::Success, json!({})).await;
 
 	Ok(HttpResponse::Ok()
-			.cookie(new_refresh_cookie)
-			.json(serde_json::json!({"access_token": new_tokens.access_token})))
+			.cookie(build_refresh_cookie(rotated.token, &app_state))
+			.json(json!({"access_token": access_token})))
+}
+
+async fn record_refresh(
+	app_state: &AppState,
+	req: &HttpRequest,
+	user_id: Option<&str>,
+	outcome: Outcome,
+	metadata: serde_json::Value,
+) {
+	record_auth_event(app_state, req, "auth.refresh", user_id, outcome, metadata).await;
+}
+
+// public keys for services verifying our tokens on their own
+pub async fn jwks(app_state: web::Data<AppState>) -> HttpResponse {
+	HttpResponse::Ok().json(app_state.keys.jwks())
 }
@@ -0,0 +1,449 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+};
+use rand::RngCore;
+use rsa::{
+    RsaPublicKey, pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, traits::PublicKeyParts,
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::common::config::JwtConfig;
+
+// secrets that were shipped in examples or committed configs at some point
+const KNOWN_WEAK_SECRETS: [&str; 4] = ["roscript-backend", "secret", "changeme", "jwt-secret"];
+const MIN_SECRET_LENGTH: usize = 32;
+const MIN_SECRET_ENTROPY_BITS: f64 = 128.0;
+
+// DER prefix of a SubjectPublicKeyInfo holding a raw 32 byte Ed25519 key
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+    // only asymmetric keys are published
+    jwk: Option<Jwk>,
+}
+
+// Keys used to sign and verify our JWTs.
+// Tokens are signed with a single active key and carry its `kid`, while every
+// configured verification key is accepted, so a new key can be rolled out
+// before the old one is removed.
+pub struct KeyStore {
+    signing: SigningKey,
+    verification: HashMap<String, VerificationKey>,
+}
+
+fn read_pem(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Cannot read key file {path}: {e}"))
+}
+
+fn pem_body(pem: &str) -> Result<Vec<u8>, String> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .map(str::trim)
+        .collect();
+
+    STANDARD
+        .decode(body)
+        .map_err(|e| format!("Invalid PEM encoding: {e}"))
+}
+
+fn public_jwk(kid: &str, pem: &str) -> Result<(Algorithm, Jwk), String> {
+    let common = |key_algorithm| CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(key_algorithm),
+        key_id: Some(kid.to_owned()),
+        ..Default::default()
+    };
+
+    let rsa_key = RsaPublicKey::from_public_key_pem(pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        .ok();
+    if let Some(rsa_key) = rsa_key {
+        let jwk = Jwk {
+            common: common(KeyAlgorithm::RS256),
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(rsa_key.n().to_bytes_be()),
+                e: URL_SAFE_NO_PAD.encode(rsa_key.e().to_bytes_be()),
+            }),
+        };
+        return Ok((Algorithm::RS256, jwk));
+    }
+
+    let der = pem_body(pem)?;
+    if der.len() == ED25519_SPKI_PREFIX.len() + 32 && der.starts_with(&ED25519_SPKI_PREFIX) {
+        let jwk = Jwk {
+            common: common(KeyAlgorithm::EdDSA),
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(&der[ED25519_SPKI_PREFIX.len()..]),
+            }),
+        };
+        return Ok((Algorithm::EdDSA, jwk));
+    }
+
+    Err(format!(
+        "Public key {kid} is neither an RSA nor an Ed25519 key"
+    ))
+}
+
+// Strength of the secret as if drawn at random from the smallest usual alphabet its
+// characters fit in: hex, base64 or printable ASCII. The characters themselves say
+// little, a random hex string has just 4 bits in each of them
+fn estimated_entropy_bits(secret: &str) -> f64 {
+    let is_base64 = |c: char| c.is_ascii_alphanumeric() || "+/-_=".contains(c);
+    let alphabet = if secret.chars().all(|c| c.is_ascii_hexdigit()) {
+        16
+    } else if secret.chars().all(is_base64) {
+        64
+    } else {
+        95
+    };
+
+    // random strings use a fair share of their alphabet, which
+    // repeated or low-variety ones like "aaaa..." or "1212..." don't
+    let length = secret.chars().count();
+    let distinct = secret.chars().collect::<HashSet<_>>().len();
+    if distinct * 2 < alphabet.min(length) {
+        return 0.0;
+    }
+
+    length as f64 * (alphabet as f64).log2()
+}
+
+pub fn validate_secret(secret: &str) -> Result<(), String> {
+    if KNOWN_WEAK_SECRETS.contains(&secret) {
+        return Err("JWT_SECRET is a well-known default value".to_string());
+    }
+    if secret.len() < MIN_SECRET_LENGTH {
+        return Err(format!(
+            "JWT_SECRET must be at least {MIN_SECRET_LENGTH} bytes long"
+        ));
+    }
+    if estimated_entropy_bits(secret) < MIN_SECRET_ENTROPY_BITS {
+        return Err("JWT_SECRET is too predictable, generate it randomly".to_string());
+    }
+
+    Ok(())
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; 48];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+impl KeyStore {
+    pub fn hmac(kid: &str, secret: &[u8]) -> Self {
+        let mut verification = HashMap::new();
+        verification.insert(
+            kid.to_owned(),
+            VerificationKey {
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret),
+                jwk: None,
+            },
+        );
+
+        Self {
+            signing: SigningKey {
+                kid: kid.to_owned(),
+                algorithm: Algorithm::HS256,
+                key: EncodingKey::from_secret(secret),
+            },
+            verification,
+        }
+    }
+
+    // `public_keys` holds every key tokens may still be signed with,
+    // including the public half of the active signing key
+    pub fn asymmetric(
+        kid: &str,
+        private_key_pem: &str,
+        public_keys: &[(String, String)],
+    ) -> Result<Self, String> {
+        let mut verification = HashMap::new();
+        for (public_kid, pem) in public_keys {
+            let (algorithm, jwk) = public_jwk(public_kid, pem)?;
+            let key = DecodingKey::from_jwk(&jwk)
+                .map_err(|e| format!("Invalid public key {public_kid}: {e}"))?;
+
+            verification.insert(
+                public_kid.clone(),
+                VerificationKey {
+                    algorithm,
+                    key,
+                    jwk: Some(jwk),
+                },
+            );
+        }
+
+        let algorithm = verification
+            .get(kid)
+            .map(|key| key.algorithm)
+            .ok_or_else(|| format!("Public key for the signing key {kid} is not configured"))?;
+
+        let key = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(private_key_pem.as_bytes()),
+            _ => EncodingKey::from_ed_pem(private_key_pem.as_bytes()),
+        }
+        .map_err(|e| format!("Invalid private key {kid}: {e}"))?;
+
+        Ok(Self {
+            signing: SigningKey {
+                kid: kid.to_owned(),
+                algorithm,
+                key,
+            },
+            verification,
+        })
+    }
+
+    // Built once at startup from the already validated config.
+    // Outside of production a weak secret only warns, and a missing one is
+    // replaced by a random secret that lives as long as the process.
+    pub fn from_config(config: &JwtConfig) -> Result<Self, String> {
+        if config.algorithm == Algorithm::HS256 {
+            let secret = match &config.secret {
+                Some(secret) => {
+                    if let Err(e) = validate_secret(secret) {
+                        eprintln!("[JWT KEYS]: {e}, this is only allowed outside of production");
+                    }
+                    secret.clone()
+                }
+                None => {
+                    eprintln!(
+                        "[JWT KEYS]: JWT_SECRET is not set, using a random secret, tokens won't survive a restart"
+                    );
+                    generate_secret()
+                }
+            };
+
+            return Ok(Self::hmac(&config.kid, secret.as_bytes()));
+        }
+
+        let private_key_path = config
+            .private_key_path
+            .as_deref()
+            .ok_or_else(|| "JWT_PRIVATE_KEY must be set for asymmetric signing".to_string())?;
+        let public_keys = config
+            .public_key_paths
+            .iter()
+            .map(|(kid, path)| Ok((kid.clone(), read_pem(path)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let store = Self::asymmetric(&config.kid, &read_pem(private_key_path)?, &public_keys)?;
+        if store.signing.algorithm != config.algorithm {
+            return Err(format!(
+                "Signing key {} doesn't match JWT_ALGORITHM {:?}",
+                config.kid, config.algorithm
+            ));
+        }
+
+        Ok(store)
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.signing.algorithm
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.signing.algorithm);
+        header.kid = Some(self.signing.kid.clone());
+
+        encode(&header, claims, &self.signing.key)
+    }
+
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let header = decode_header(token).ok()?;
+
+        // tokens issued before key ids were introduced have no kid
+        let kid = header.kid.as_deref().unwrap_or(&self.signing.kid);
+        let key = self.verification.get(kid)?;
+
+        // algorithm comes from our key, never from the token header
+        decode::<T>(token, &key.key, &Validation::new(key.algorithm))
+            .map(|data| data.claims)
+            .ok()
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verification
+                .values()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct TestClaims {
+        sub: String,
+        exp: usize,
+    }
+
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/tests/fixtures/keys/{name}", env!("CARGO_MANIFEST_DIR"));
+        fs::read_to_string(path).unwrap()
+    }
+
+    // signs with the `name` key, `public_keys` lists the kids of every key accepted
+    fn store(kid: &str, name: &str, public_keys: &[(&str, &str)]) -> KeyStore {
+        let public_keys: Vec<_> = public_keys
+            .iter()
+            .map(|(kid, name)| (kid.to_string(), fixture(&format!("{name}.pub.pem"))))
+            .collect();
+
+        KeyStore::asymmetric(kid, &fixture(&format!("{name}.pem")), &public_keys).unwrap()
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "user".into(),
+            exp: usize::MAX,
+        }
+    }
+
+    fn verifies(keys: &KeyStore, token: &str) -> bool {
+        keys.verify::<TestClaims>(token)
+            .is_some_and(|claims| claims.sub == "user")
+    }
+
+    #[test]
+    fn random_secrets_are_accepted() {
+        for secret in [
+            // 32 hex characters, 128 bits
+            "9f86d081884c7d659a2feaa0c55ad015",
+            "Zm9vYmFyYmF6cXV4cXV1eGNvcmdlZ3JhdWx0Z2FycGx5",
+            generate_secret().as_str(),
+        ] {
+            assert_eq!(validate_secret(secret), Ok(()), "{secret}");
+        }
+    }
+
+    #[test]
+    fn weak_secrets_are_rejected() {
+        for secret in [
+            "roscript-backend",
+            "9f86d081884c7d659a2feaa0c55ad01",
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            "1212121212121212121212121212121212121212",
+            "00000000000000000000000000000001",
+        ] {
+            assert!(validate_secret(secret).is_err(), "{secret}");
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip_with_every_algorithm() {
+        let stores = [
+            KeyStore::hmac("hmac", b"a secret of some length"),
+            store("rsa", "rsa", &[("rsa", "rsa")]),
+            store("ed", "ed25519", &[("ed", "ed25519")]),
+        ];
+
+        for keys in stores {
+            let token = keys.sign(&claims()).unwrap();
+            assert!(verifies(&keys, &token));
+        }
+    }
+
+    #[test]
+    fn verification_key_is_picked_by_kid() {
+        let old = store("old", "rsa", &[("old", "rsa")]);
+        let token = old.sign(&claims()).unwrap();
+
+        // rolled over to a new signing key, the old one is still accepted
+        let rolled_over = store("new", "ed25519", &[("new", "ed25519"), ("old", "rsa")]);
+        assert!(verifies(&rolled_over, &token));
+
+        // and refused once it's removed
+        let old_removed = store("new", "ed25519", &[("new", "ed25519")]);
+        assert!(!verifies(&old_removed, &token));
+    }
+
+    #[test]
+    fn token_without_kid_is_verified_with_the_signing_key() {
+        let secret = b"a secret of some length";
+        let keys = KeyStore::hmac("default", secret);
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims(),
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+
+        assert!(verifies(&keys, &token));
+    }
+
+    #[test]
+    fn algorithm_of_the_header_is_ignored() {
+        let keys = store("rsa", "rsa", &[("rsa", "rsa")]);
+        // HS256 keyed with the public key, the classic algorithm confusion
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("rsa".into());
+        let forged = encode(
+            &header,
+            &claims(),
+            &EncodingKey::from_secret(fixture("rsa.pub.pem").as_bytes()),
+        )
+        .unwrap();
+
+        assert!(!verifies(&keys, &forged));
+    }
+
+    #[test]
+    fn jwks_publishes_only_public_keys() {
+        assert!(KeyStore::hmac("hmac", b"secret").jwks().keys.is_empty());
+
+        let keys = store("new", "ed25519", &[("new", "ed25519"), ("old", "rsa")]);
+        let jwks = serde_json::to_value(keys.jwks()).unwrap();
+        let mut published: Vec<_> = jwks["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|jwk| {
+                // `d` and the RSA primes are the private parts
+                for private in ["d", "p", "q", "dp", "dq", "qi"] {
+                    assert!(jwk.get(private).is_none());
+                }
+                (jwk["kid"].as_str().unwrap(), jwk["kty"].as_str().unwrap())
+            })
+            .collect();
+        published.sort();
+
+        assert_eq!(published, [("new", "OKP"), ("old", "RSA")]);
+    }
+}
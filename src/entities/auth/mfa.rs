@@ -0,0 +1,681 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        AppState, audit::Outcome, errors::api_error::ApiError, request::client_ip,
+        tokens::hash_token,
+    },
+    entities::{
+        auth::{
+            dto::{
+                AuthResponse, MfaVerifyDto, RecoveryCodesResponse, TotpCodeDto,
+                TotpEnrollmentResponse,
+            },
+            jwt, record_auth_event,
+            throttle::{self, ThrottleKey},
+        },
+        user::find_user_by_id,
+    },
+    models::auth::{Claims, MfaChallengeClaims},
+};
+
+const TOTP_ISSUER: &str = "rust-backend";
+const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: usize = 6;
+const MFA_CHALLENGE_PURPOSE: &str = "mfa_pending";
+const MFA_CHALLENGE_TTL: i64 = 300;
+const RECOVERY_CODES_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+#[derive(sqlx::FromRow)]
+struct TotpState {
+    username: String,
+    totp_secret: Option<String>,
+    totp_enabled_at: Option<DateTime<Utc>>,
+}
+
+async fn find_totp_state(app_state: &AppState, user_id: &str) -> Result<TotpState, ApiError> {
+    sqlx::query_as::<_, TotpState>(
+        "SELECT username, totp_secret, totp_enabled_at FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("User {} not found", user_id)))
+}
+
+pub async fn is_totp_enabled(app_state: &AppState, user_id: &str) -> Result<bool, ApiError> {
+    Ok(find_totp_state(app_state, user_id)
+        .await?
+        .totp_enabled_at
+        .is_some())
+}
+
+fn build_totp(secret: &str, username: &str) -> Result<TOTP, ApiError> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| ApiError::InternalServer(format!("Invalid TOTP secret: {:?}", e)))?;
+
+    // ':' separates issuer and account in the otpauth label
+    TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP,
+        bytes,
+        Some(TOTP_ISSUER.to_string()),
+        username.replace(':', "_"),
+    )
+    .map_err(|e| ApiError::InternalServer(format!("Cannot build TOTP: {:?}", e)))
+}
+
+// Accepts the current step and one step on each side for clock drift.
+// The matched step is stored, so a code can't be used twice
+async fn check_totp_code(
+    app_state: &AppState,
+    user_id: &str,
+    totp: &TOTP,
+    code: &str,
+) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp() as u64;
+    let step = [now - TOTP_STEP, now, now + TOTP_STEP]
+        .into_iter()
+        .find(|time| totp.check(code.trim(), *time))
+        .map(|time| (time / TOTP_STEP) as i64)
+        .ok_or_else(|| ApiError::Other("Invalid two-factor code".into()))?;
+
+    let result = sqlx::query(
+        "
+			UPDATE users
+			SET totp_last_used_step = $1
+			WHERE id = $2 AND (totp_last_used_step IS NULL OR totp_last_used_step < $1)
+		",
+    )
+    .bind(step)
+    .bind(user_id)
+    .execute(&app_state.pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Other("Two-factor code was already used".into()));
+    }
+
+    Ok(())
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let chars: String = (0..10)
+        .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+        .collect();
+
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+// replaces every previous recovery code, the plain codes are shown only once
+async fn replace_recovery_codes(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: &str,
+) -> Result<Vec<String>, ApiError> {
+    let codes: Vec<String> = (0..RECOVERY_CODES_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+
+    for code in &codes {
+        sqlx::query("INSERT INTO mfa_recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id)
+            .bind(hash_token(&normalize_recovery_code(code)))
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(codes)
+}
+
+async fn use_recovery_code(app_state: &AppState, user_id: &str, code: &str) -> Result<(), ApiError> {
+    let result = sqlx::query(
+        "
+			UPDATE mfa_recovery_codes
+			SET used_at = NOW()
+			WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+		",
+    )
+    .bind(user_id)
+    .bind(hash_token(&normalize_recovery_code(code)))
+    .execute(&app_state.pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Other("Invalid two-factor code".into()));
+    }
+
+    Ok(())
+}
+
+// A challenge is exchanged for tokens only once. It's claimed before the code is
+// checked so concurrent exchanges can't both pass, and released again when the
+// code is wrong so a mistyped code doesn't end the login
+async fn claim_mfa_challenge(
+    app_state: &AppState,
+    challenge: &MfaChallengeClaims,
+) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM used_mfa_challenges WHERE expires_at <= NOW()")
+        .execute(&app_state.pool)
+        .await?;
+
+    let result = sqlx::query(
+        "
+			INSERT INTO used_mfa_challenges (jti, expires_at)
+			VALUES ($1, $2)
+			ON CONFLICT (jti) DO NOTHING
+		",
+    )
+    .bind(&challenge.jti)
+    .bind(DateTime::from_timestamp(challenge.exp as i64, 0))
+    .execute(&app_state.pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Unauthorized("Invalid or expired MFA token".into()));
+    }
+
+    Ok(())
+}
+
+async fn release_mfa_challenge(app_state: &AppState, jti: &str) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM used_mfa_challenges WHERE jti = $1")
+        .bind(jti)
+        .execute(&app_state.pool)
+        .await?;
+
+    Ok(())
+}
+
+pub fn create_mfa_challenge(app_state: &AppState, user_id: &str) -> Result<String, ApiError> {
+    let now = Utc::now().timestamp();
+    let claims = MfaChallengeClaims {
+        sub: user_id.to_owned(),
+        purpose: MFA_CHALLENGE_PURPOSE.to_string(),
+        jti: Uuid::new_v4().to_string(),
+        iat: now as usize,
+        exp: (now + MFA_CHALLENGE_TTL) as usize,
+    };
+
+    app_state
+        .keys
+        .sign(&claims)
+        .map_err(|e| ApiError::Other(format!("Error when trying to generate MFA token {:?}", e)))
+}
+
+pub async fn enroll_totp(
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let state = find_totp_state(&app_state, &claims.sub).await?;
+    if state.totp_enabled_at.is_some() {
+        return Err(ApiError::Other(
+            "Two-factor authentication is already enabled".into(),
+        ));
+    }
+
+    // a new enrollment replaces an unconfirmed one
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    let totp = build_totp(&secret, &state.username)?;
+
+    sqlx::query("UPDATE users SET totp_secret = $1, totp_last_used_step = NULL WHERE id = $2")
+        .bind(&secret)
+        .bind(&claims.sub)
+        .execute(&app_state.pool)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(TotpEnrollmentResponse {
+        otpauth_uri: totp.get_url(),
+        secret,
+    }))
+}
+
+// a signed in user guessing codes is throttled like a login, so a stolen access token
+// alone can't brute force its way to disabling the second factor
+async fn check_code_throttled(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user_id: &str,
+    totp: &TOTP,
+    code: &str,
+) -> Result<(), ApiError> {
+    let ip = client_ip(req, &app_state.config);
+    let keys = [ThrottleKey::Account(user_id), ThrottleKey::Ip(&ip)];
+    let attempt = throttle::count_attempt(app_state, &keys).await?;
+
+    // only a wrong code counts
+    if let Err(e) = check_totp_code(app_state, user_id, totp, code).await {
+        if !matches!(e, ApiError::Other(_)) {
+            attempt.forgive(app_state).await?;
+        }
+        return Err(e);
+    }
+    attempt.forgive(app_state).await
+}
+
+pub async fn confirm_totp(
+    req: HttpRequest,
+    dto: web::Json<TotpCodeDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let state = find_totp_state(&app_state, &claims.sub).await?;
+    if state.totp_enabled_at.is_some() {
+        return Err(ApiError::Other(
+            "Two-factor authentication is already enabled".into(),
+        ));
+    }
+    let secret = state
+        .totp_secret
+        .ok_or_else(|| ApiError::Other("Two-factor enrollment was not started".into()))?;
+
+    let totp = build_totp(&secret, &state.username)?;
+    check_code_throttled(&app_state, &req, &claims.sub, &totp, &dto.code).await?;
+
+    // enabled together with its recovery codes, never one without the other
+    let mut tx = app_state.pool.begin().await?;
+
+    let enabled = sqlx::query(
+        "UPDATE users SET totp_enabled_at = NOW() WHERE id = $1 AND totp_enabled_at IS NULL",
+    )
+    .bind(&claims.sub)
+    .execute(&mut *tx)
+    .await?;
+    if enabled.rows_affected() == 0 {
+        return Err(ApiError::Other(
+            "Two-factor authentication is already enabled".into(),
+        ));
+    }
+
+    let recovery_codes = replace_recovery_codes(&mut tx, &claims.sub).await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }))
+}
+
+pub async fn disable_totp(
+    req: HttpRequest,
+    dto: web::Json<TotpCodeDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let state = find_totp_state(&app_state, &claims.sub).await?;
+    let secret = match (state.totp_secret, state.totp_enabled_at) {
+        (Some(secret), Some(_)) => secret,
+        _ => {
+            return Err(ApiError::Other(
+                "Two-factor authentication is not enabled".into(),
+            ));
+        }
+    };
+
+    let totp = build_totp(&secret, &state.username)?;
+    check_code_throttled(&app_state, &req, &claims.sub, &totp, &dto.code).await?;
+
+    let mut tx = app_state.pool.begin().await?;
+
+    sqlx::query(
+        "
+			UPDATE users
+			SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_used_step = NULL
+			WHERE id = $1
+		",
+    )
+    .bind(&claims.sub)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+        .bind(&claims.sub)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn is_totp_code(code: &str) -> bool {
+    code.len() == TOTP_DIGITS && code.chars().all(|c| c.is_ascii_digit())
+}
+
+// accepts either a TOTP code or a recovery code, spending the challenge on the right one
+async fn check_second_factor(
+    app_state: &AppState,
+    ip: &str,
+    challenge: &MfaChallengeClaims,
+    code: &str,
+) -> Result<(), ApiError> {
+    // guesses count against the same account as password failures
+    let keys = [ThrottleKey::Account(&challenge.sub), ThrottleKey::Ip(ip)];
+    let attempt = throttle::count_attempt(app_state, &keys).await?;
+
+    let state = find_totp_state(app_state, &challenge.sub).await?;
+    let secret = match (state.totp_secret, state.totp_enabled_at) {
+        (Some(secret), Some(_)) => secret,
+        _ => {
+            attempt.forgive(app_state).await?;
+            return Err(ApiError::Unauthorized(
+                "Invalid or expired MFA token".into(),
+            ));
+        }
+    };
+
+    if let Err(e) = claim_mfa_challenge(app_state, challenge).await {
+        attempt.forgive(app_state).await?;
+        return Err(e);
+    }
+
+    let checked = if is_totp_code(code) {
+        let totp = build_totp(&secret, &state.username)?;
+        check_totp_code(app_state, &challenge.sub, &totp, code).await
+    } else {
+        use_recovery_code(app_state, &challenge.sub, code).await
+    };
+    // only a wrong code counts
+    if let Err(e) = checked {
+        release_mfa_challenge(app_state, &challenge.jti).await?;
+        if !matches!(e, ApiError::Other(_)) {
+            attempt.forgive(app_state).await?;
+        }
+        return Err(e);
+    }
+    attempt.forgive(app_state).await?;
+    throttle::clear_account(app_state, &challenge.sub).await
+}
+
+// second step of the login, every guess ends up in the audit log
+pub async fn verify_mfa(
+    req: HttpRequest,
+    dto: web::Json<MfaVerifyDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let challenge = app_state
+        .keys
+        .verify::<MfaChallengeClaims>(&dto.mfa_token)
+        .filter(|c| c.purpose == MFA_CHALLENGE_PURPOSE)
+        .ok_or_else(|| ApiError::Unauthorized("Invalid or expired MFA token".into()))?;
+
+    let code = dto.code.trim();
+    let factor = if is_totp_code(code) {
+        "totp"
+    } else {
+        "recovery_code"
+    };
+    let record_failure = |e: &ApiError| {
+        record_auth_event(
+            &app_state,
+            &req,
+            "auth.mfa",
+            Some(&challenge.sub),
+            Outcome::Failure,
+            json!({ "factor": factor, "reason": e.to_string() }),
+        )
+    };
+
+    let ip = client_ip(&req, &app_state.config);
+    if let Err(e) = check_second_factor(&app_state, &ip, &challenge, code).await {
+        record_failure(&e).await;
+        return Err(e);
+    }
+
+    let user = find_user_by_id(&challenge.sub, &app_state.pool).await?;
+    let tokens = match jwt::generate_tokens(&app_state, &req, &user).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            record_failure(&e).await;
+            return Err(e);
+        }
+    };
+
+    record_auth_event(
+        &app_state,
+        &req,
+        "auth.mfa",
+        Some(&user.id),
+        Outcome::Success,
+        json!({ "factor": factor }),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok()
+        .cookie(jwt::build_refresh_cookie(tokens.refresh_token, &app_state))
+        .json(AuthResponse {
+            user,
+            access_token: tokens.access_token,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        common::testing,
+        entities::auth::{dto::LoginDto, login},
+        models::user::User,
+    };
+
+    async fn enable_totp(app_state: &AppState, user_id: &str, username: &str) -> TOTP {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        sqlx::query("UPDATE users SET totp_secret = $1, totp_enabled_at = NOW() WHERE id = $2")
+            .bind(&secret)
+            .bind(user_id)
+            .execute(&app_state.pool)
+            .await
+            .unwrap();
+
+        build_totp(&secret, username).unwrap()
+    }
+
+    async fn verify(
+        app_state: &web::Data<AppState>,
+        mfa_token: &str,
+        code: String,
+    ) -> Result<HttpResponse, ApiError> {
+        verify_mfa(
+            TestRequest::default().to_http_request(),
+            web::Json(MfaVerifyDto {
+                mfa_token: mfa_token.to_string(),
+                code,
+            }),
+            app_state.clone(),
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn challenge_is_exchanged_for_tokens_once(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let totp = enable_totp(&app_state, &user.id, &user.username).await;
+        let mut tx = app_state.pool.begin().await.unwrap();
+        let recovery_codes = replace_recovery_codes(&mut tx, &user.id).await.unwrap();
+        tx.commit().await.unwrap();
+        let mfa_token = create_mfa_challenge(&app_state, &user.id).unwrap();
+
+        verify(&app_state, &mfa_token, totp.generate_current().unwrap())
+            .await
+            .unwrap();
+
+        // a second factor the replay didn't spend yet doesn't revive the challenge
+        assert!(matches!(
+            verify(&app_state, &mfa_token, recovery_codes[0].clone()).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+
+        // and the recovery code wasn't burnt by the refused replay
+        let next_login = create_mfa_challenge(&app_state, &user.id).unwrap();
+        verify(&app_state, &next_login, recovery_codes[0].clone())
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn wrong_code_leaves_the_challenge_usable(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let totp = enable_totp(&app_state, &user.id, &user.username).await;
+        let mfa_token = create_mfa_challenge(&app_state, &user.id).unwrap();
+
+        verify(&app_state, &mfa_token, "not-a-recovery-code".into())
+            .await
+            .err()
+            .unwrap();
+        verify(&app_state, &mfa_token, totp.generate_current().unwrap())
+            .await
+            .unwrap();
+    }
+
+    async fn recovery_code_hashes(app_state: &AppState, user_id: &str) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT code_hash FROM mfa_recovery_codes WHERE user_id = $1 ORDER BY code_hash",
+        )
+        .bind(user_id)
+        .fetch_all(&app_state.pool)
+        .await
+        .unwrap()
+    }
+
+    async fn confirm(
+        app_state: &web::Data<AppState>,
+        user: &User,
+        code: String,
+    ) -> Result<HttpResponse, ApiError> {
+        confirm_totp(
+            TestRequest::default().to_http_request(),
+            web::Json(TotpCodeDto { code }),
+            testing::signed_in(user).await,
+            app_state.clone(),
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn confirmation_enables_totp_with_its_recovery_codes(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        enroll_totp(testing::signed_in(&user).await, app_state.clone())
+            .await
+            .unwrap();
+        let state = find_totp_state(&app_state, &user.id).await.unwrap();
+        let totp = build_totp(&state.totp_secret.unwrap(), &user.username).unwrap();
+
+        confirm(&app_state, &user, totp.generate_current().unwrap())
+            .await
+            .unwrap();
+
+        let state = find_totp_state(&app_state, &user.id).await.unwrap();
+        assert!(state.totp_enabled_at.is_some());
+        let codes = recovery_code_hashes(&app_state, &user.id).await;
+        assert_eq!(codes.len(), RECOVERY_CODES_COUNT);
+
+        // a second confirmation doesn't hand out new codes
+        assert!(
+            confirm(&app_state, &user, totp.generate_current().unwrap())
+                .await
+                .is_err()
+        );
+        assert_eq!(recovery_code_hashes(&app_state, &user.id).await, codes);
+    }
+
+    #[sqlx::test]
+    async fn disabling_guesses_are_throttled(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let totp = enable_totp(&app_state, &user.id, &user.username).await;
+
+        let disable = async |code: String| {
+            disable_totp(
+                TestRequest::default().to_http_request(),
+                web::Json(TotpCodeDto { code }),
+                testing::signed_in(&user).await,
+                app_state.clone(),
+            )
+            .await
+        };
+        let code = totp.generate_current().unwrap();
+        let wrong_code = format!("{}{}", (code.as_bytes()[0] - b'0' + 1) % 10, &code[1..]);
+
+        // the attempt after `backoff_after` failures is the last one let through
+        for _ in 0..=app_state.config.login_throttle.backoff_after {
+            assert!(matches!(
+                disable(wrong_code.clone()).await,
+                Err(ApiError::Other(_))
+            ));
+        }
+        assert!(matches!(
+            disable(code).await,
+            Err(ApiError::TooManyRequests { .. })
+        ));
+        assert!(is_totp_enabled(&app_state, &user.id).await.unwrap());
+    }
+
+    #[sqlx::test]
+    async fn second_factor_ends_the_pending_login(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let totp = enable_totp(&app_state, &user.id, &user.username).await;
+
+        let response = login(
+            TestRequest::default().to_http_request(),
+            web::Json(LoginDto {
+                username_or_email: user.username.clone(),
+                password: "secret12".into(),
+            }),
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        let body = testing::json_body(response).await;
+        let mfa_token = body["mfa_token"].as_str().unwrap();
+        assert_eq!(
+            testing::audit_outcomes(&app_state.pool, "auth.login").await,
+            ["pending"]
+        );
+
+        // a code of no step the drift window accepts
+        let now = Utc::now().timestamp() as u64;
+        let valid = [now - TOTP_STEP, now, now + TOTP_STEP].map(|time| totp.generate(time));
+        let wrong = (0..)
+            .map(|n| format!("{n:06}"))
+            .find(|code| !valid.contains(code))
+            .unwrap();
+
+        verify(&app_state, mfa_token, wrong).await.err().unwrap();
+        verify(&app_state, mfa_token, totp.generate_current().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            testing::audit_outcomes(&app_state.pool, "auth.mfa").await,
+            ["failure", "success"]
+        );
+    }
+}
@@ -1,11 +1,14 @@
 use std::rc::Rc;
 
 use actix_web::{
-    dev::{forward_ready, ServiceRequest, ServiceResponse, Transform}, Error, HttpMessage
+    dev::{forward_ready, ServiceRequest, ServiceResponse, Transform}, web, Error, HttpMessage
 };
 use futures_util::future::{LocalBoxFuture, Ready, ok};
 
-use crate::{common::errors::api_error::ApiError, entities::auth::jwt::verify_jwt};
+use crate::{
+    common::{AppState, errors::api_error::ApiError},
+    entities::auth::jwt::verify_jwt,
+};
 
 pub struct JwtAuth;
 
@@ -55,9 +58,9 @@ where
 
         Box::pin(async move {
             let auth_header = match req.headers().get("Authorization") {
-                Some(header) => header.to_str().map_err(|_| {
-                    return ApiError::Unauthorized("Invalid Authorization header".into());
-                })?,
+                Some(header) => header
+                    .to_str()
+                    .map_err(|_| ApiError::Unauthorized("Invalid Authorization header".into()))?,
                 None => {
                     return Err(
                         ApiError::Unauthorized("Authorization header missing".into()).into(),
@@ -73,15 +76,43 @@ where
 
             let token = &auth_header[7..];
 
-            match verify_jwt(token) {
-                Some(claims) => {
-                    req.extensions_mut().insert(claims);
-                },
+            let app_state = req
+                .app_data::<web::Data<AppState>>()
+                .ok_or_else(|| ApiError::InternalServer("App state is not configured".into()))?;
+
+            let claims = match verify_jwt(&app_state.keys, token) {
+                Some(claims) => claims,
                 None => {
                     return Err(ApiError::Unauthorized("Invalid or expired token".into()).into());
                 },
             };
 
+            // tokens delegated to OAuth clients are for resource servers, not for our own api
+            if claims.client_id.is_some() {
+                return Err(
+                    ApiError::Unauthorized("Token was issued to an OAuth client".into()).into(),
+                );
+            }
+
+            // signature is fine, but the token could have been revoked before expiring
+            if app_state
+                .revocations
+                .is_revoked(&app_state.pool, &claims)
+                .await?
+            {
+                return Err(ApiError::Unauthorized("Token has been revoked".into()).into());
+            }
+
+            // tokens stay valid until they expire, unless the status is checked on every request
+            if app_state.config.account_status.per_request {
+                app_state
+                    .account_statuses
+                    .check(&app_state.pool, &claims.sub)
+                    .await?;
+            }
+
+            req.extensions_mut().insert(claims);
+
             svc.call(req).await
         })
     }
@@ -1 +1,2 @@
-pub mod jwt_auth;
\ No newline at end of file
+pub mod jwt_auth;
+pub mod rate_limit;
\ No newline at end of file
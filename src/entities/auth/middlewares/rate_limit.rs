@@ -0,0 +1,183 @@
+use std::rc::Rc;
+
+use actix_web::{
+    Error, HttpMessage, ResponseError,
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    web,
+};
+use futures_util::future::{LocalBoxFuture, Ready, ok};
+
+use crate::{
+    common::{
+        AppState,
+        errors::api_error::ApiError,
+        rate_limit::{Decision, Limit},
+        request::client_ip,
+    },
+    models::auth::Claims,
+};
+
+const X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+pub type KeyExtractor = Rc<dyn Fn(&ServiceRequest) -> Option<String>>;
+
+// What requests are counted by. Requests without a user or a custom key are counted per ip
+#[derive(Clone)]
+pub enum RateLimitKey {
+    Ip,
+    // `Claims.sub`, JwtAuth has to run before the limit
+    User,
+    Custom(KeyExtractor),
+}
+
+impl RateLimitKey {
+    fn extract(&self, req: &ServiceRequest, app_state: &AppState) -> String {
+        let key = match self {
+            RateLimitKey::Ip => None,
+            RateLimitKey::User => req
+                .extensions()
+                .get::<Claims>()
+                .map(|claims| format!("user:{}", claims.sub)),
+            RateLimitKey::Custom(extract) => extract(req).map(|key| format!("custom:{key}")),
+        };
+
+        key.unwrap_or_else(|| format!("ip:{}", client_ip(req.request(), &app_state.config)))
+    }
+}
+
+// Token bucket of `capacity` requests refilled over `period` seconds, every
+// limit has its own buckets so routes sharing a name share their budget
+#[derive(Clone)]
+pub struct RateLimit {
+    name: &'static str,
+    limit: Limit,
+    key: RateLimitKey,
+}
+
+impl RateLimit {
+    pub fn new(name: &'static str, capacity: u32, period: u64, key: RateLimitKey) -> Self {
+        assert!(
+            capacity > 0 && period > 0,
+            "rate limit `{name}` needs a capacity and a period"
+        );
+
+        Self {
+            name,
+            limit: Limit { capacity, period },
+            key,
+        }
+    }
+
+    pub fn per_ip(name: &'static str, capacity: u32, period: u64) -> Self {
+        Self::new(name, capacity, period, RateLimitKey::Ip)
+    }
+
+    pub fn per_user(name: &'static str, capacity: u32, period: u64) -> Self {
+        Self::new(name, capacity, period, RateLimitKey::User)
+    }
+
+    pub fn custom(
+        name: &'static str,
+        capacity: u32,
+        period: u64,
+        extract: impl Fn(&ServiceRequest) -> Option<String> + 'static,
+    ) -> Self {
+        Self::new(
+            name,
+            capacity,
+            period,
+            RateLimitKey::Custom(Rc::new(extract)),
+        )
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Transform = RateLimitMiddleware<S>;
+    type Error = Error;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            rate_limit: self.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    rate_limit: RateLimit,
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert(X_RATELIMIT_LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(X_RATELIMIT_RESET, HeaderValue::from(decision.reset_after));
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let rate_limit = self.rate_limit.clone();
+
+        Box::pin(async move {
+            let app_state = req
+                .app_data::<web::Data<AppState>>()
+                .cloned()
+                .ok_or_else(|| ApiError::InternalServer("App state is not configured".into()))?;
+
+            let key = format!(
+                "{}:{}",
+                rate_limit.name,
+                rate_limit.key.extract(&req, &app_state)
+            );
+
+            // a broken store shouldn't take the routes it protects down with it
+            let decision = match app_state.rate_limits.take(key, rate_limit.limit).await {
+                Ok(decision) => decision,
+                Err(e) => {
+                    eprintln!("[RATE LIMIT]: {e}");
+                    return Ok(svc.call(req).await?.map_into_left_body());
+                }
+            };
+
+            if !decision.allowed {
+                let mut response = ApiError::TooManyRequests {
+                    message: "Too many requests, try again later".into(),
+                    retry_after: decision.retry_after,
+                }
+                .error_response();
+                insert_headers(response.headers_mut(), &decision);
+
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut res = svc.call(req).await?;
+            insert_headers(res.headers_mut(), &decision);
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
@@ -1,25 +1,49 @@
-pub mod constants;
+pub mod account;
+pub mod account_status;
 pub mod dto;
+pub mod email_verification;
 pub mod jwt;
+pub mod keys;
+pub mod mfa;
 pub mod middlewares;
+pub mod passkeys;
+pub mod password_policy;
+pub mod permissions;
+pub mod password_reset;
 pub mod guards;
+pub mod refresh_store;
+pub mod revocation;
+pub mod sessions;
+pub mod social;
+pub mod throttle;
 
 use crate::{
-    entities::auth::dto::{AuthResponse, CreateUserDto, LoginDto},
-    common::{AppState, errors::api_error::ApiError},
-    models::user::User,
-    entities::user::{check_user_exists, dto::CheckUserExistsDto},
+    entities::auth::dto::{AuthResponse, CreateUserDto, LoginDto, MfaChallengeResponse},
+    common::{
+        AppState,
+        audit::{self, AuditEvent, Outcome},
+        config::EmailVerificationPolicy,
+        errors::api_error::ApiError,
+        password_hasher::{PasswordHashers, Verification},
+        request::client_ip,
+        tokens::hash_token,
+    },
+    models::{
+        auth::Claims,
+        user::{AccountStatus, User, UserWithPassword},
+    },
+    entities::{
+        auth::throttle::ThrottleKey,
+        user::{check_user_exists, dto::CheckUserExistsDto},
+    },
 };
-use actix_web::{
-    HttpResponse,
-    cookie::{Cookie, SameSite, time},
-    web,
-};
-use bcrypt::{DEFAULT_COST, hash, verify};
+use actix_web::{HttpRequest, HttpResponse, web};
+use serde_json::json;
 use uuid::Uuid;
 use validator::Validate;
 
 pub async fn register(
+    req: HttpRequest,
     new_user: web::Json<CreateUserDto>,
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, ApiError> {
@@ -30,23 +54,32 @@ pub async fn register(
         }?,
     }
 
+    password_policy::check(
+        &app_state,
+        "password",
+        &new_user.password,
+        &new_user.username,
+        &new_user.email,
+    )
+    .await?;
+
     // hash password
-    let password_hash = match hash(&new_user.password, DEFAULT_COST) {
-        Ok(h) => h,
-        Err(_) => {
-            return Err(ApiError::InternalServer(
-                "Something went wrong on server side".to_string(),
-            ));
-        }
-    };
+    let password_hash = app_state.passwords.hash(&new_user.password).await?;
 
     // generating uuid for new user
     let new_user_id = Uuid::new_v4();
 
+    // logging in has to wait for the email to be verified
+    let status = match app_state.config.email_verification.policy {
+        EmailVerificationPolicy::BlockLogin => AccountStatus::PendingVerification,
+        _ => AccountStatus::Active,
+    };
+
     let query = "
-			INSERT INTO users (username, email, password, id)
-			VALUES ($1, $2, $3, $4)
-			RETURNING id, username, email, role, created_at
+			INSERT INTO users (username, email, password, id, status)
+			VALUES ($1, $2, $3, $4, $5)
+			RETURNING id, username, email, role, email_verified_at, status, suspended_until,
+				display_name, avatar_url, bio, locale, timezone, created_at
 		";
 
     // creating new user and after that fetching it
@@ -55,20 +88,44 @@ pub async fn register(
         .bind(&new_user.email)
         .bind(&password_hash)
         .bind(new_user_id.to_string())
+        .bind(status)
         .fetch_one(&app_state.pool)
         .await?;
 
+    let ip = client_ip(&req, &app_state.config);
+    app_state
+        .audit
+        .record(AuditEvent {
+            event_type: "auth.register",
+            actor_id: Some(&result.id),
+            target_id: Some(&result.id),
+            ip: Some(&ip),
+            user_agent: audit::user_agent(&req),
+            outcome: Outcome::Success,
+            metadata: json!({ "username": result.username, "email": result.email }),
+        })
+        .await;
+
+    // a failed delivery shouldn't fail the registration, the link can be resent
+    if let Err(e) =
+        email_verification::send_verification_email(&app_state, &result.id, &result.email).await
+    {
+        eprintln!("[EMAIL VERIFICATION]: {e}");
+    }
+
+    // no tokens until the email is verified
+    if app_state.config.email_verification.policy == EmailVerificationPolicy::BlockLogin {
+        return Ok(HttpResponse::Created().json(serde_json::json!({
+            "user": result,
+            "email_verification_required": true,
+        })));
+    }
+
     // generating access and refresh tokens
-    let tokens = jwt::generate_tokens(&result.id, &false, &result.role)?;
+    let tokens = jwt::generate_tokens(&app_state, &req, &result).await?;
 
     // build cookie for refresh token that stands by httpOnly parameter
-    let refresh_cookie = Cookie::build("refresh_token", tokens.refresh_token)
-        .http_only(true)
-        .secure(app_state.is_production)
-        .path("/")
-        .same_site(SameSite::Strict)
-        .max_age(time::Duration::days(30))
-        .finish();
+    let refresh_cookie = jwt::build_refresh_cookie(tokens.refresh_token, &app_state);
 
     Ok(HttpResponse::Ok()
         .cookie(refresh_cookie)
@@ -79,40 +136,519 @@ pub async fn register(
 }
 
 pub async fn login(
+    req: HttpRequest,
     dto: web::Json<LoginDto>,
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, ApiError> {
-    // is user existing
-    let user = check_user_exists(
+    let ip = client_ip(&req, &app_state.config);
+
+    let user = match check_user_exists(
         CheckUserExistsDto {
             username_or_email: dto.username_or_email.clone(),
         },
         &app_state.pool,
     )
-    .await?;
+    .await
+    {
+        Ok(user) => Some(user),
+        Err(ApiError::NotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+
+    // unknown names are throttled all the same
+    let account = match &user {
+        Some(user) => user.id.clone(),
+        None => throttle::normalize_identifier(&dto.username_or_email),
+    };
 
-    // is password valid
-    let is_password_valid = verify(&dto.password, &user.password)
-        .map_err(|_| ApiError::Other("Error when tried to compare passwords".into()))?;
-    if !is_password_valid {
-        return Err(ApiError::Other("Incorrect Password".into()));
+    // the identifier can be a password typed into the wrong field, so it's never
+    // recorded as such. Known accounts are the target, unknown names only a hash
+    let user_id = user.as_ref().map(|user| user.id.clone());
+    let record_failure = |e: &ApiError| {
+        let metadata = match &user_id {
+            Some(_) => json!({ "reason": e.to_string() }),
+            None => json!({ "identifier_hash": hash_token(&account), "reason": e.to_string() }),
+        };
+        record_auth_event(
+            &app_state,
+            &req,
+            "auth.login",
+            user_id.as_deref(),
+            Outcome::Failure,
+            metadata,
+        )
+    };
+    let keys = [ThrottleKey::Account(&account), ThrottleKey::Ip(&ip)];
+    let attempt = match throttle::count_attempt(&app_state, &keys).await {
+        Ok(attempt) => attempt,
+        Err(e) => {
+            record_failure(&e).await;
+            return Err(e);
+        }
+    };
+
+    let (user, verification) =
+        match verify_credentials(&app_state.passwords, user, &dto.password).await {
+            Ok(verified) => verified,
+            Err(e) => {
+                record_failure(&e).await;
+                return Err(e);
+            }
+        };
+    attempt.forgive(&app_state).await?;
+
+    // told only to whoever knows the password
+    if let Err(e) = account_status::ensure_active(user.status, user.suspended_until) {
+        record_failure(&e).await;
+        return Err(e);
     }
 
-    // generating access and refresh tokens
-    let tokens = jwt::generate_tokens(&user.id, &false, &user.role)?;
+    // the plain password is only ever at hand here, so older hashes are upgraded now
+    if verification == Verification::Outdated
+        && let Err(e) = rehash_password(&app_state, &user, &dto.password).await
+    {
+        eprintln!("[PASSWORD REHASH]: {e}");
+    }
+
+    // with two-factor enabled the password only earns a short-lived challenge,
+    // verify_mfa records how the sign in ends
+    if mfa::is_totp_enabled(&app_state, &user.id).await? {
+        record_auth_event(
+            &app_state,
+            &req,
+            "auth.login",
+            Some(&user.id),
+            Outcome::Pending,
+            json!({ "mfa_required": true }),
+        )
+        .await;
+
+        return Ok(HttpResponse::Ok().json(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token: mfa::create_mfa_challenge(&app_state, &user.id)?,
+        }));
+    }
 
-    let refresh_cookie = Cookie::build("refresh_token", tokens.refresh_token)
-        .http_only(true)
-        .secure(app_state.is_production)
-        .path("/")
-        .same_site(SameSite::Strict)
-        .max_age(time::Duration::days(30))
-        .finish();
+    // failures are only forgotten once the whole sign in succeeded,
+    // otherwise the password would reset the count for second factor guesses
+    throttle::clear_account(&app_state, &user.id).await?;
+
+    // generating access and refresh tokens, refused for unverified emails under block_login
+    let user = User::from(user);
+    let tokens = jwt::generate_tokens(&app_state, &req, &user).await?;
+
+    let refresh_cookie = jwt::build_refresh_cookie(tokens.refresh_token, &app_state);
+
+    record_auth_event(
+        &app_state,
+        &req,
+        "auth.login",
+        Some(&user.id),
+        Outcome::Success,
+        json!({ "mfa_required": false }),
+    )
+    .await;
 
     Ok(HttpResponse::Ok()
         .cookie(refresh_cookie)
         .json(AuthResponse {
-            user: User::from(user),
+            user,
             access_token: tokens.access_token,
         }))
 }
+
+// Sign ins and the other steps of authentication. Failed ones are recorded
+// against the account when it's known, but not as done by its owner
+pub async fn record_auth_event(
+    app_state: &AppState,
+    req: &HttpRequest,
+    event_type: &str,
+    user_id: Option<&str>,
+    outcome: Outcome,
+    metadata: serde_json::Value,
+) {
+    let ip = client_ip(req, &app_state.config);
+    let actor_id = match outcome {
+        Outcome::Failure => None,
+        Outcome::Success | Outcome::Pending => user_id,
+    };
+
+    app_state
+        .audit
+        .record(AuditEvent {
+            event_type,
+            actor_id,
+            target_id: user_id,
+            ip: Some(&ip),
+            user_agent: audit::user_agent(req),
+            outcome,
+            metadata,
+        })
+        .await;
+}
+
+// Same error, after the same hashing work, whether the user is missing or the password is wrong
+async fn verify_credentials(
+    passwords: &PasswordHashers,
+    user: Option<UserWithPassword>,
+    password: &str,
+) -> Result<(UserWithPassword, Verification), ApiError> {
+    let Some(user) = user else {
+        passwords.verify_dummy(password).await;
+        return Err(ApiError::InvalidCredentials);
+    };
+
+    // an unreadable hash can't be told apart either, it costs a dummy
+    // verification like an unknown user
+    match passwords.verify(password, &user.password).await {
+        Ok(Verification::Invalid) => Err(ApiError::InvalidCredentials),
+        Ok(verification) => Ok((user, verification)),
+        Err(_) => {
+            passwords.verify_dummy(password).await;
+            Err(ApiError::InvalidCredentials)
+        }
+    }
+}
+
+// only replaces the hash that was verified, a password changed meanwhile stays
+async fn rehash_password(
+    app_state: &AppState,
+    user: &UserWithPassword,
+    password: &str,
+) -> Result<(), ApiError> {
+    let password_hash = app_state.passwords.hash(password).await?;
+
+    sqlx::query("UPDATE users SET password = $1 WHERE id = $2 AND password = $3")
+        .bind(&password_hash)
+        .bind(&user.id)
+        .bind(&user.password)
+        .execute(&app_state.pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn logout(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    // revoking the session behind the cookie, missing cookie means nothing to revoke.
+    // Its access tokens stop working too, like on DELETE /me/sessions/{id}
+    if let Some(cookie) = req.cookie("refresh_token")
+        && let Some((user_id, session_id)) =
+            refresh_store::revoke_by_token(&app_state.pool, cookie.value()).await?
+    {
+        app_state
+            .revocations
+            .revoke_session(&app_state.pool, &user_id, &session_id)
+            .await?;
+    }
+
+    Ok(HttpResponse::NoContent()
+        .cookie(jwt::build_refresh_removal_cookie(&app_state))
+        .finish())
+}
+
+pub async fn logout_all(
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    revoke_all_sessions(&app_state, &claims.sub).await?;
+
+    Ok(HttpResponse::NoContent()
+        .cookie(jwt::build_refresh_removal_cookie(&app_state))
+        .finish())
+}
+
+// ends every session of the user: refresh tokens in their store, access tokens via the denylist
+pub async fn revoke_all_sessions(app_state: &AppState, user_id: &str) -> Result<(), ApiError> {
+    refresh_store::revoke_all_for_user(&app_state.pool, user_id).await?;
+    app_state
+        .revocations
+        .revoke_user(&app_state.pool, user_id)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        FromRequest, HttpMessage, ResponseError, body::to_bytes, cookie::Cookie,
+        test::TestRequest,
+    };
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        common::{
+            config::{PasswordHashAlgorithm, PasswordHashingConfig},
+            password_hasher::create_password_hashers,
+            testing,
+        },
+        entities::auth::refresh_store::rotate,
+        models::auth::UserRole,
+    };
+
+    const TTL: i64 = 60 * 60;
+
+    async fn app_state(pool: PgPool) -> (web::Data<AppState>, String) {
+        let user_id = Uuid::new_v4().to_string();
+        sqlx::query("INSERT INTO users (id, username, email, password) VALUES ($1, $1, $1, '')")
+            .bind(&user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        (app_state, user_id)
+    }
+
+    fn with_cookie(refresh_token: &str) -> HttpRequest {
+        TestRequest::default()
+            .cookie(Cookie::new("refresh_token", refresh_token.to_owned()))
+            .to_http_request()
+    }
+
+    #[sqlx::test]
+    async fn refresh_hands_out_a_new_cookie_once(pool: PgPool) {
+        let (app_state, user_id) = app_state(pool).await;
+        let issued =
+            refresh_store::issue(&app_state.pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+                .await
+                .unwrap();
+
+        let response = jwt::refresh_token(with_cookie(&issued.token), app_state.clone())
+            .await
+            .unwrap();
+        let rotated = response
+            .cookies()
+            .find(|cookie| cookie.name() == "refresh_token")
+            .unwrap();
+        assert_ne!(rotated.value(), issued.token);
+
+        assert!(matches!(
+            jwt::refresh_token(with_cookie(&issued.token), app_state.clone()).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn logout_ends_only_the_session_of_the_cookie(pool: PgPool) {
+        let (app_state, user_id) = app_state(pool).await;
+        let this_device =
+            refresh_store::issue(&app_state.pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+                .await
+                .unwrap();
+        let other_device =
+            refresh_store::issue(&app_state.pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+                .await
+                .unwrap();
+
+        let response = logout(with_cookie(&this_device.token), app_state.clone())
+            .await
+            .unwrap();
+        let removal = response
+            .cookies()
+            .find(|cookie| cookie.name() == "refresh_token")
+            .unwrap();
+        assert_eq!(removal.value(), "");
+
+        assert!(
+            rotate(&app_state.pool, &this_device.token, None, TTL)
+                .await
+                .is_err()
+        );
+        assert!(
+            rotate(&app_state.pool, &other_device.token, None, TTL)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[sqlx::test]
+    async fn logout_all_ends_every_session(pool: PgPool) {
+        let (app_state, user_id) = app_state(pool).await;
+        let sessions = [
+            refresh_store::issue(&app_state.pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+                .await
+                .unwrap(),
+            refresh_store::issue(&app_state.pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+                .await
+                .unwrap(),
+        ];
+
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut().insert(Claims {
+            sub: user_id,
+            role: UserRole::User,
+            is_premium: false,
+            email_verified: true,
+            jti: Uuid::new_v4().to_string(),
+            iat: 0,
+            exp: usize::MAX,
+            auth_time: None,
+            client_id: None,
+            scope: None,
+            perms: Vec::new(),
+            sid: None,
+        });
+        let claims = web::ReqData::<Claims>::extract(&req).await.unwrap();
+        logout_all(claims, app_state.clone()).await.unwrap();
+
+        for session in sessions {
+            assert!(rotate(&app_state.pool, &session.token, None, TTL).await.is_err());
+        }
+    }
+
+    fn passwords() -> PasswordHashers {
+        create_password_hashers(&PasswordHashingConfig {
+            algorithm: PasswordHashAlgorithm::Argon2id,
+            stored_algorithm: PasswordHashAlgorithm::Argon2id,
+            bcrypt_cost: 4,
+            argon2_memory_kib: 64,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    async fn user_with_password(passwords: &PasswordHashers, password: &str) -> UserWithPassword {
+        UserWithPassword {
+            id: Uuid::new_v4().to_string(),
+            username: "alice".into(),
+            password: passwords.hash(password).await.unwrap(),
+            email: "alice@example.com".into(),
+            role: UserRole::User,
+            email_verified_at: None,
+            status: AccountStatus::Active,
+            suspended_until: None,
+            display_name: None,
+            avatar_url: None,
+            bio: None,
+            locale: None,
+            timezone: None,
+        }
+    }
+
+    async fn rendered(error: ApiError) -> (u16, Vec<(String, String)>, String) {
+        let response = error.error_response();
+        let status = response.status().as_u16();
+        let mut headers: Vec<(String, String)> = response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+            .collect();
+        headers.sort();
+        let body = to_bytes(response.into_body()).await.unwrap();
+
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn unknown_user_and_wrong_password_look_the_same() {
+        let passwords = passwords();
+        let user = user_with_password(&passwords, "secret12").await;
+
+        let unknown = verify_credentials(&passwords, None, "secret12")
+            .await
+            .err()
+            .unwrap();
+        let wrong = verify_credentials(&passwords, Some(user), "secret13")
+            .await
+            .err()
+            .unwrap();
+
+        let unknown = rendered(unknown).await;
+        assert_eq!(unknown.0, 401);
+        assert_eq!(unknown, rendered(wrong).await);
+    }
+
+    #[actix_web::test]
+    async fn unreadable_hash_looks_like_a_wrong_password() {
+        let passwords = passwords();
+        let mut user = user_with_password(&passwords, "secret12").await;
+        user.password = "not a password hash".into();
+
+        let unreadable = verify_credentials(&passwords, Some(user), "secret12")
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            rendered(unreadable).await,
+            rendered(ApiError::InvalidCredentials).await
+        );
+    }
+
+    #[actix_web::test]
+    async fn right_password_is_accepted() {
+        let passwords = passwords();
+        let user = user_with_password(&passwords, "secret12").await;
+        let id = user.id.clone();
+
+        let (user, verification) = verify_credentials(&passwords, Some(user), "secret12")
+            .await
+            .unwrap();
+        assert_eq!(user.id, id);
+        assert_eq!(verification, Verification::Valid);
+    }
+
+    #[actix_web::test]
+    async fn bcrypt_hash_from_before_phc_strings_is_accepted_and_outdated() {
+        let passwords = passwords();
+        let mut user = user_with_password(&passwords, "secret12").await;
+        user.password = bcrypt::hash("secret12", 4).unwrap();
+
+        let (_, verification) = verify_credentials(&passwords, Some(user), "secret12")
+            .await
+            .unwrap();
+        assert_eq!(verification, Verification::Outdated);
+    }
+
+    #[sqlx::test]
+    async fn failed_login_never_records_the_identifier(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        for username_or_email in ["alice", "hunter2-typed-as-name"] {
+            let dto = LoginDto {
+                username_or_email: username_or_email.to_owned(),
+                password: "wrong-password".to_owned(),
+            };
+            assert!(
+                login(
+                    TestRequest::default().to_http_request(),
+                    web::Json(dto),
+                    app_state.clone(),
+                )
+                .await
+                .is_err()
+            );
+        }
+
+        let recorded = sqlx::query_as::<_, (Option<String>, serde_json::Value)>(
+            "
+				SELECT target_id, metadata FROM audit_events
+				WHERE event_type = 'auth.login' AND outcome = 'failure'
+				ORDER BY created_at
+			",
+        )
+        .fetch_all(&app_state.pool)
+        .await
+        .unwrap();
+        assert_eq!(recorded.len(), 2);
+
+        // a known account is the target
+        let (target_id, metadata) = &recorded[0];
+        assert_eq!(target_id.as_deref(), Some(user.id.as_str()));
+        assert!(metadata.get("identifier_hash").is_none());
+
+        // an unknown name is only kept as a hash
+        let (target_id, metadata) = &recorded[1];
+        assert_eq!(*target_id, None);
+        assert_eq!(
+            metadata["identifier_hash"],
+            hash_token("hunter2-typed-as-name")
+        );
+        assert!(!metadata.to_string().contains("hunter2"));
+    }
+}
@@ -0,0 +1,570 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::json;
+use uuid::Uuid;
+use webauthn_rs::{
+    Webauthn, WebauthnBuilder,
+    prelude::{CredentialID, Passkey, PasskeyAuthentication, PublicKeyCredential, Url},
+};
+
+use crate::{
+    common::{AppState, audit::Outcome, config::WebAuthnConfig, errors::api_error::ApiError},
+    entities::{
+        auth::{
+            dto::{
+                AuthResponse, PasskeyChallengeResponse, PasskeyLoginDto, PasskeyLoginStartDto,
+                PasskeyRegistrationDto, PasskeyRegistrationStartDto,
+            },
+            account::check_current_password,
+            jwt, record_auth_event,
+        },
+        user::{check_user_exists, dto::CheckUserExistsDto, find_user_by_id},
+    },
+    models::{auth::Claims, credential::UserCredential},
+};
+
+const CHALLENGE_TTL: i64 = 300;
+const REGISTRATION: &str = "registration";
+const AUTHENTICATION: &str = "authentication";
+
+pub fn create_webauthn(config: &WebAuthnConfig) -> Result<Webauthn, String> {
+    let origin = Url::parse(&config.rp_origin)
+        .map_err(|e| format!("Invalid origin {}: {e}", config.rp_origin))?;
+
+    WebauthnBuilder::new(&config.rp_id, &origin)
+        .and_then(|builder| builder.rp_name(&config.rp_name).build())
+        .map_err(|e| e.to_string())
+}
+
+fn encode_credential_id(id: &CredentialID) -> String {
+    URL_SAFE_NO_PAD.encode(id.as_ref())
+}
+
+fn user_handle(user_id: &str) -> Result<Uuid, ApiError> {
+    Uuid::parse_str(user_id)
+        .map_err(|_| ApiError::InternalServer(format!("User id {user_id} is not a uuid")))
+}
+
+async fn find_passkeys(app_state: &AppState, user_id: &str) -> Result<Vec<Passkey>, ApiError> {
+    let rows = sqlx::query_scalar::<_, String>(
+        "SELECT passkey FROM user_credentials WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_all(&app_state.pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            serde_json::from_str(row)
+                .map_err(|e| ApiError::InternalServer(format!("Corrupted passkey: {e}")))
+        })
+        .collect()
+}
+
+async fn save_challenge<T: Serialize>(
+    app_state: &AppState,
+    user_id: &str,
+    ceremony: &str,
+    state: &T,
+) -> Result<String, ApiError> {
+    let state = serde_json::to_string(state)
+        .map_err(|e| ApiError::InternalServer(format!("Cannot store ceremony state: {e}")))?;
+    let id = Uuid::new_v4().to_string();
+
+    // abandoned ceremonies are cleaned up by the next one
+    sqlx::query("DELETE FROM webauthn_challenges WHERE user_id = $1 AND expires_at <= NOW()")
+        .bind(user_id)
+        .execute(&app_state.pool)
+        .await?;
+
+    sqlx::query(
+        "
+			INSERT INTO webauthn_challenges (id, user_id, ceremony, state, expires_at)
+			VALUES ($1, $2, $3, $4, $5)
+		",
+    )
+    .bind(&id)
+    .bind(user_id)
+    .bind(ceremony)
+    .bind(state)
+    .bind(Utc::now() + Duration::seconds(CHALLENGE_TTL))
+    .execute(&app_state.pool)
+    .await?;
+
+    Ok(id)
+}
+
+// a challenge answers exactly one finish call, whatever its outcome
+async fn take_challenge<T: DeserializeOwned>(
+    app_state: &AppState,
+    id: &str,
+    ceremony: &str,
+) -> Result<(String, T), ApiError> {
+    let (user_id, state) = sqlx::query_as::<_, (String, String)>(
+        "
+			DELETE FROM webauthn_challenges
+			WHERE id = $1 AND ceremony = $2 AND expires_at > NOW()
+			RETURNING user_id, state
+		",
+    )
+    .bind(id)
+    .bind(ceremony)
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or_else(|| ApiError::Other("Invalid or expired passkey challenge".into()))?;
+
+    let state = serde_json::from_str(&state)
+        .map_err(|e| ApiError::InternalServer(format!("Corrupted ceremony state: {e}")))?;
+
+    Ok((user_id, state))
+}
+
+// the password is asked again, a stolen access token alone can't add a login
+// method that outlives it
+pub async fn start_registration(
+    dto: web::Json<PasskeyRegistrationStartDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    check_current_password(&app_state, &claims.sub, &dto.current_password).await?;
+    let user = find_user_by_id(&claims.sub, &app_state.pool).await?;
+
+    // the same authenticator can't be registered twice
+    let exclude = find_passkeys(&app_state, &user.id)
+        .await?
+        .iter()
+        .map(|passkey| passkey.cred_id().clone())
+        .collect::<Vec<_>>();
+
+    let (options, state) = app_state
+        .webauthn
+        .start_passkey_registration(
+            user_handle(&user.id)?,
+            &user.email,
+            &user.username,
+            Some(exclude),
+        )
+        .map_err(|e| ApiError::InternalServer(format!("Cannot start passkey registration: {e}")))?;
+
+    let challenge_id = save_challenge(&app_state, &user.id, REGISTRATION, &state).await?;
+
+    Ok(HttpResponse::Ok().json(PasskeyChallengeResponse {
+        challenge_id,
+        options,
+    }))
+}
+
+pub async fn finish_registration(
+    dto: web::Json<PasskeyRegistrationDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (user_id, state) = take_challenge(&app_state, &dto.challenge_id, REGISTRATION).await?;
+    if user_id != claims.sub {
+        return Err(ApiError::Other("Invalid or expired passkey challenge".into()));
+    }
+
+    let passkey = app_state
+        .webauthn
+        .finish_passkey_registration(&dto.credential, &state)
+        .map_err(|e| ApiError::Other(format!("Passkey registration failed: {e}")))?;
+
+    let serialized = serde_json::to_string(&passkey)
+        .map_err(|e| ApiError::InternalServer(format!("Cannot store passkey: {e}")))?;
+    let name = dto
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or("Passkey");
+
+    // credential_id is unique, a credential already bound to another account is refused
+    let credential = sqlx::query_as::<_, UserCredential>(
+        "
+			INSERT INTO user_credentials (id, user_id, credential_id, name, passkey)
+			VALUES ($1, $2, $3, $4, $5)
+			RETURNING id, name, sign_count, created_at, last_used_at
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(&user_id)
+    .bind(encode_credential_id(passkey.cred_id()))
+    .bind(name)
+    .bind(serialized)
+    .fetch_one(&app_state.pool)
+    .await?;
+
+    Ok(HttpResponse::Created().json(credential))
+}
+
+pub async fn start_login(
+    dto: web::Json<PasskeyLoginStartDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    // unknown accounts and accounts without passkeys answer the same
+    let unavailable = || ApiError::Unauthorized("Passkey login is not available".into());
+
+    let user = match check_user_exists(
+        CheckUserExistsDto {
+            username_or_email: dto.username_or_email.clone(),
+        },
+        &app_state.pool,
+    )
+    .await
+    {
+        Ok(user) => user,
+        Err(ApiError::NotFound(_)) => return Err(unavailable()),
+        Err(e) => return Err(e),
+    };
+
+    let passkeys = find_passkeys(&app_state, &user.id).await?;
+    if passkeys.is_empty() {
+        return Err(unavailable());
+    }
+
+    let (options, state) = app_state
+        .webauthn
+        .start_passkey_authentication(&passkeys)
+        .map_err(|e| ApiError::InternalServer(format!("Cannot start passkey login: {e}")))?;
+
+    let challenge_id = save_challenge(&app_state, &user.id, AUTHENTICATION, &state).await?;
+
+    Ok(HttpResponse::Ok().json(PasskeyChallengeResponse {
+        challenge_id,
+        options,
+    }))
+}
+
+// stands in for the password check of `login`, the session is issued the same way
+// the stored credential that made the assertion, moved to its new sign count
+async fn use_passkey(
+    app_state: &AppState,
+    user_id: &str,
+    credential: &PublicKeyCredential,
+    state: &PasskeyAuthentication,
+) -> Result<String, ApiError> {
+    let result = app_state
+        .webauthn
+        .finish_passkey_authentication(credential, state)
+        .map_err(|e| ApiError::Unauthorized(format!("Passkey verification failed: {e}")))?;
+
+    let credential_id = encode_credential_id(result.cred_id());
+    let (id, stored) = sqlx::query_as::<_, (String, String)>(
+        "SELECT id, passkey FROM user_credentials WHERE credential_id = $1 AND user_id = $2",
+    )
+    .bind(&credential_id)
+    .bind(user_id)
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or_else(|| ApiError::Unauthorized("Passkey is not registered".into()))?;
+
+    let mut passkey: Passkey = serde_json::from_str(&stored)
+        .map_err(|e| ApiError::InternalServer(format!("Corrupted passkey: {e}")))?;
+    passkey.update_credential(&result);
+    let serialized = serde_json::to_string(&passkey)
+        .map_err(|e| ApiError::InternalServer(format!("Cannot store passkey: {e}")))?;
+
+    // Authenticators with a counter must move it forward on every use,
+    // checked again here against concurrent logins with the same assertion state
+    let counter = i64::from(result.counter());
+    let updated = sqlx::query(
+        "
+			UPDATE user_credentials
+			SET passkey = $1, sign_count = $2, last_used_at = NOW()
+			WHERE id = $3 AND ($2 = 0 OR sign_count < $2)
+		",
+    )
+    .bind(serialized)
+    .bind(counter)
+    .bind(&id)
+    .execute(&app_state.pool)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(ApiError::Unauthorized(
+            "Passkey sign count went backwards, the authenticator may be cloned".into(),
+        ));
+    }
+
+    Ok(id)
+}
+
+// failures are audited too, a cloned authenticator shows up there
+pub async fn finish_login(
+    req: HttpRequest,
+    dto: web::Json<PasskeyLoginDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (user_id, state) = take_challenge(&app_state, &dto.challenge_id, AUTHENTICATION).await?;
+    let record_failure = |e: &ApiError| {
+        record_auth_event(
+            &app_state,
+            &req,
+            "auth.passkey_login",
+            Some(&user_id),
+            Outcome::Failure,
+            json!({ "reason": e.to_string() }),
+        )
+    };
+
+    let credential = match use_passkey(&app_state, &user_id, &dto.credential, &state).await {
+        Ok(credential) => credential,
+        Err(e) => {
+            record_failure(&e).await;
+            return Err(e);
+        }
+    };
+
+    let user = find_user_by_id(&user_id, &app_state.pool).await?;
+    let tokens = match jwt::generate_tokens(&app_state, &req, &user).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            record_failure(&e).await;
+            return Err(e);
+        }
+    };
+
+    record_auth_event(
+        &app_state,
+        &req,
+        "auth.passkey_login",
+        Some(&user.id),
+        Outcome::Success,
+        json!({ "credential_id": credential }),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok()
+        .cookie(jwt::build_refresh_cookie(tokens.refresh_token, &app_state))
+        .json(AuthResponse {
+            user,
+            access_token: tokens.access_token,
+        }))
+}
+
+pub async fn list_credentials(
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let credentials = sqlx::query_as::<_, UserCredential>(
+        "
+			SELECT id, name, sign_count, created_at, last_used_at
+			FROM user_credentials
+			WHERE user_id = $1
+			ORDER BY created_at
+		",
+    )
+    .bind(&claims.sub)
+    .fetch_all(&app_state.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(credentials))
+}
+
+pub async fn delete_credential(
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+
+    let deleted = sqlx::query("DELETE FROM user_credentials WHERE id = $1 AND user_id = $2")
+        .bind(&id)
+        .bind(&claims.sub)
+        .execute(&app_state.pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!("Passkey {} not found", id)));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use sqlx::PgPool;
+    use webauthn_authenticator_rs::{WebauthnAuthenticator, softpasskey::SoftPasskey};
+    use webauthn_rs::prelude::{
+        CreationChallengeResponse, PublicKeyCredential, RequestChallengeResponse,
+    };
+
+    use super::*;
+    use crate::{common::testing, models::user::User};
+
+    type Authenticator = WebauthnAuthenticator<SoftPasskey>;
+
+    fn authenticator() -> Authenticator {
+        // a platform authenticator, which verifies the user itself
+        WebauthnAuthenticator::new(SoftPasskey::new(true))
+    }
+
+    fn origin(app_state: &AppState) -> Url {
+        Url::parse(&app_state.config.webauthn.rp_origin).unwrap()
+    }
+
+    fn challenge<T: DeserializeOwned>(body: serde_json::Value) -> (String, T) {
+        let challenge_id = body["challenge_id"].as_str().unwrap().to_string();
+        (
+            challenge_id,
+            serde_json::from_value(body["options"].clone()).unwrap(),
+        )
+    }
+
+    async fn register(
+        app_state: &web::Data<AppState>,
+        user: &User,
+        authenticator: &mut Authenticator,
+    ) {
+        let response = start_registration(
+            web::Json(PasskeyRegistrationStartDto {
+                current_password: "secret12".into(),
+            }),
+            testing::signed_in(user).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        let (challenge_id, options) =
+            challenge::<CreationChallengeResponse>(testing::json_body(response).await);
+
+        let credential = authenticator
+            .do_registration(origin(app_state), options)
+            .unwrap();
+        let response = finish_registration(
+            web::Json(PasskeyRegistrationDto {
+                challenge_id,
+                name: Some("Laptop".into()),
+                credential,
+            }),
+            testing::signed_in(user).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status().as_u16(), 201);
+    }
+
+    // the assertion is made right away, the login is only finished by `sign_in`
+    async fn assert_login(
+        app_state: &web::Data<AppState>,
+        user: &User,
+        authenticator: &mut Authenticator,
+    ) -> (String, PublicKeyCredential) {
+        let response = start_login(
+            web::Json(PasskeyLoginStartDto {
+                username_or_email: user.username.clone(),
+            }),
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+        let (challenge_id, options) =
+            challenge::<RequestChallengeResponse>(testing::json_body(response).await);
+
+        let credential = authenticator
+            .do_authentication(origin(app_state), options)
+            .unwrap();
+
+        (challenge_id, credential)
+    }
+
+    async fn sign_in(
+        app_state: &web::Data<AppState>,
+        (challenge_id, credential): (String, PublicKeyCredential),
+    ) -> Result<HttpResponse, ApiError> {
+        finish_login(
+            TestRequest::default().to_http_request(),
+            web::Json(PasskeyLoginDto {
+                challenge_id,
+                credential,
+            }),
+            app_state.clone(),
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn registered_passkey_signs_in(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let mut authenticator = authenticator();
+
+        register(&app_state, &user, &mut authenticator).await;
+        let assertion = assert_login(&app_state, &user, &mut authenticator).await;
+        let response = sign_in(&app_state, assertion).await.unwrap();
+
+        let body = testing::json_body(response).await;
+        assert_eq!(body["user"]["id"], user.id.as_str());
+        assert!(body["access_token"].is_string());
+
+        let sign_count = sqlx::query_scalar::<_, i64>(
+            "SELECT sign_count FROM user_credentials WHERE user_id = $1",
+        )
+        .bind(&user.id)
+        .fetch_one(&app_state.pool)
+        .await
+        .unwrap();
+        assert_eq!(sign_count, 1);
+    }
+
+    #[sqlx::test]
+    async fn registration_needs_the_current_password(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let refused = start_registration(
+            web::Json(PasskeyRegistrationStartDto {
+                current_password: "not-the-password".into(),
+            }),
+            testing::signed_in(&user).await,
+            app_state.clone(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert!(matches!(refused, ApiError::Other(_)));
+    }
+
+    #[sqlx::test]
+    async fn challenge_answers_a_single_login(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let mut authenticator = authenticator();
+
+        register(&app_state, &user, &mut authenticator).await;
+        let assertion = assert_login(&app_state, &user, &mut authenticator).await;
+        sign_in(&app_state, assertion.clone()).await.unwrap();
+
+        let replayed = sign_in(&app_state, assertion).await.err().unwrap();
+        assert!(matches!(replayed, ApiError::Other(_)));
+    }
+
+    // Both ceremonies start from the same stored counter, so webauthn-rs accepts
+    // either assertion and only the counter check of `finish_login` tells them apart
+    #[sqlx::test]
+    async fn sign_count_going_backwards_is_rejected(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let mut authenticator = authenticator();
+
+        register(&app_state, &user, &mut authenticator).await;
+        let older = assert_login(&app_state, &user, &mut authenticator).await;
+        let newer = assert_login(&app_state, &user, &mut authenticator).await;
+
+        sign_in(&app_state, newer).await.unwrap();
+        let rejected = sign_in(&app_state, older).await.err().unwrap();
+
+        let ApiError::Unauthorized(message) = rejected else {
+            panic!("expected a 401, got {rejected:?}");
+        };
+        assert!(message.contains("sign count went backwards"));
+        assert_eq!(
+            testing::audit_outcomes(&app_state.pool, "auth.passkey_login").await,
+            ["success", "failure"]
+        );
+    }
+}
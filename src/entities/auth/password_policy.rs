@@ -0,0 +1,255 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use actix_web::web;
+use sha1::{Digest, Sha1};
+use validator::{ValidationError, ValidationErrors};
+
+use crate::common::{AppState, config::PasswordPolicyConfig, errors::api_error::ApiError};
+
+// personal values shorter than this would reject too many passwords
+const MIN_PERSONAL_LENGTH: usize = 3;
+
+fn violation(code: &'static str, message: String) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::Owned(message))
+}
+
+// every rule but the breached list, which has to read from disk
+fn check_rules(
+    policy: &PasswordPolicyConfig,
+    password: &str,
+    username: &str,
+    email: &str,
+) -> Vec<ValidationError> {
+    let mut violations = Vec::new();
+
+    if password.chars().count() < policy.min_length {
+        violations.push(violation(
+            "min_length",
+            format!(
+                "Password must be at least {} characters long",
+                policy.min_length
+            ),
+        ));
+    }
+    // bytes, not characters, is what bcrypt counts
+    if password.len() > policy.max_length {
+        violations.push(violation(
+            "max_length",
+            format!("Password must be at most {} bytes long", policy.max_length),
+        ));
+    }
+
+    let has = |matches: fn(char) -> bool| password.chars().any(matches);
+    let classes = [
+        (
+            policy.require_lowercase,
+            "lowercase",
+            "a lowercase letter",
+            has(char::is_lowercase),
+        ),
+        (
+            policy.require_uppercase,
+            "uppercase",
+            "an uppercase letter",
+            has(char::is_uppercase),
+        ),
+        (
+            policy.require_digit,
+            "digit",
+            "a digit",
+            has(char::is_numeric),
+        ),
+        (
+            policy.require_symbol,
+            "symbol",
+            "a symbol",
+            has(|c| !c.is_alphanumeric()),
+        ),
+    ];
+    for (required, code, name, present) in classes {
+        if required && !present {
+            violations.push(violation(code, format!("Password must contain {name}")));
+        }
+    }
+
+    let local_part = email.split('@').next().unwrap_or_default();
+    let password = password.to_lowercase();
+    if [username, email, local_part]
+        .iter()
+        .filter(|value| value.chars().count() >= MIN_PERSONAL_LENGTH)
+        .any(|value| password.contains(&value.to_lowercase()))
+    {
+        violations.push(violation(
+            "personal_info",
+            "Password must not contain your username or email".to_string(),
+        ));
+    }
+
+    violations
+}
+
+// Only the first 5 hex chars of the hash pick the file, like the range api
+// of Pwned Passwords, so the list can be a mirror of it
+fn is_breached(list: &Path, password: &str) -> io::Result<bool> {
+    let digest = hex::encode_upper(Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = digest.split_at(5);
+
+    let file = match File::open(list.join(format!("{prefix}.txt"))) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let (hash, count) = line.split_once(':').unwrap_or((&line, ""));
+
+        // a count of 0 is padding
+        if hash.trim().eq_ignore_ascii_case(suffix) && count.trim() != "0" {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Fails with every broken rule as a message of `field`
+pub async fn check(
+    app_state: &AppState,
+    field: &'static str,
+    password: &str,
+    username: &str,
+    email: &str,
+) -> Result<(), ApiError> {
+    let policy = &app_state.config.password_policy;
+    let mut violations = check_rules(policy, password, username, email);
+
+    if let Some(list) = policy.breached_list.clone() {
+        let password = password.to_owned();
+        let breached = web::block(move || is_breached(&list, &password))
+            .await
+            .map_err(|e| ApiError::InternalServer(e.to_string()))?
+            .map_err(|e| {
+                ApiError::InternalServer(format!("Cannot read the breached password list: {e}"))
+            })?;
+
+        if breached {
+            violations.push(violation(
+                "breached",
+                "Password has appeared in a data breach, choose another one".to_string(),
+            ));
+        }
+    }
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let mut errors = ValidationErrors::new();
+    for violation in violations {
+        errors.add(field, violation);
+    }
+
+    Err(ApiError::Validation(errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::common::config::BCRYPT_MAX_PASSWORD_BYTES;
+
+    fn policy() -> PasswordPolicyConfig {
+        PasswordPolicyConfig {
+            min_length: 8,
+            max_length: BCRYPT_MAX_PASSWORD_BYTES,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            breached_list: None,
+        }
+    }
+
+    fn broken_rules(password: &str, username: &str, email: &str) -> Vec<String> {
+        check_rules(&policy(), password, username, email)
+            .into_iter()
+            .map(|violation| violation.code.into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn max_length_counts_bytes_of_multibyte_passwords() {
+        // 3 bytes each, 24 of them fill bcrypt's 72 bytes exactly
+        let fits = "€".repeat(24);
+        let too_long = "€".repeat(25);
+
+        assert!(broken_rules(&fits, "alice", "alice@example.com").is_empty());
+        assert_eq!(
+            broken_rules(&too_long, "alice", "alice@example.com"),
+            ["max_length"]
+        );
+        // while the minimum is in characters, 8 of them are 24 bytes
+        assert!(broken_rules(&"€".repeat(8), "alice", "alice@example.com").is_empty());
+        assert_eq!(
+            broken_rules(&"€".repeat(7), "alice", "alice@example.com"),
+            ["min_length"]
+        );
+    }
+
+    #[test]
+    fn personal_info_is_rejected_in_any_case() {
+        for password in [
+            "xxALICExx99",
+            "my-alice.smith-pw",
+            "Alice.Smith@example.com1",
+        ] {
+            assert_eq!(
+                broken_rules(password, "alice", "alice.smith@example.com"),
+                ["personal_info"],
+                "{password}"
+            );
+        }
+
+        // values too short to tell anything are ignored
+        assert!(broken_rules("al-is-a-password", "al", "al@example.com").is_empty());
+    }
+
+    // `<first 5 hex chars>.txt` files of `<rest>:<count>` lines, as the downloader writes them
+    fn breached_list(entries: &[(&str, u32)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("breached-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for (password, count) in entries {
+            let digest = hex::encode_upper(Sha1::digest(password.as_bytes()));
+            let (prefix, suffix) = digest.split_at(5);
+            let mut file =
+                fs::read_to_string(dir.join(format!("{prefix}.txt"))).unwrap_or_default();
+            file.push_str(&format!("{suffix}:{count}\r\n"));
+            fs::write(dir.join(format!("{prefix}.txt")), file).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn breached_list_is_looked_up_by_prefix_file() {
+        let dir = breached_list(&[("password123", 2_000_000), ("padding-only", 0)]);
+
+        assert!(is_breached(&dir, "password123").unwrap());
+        // zero counts only pad the file to hide how many hashes it holds
+        assert!(!is_breached(&dir, "padding-only").unwrap());
+        // no file for the prefix is no breach
+        assert!(!is_breached(&dir, "correct horse battery staple").unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+use actix_web::{HttpResponse, rt, web};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        AppState,
+        errors::api_error::ApiError,
+        mailer::Email,
+        tokens::{generate_opaque_token, hash_token},
+    },
+    entities::{
+        auth::{
+            dto::{ForgotPasswordDto, ResetPasswordDto},
+            password_policy, revoke_all_sessions,
+        },
+        user::{check_user_exists, dto::CheckUserExistsDto},
+    },
+};
+
+// also sent when an admin forces a reset
+pub async fn send_reset_link(
+    app_state: &AppState,
+    user_id: &str,
+    email: String,
+) -> Result<(), ApiError> {
+    let token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::seconds(app_state.config.tokens.password_reset_ttl);
+
+    sqlx::query(
+        "
+			INSERT INTO password_reset_tokens (id, user_id, token_hash, expires_at)
+			VALUES ($1, $2, $3, $4)
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(expires_at)
+    .execute(&app_state.pool)
+    .await?;
+
+    let link = format!(
+        "{}/reset-password?token={}",
+        app_state.config.frontend_url, token
+    );
+
+    app_state
+        .mailer
+        .send(Email {
+            to: email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Open the link below to choose a new password:\n\n{link}\n\nThe link expires in {} minutes. If you didn't ask for it, ignore this email.",
+                app_state.config.tokens.password_reset_ttl / 60
+            ),
+        })
+        .await
+        .map_err(|e| ApiError::InternalServer(format!("Cannot send password reset email: {e}")))
+}
+
+async fn send_reset_email(app_state: &AppState, username_or_email: String) -> Result<(), ApiError> {
+    let user =
+        match check_user_exists(CheckUserExistsDto { username_or_email }, &app_state.pool).await {
+            Ok(user) => user,
+            // unknown accounts are silently ignored
+            Err(ApiError::NotFound(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+    send_reset_link(app_state, &user.id, user.email).await
+}
+
+// Always answers the same and does the work in the background,
+// so neither the body nor the timing tells whether the account exists
+pub async fn forgot_password(
+    dto: web::Json<ForgotPasswordDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let username_or_email = dto.into_inner().username_or_email;
+    let app_state = app_state.clone();
+
+    rt::spawn(async move {
+        if let Err(e) = send_reset_email(&app_state, username_or_email).await {
+            eprintln!("[PASSWORD RESET]: {e}");
+        }
+    });
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+pub async fn reset_password(
+    dto: web::Json<ResetPasswordDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let token_hash = hash_token(&dto.token);
+    let invalid_token = || ApiError::Other("Invalid or expired reset token".into());
+
+    // only looked at for now, a rejected password leaves the token usable
+    let (user_id, username, email) = sqlx::query_as::<_, (String, String, String)>(
+        "
+			SELECT u.id, u.username, u.email
+			FROM password_reset_tokens t
+			JOIN users u ON u.id = t.user_id
+			WHERE t.token_hash = $1 AND t.used_at IS NULL AND t.expires_at > NOW()
+		",
+    )
+    .bind(&token_hash)
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or_else(invalid_token)?;
+
+    // the slow part, done before any row is locked
+    password_policy::check(
+        &app_state,
+        "new_password",
+        &dto.new_password,
+        &username,
+        &email,
+    )
+    .await?;
+    let password_hash = app_state.passwords.hash(&dto.new_password).await?;
+
+    let mut tx = app_state.pool.begin().await?;
+
+    // burning the token, of two concurrent resets only one gets it
+    let burned = sqlx::query(
+        "
+			UPDATE password_reset_tokens
+			SET used_at = NOW()
+			WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+		",
+    )
+    .bind(&token_hash)
+    .execute(&mut *tx)
+    .await?;
+    if burned.rows_affected() == 0 {
+        return Err(invalid_token());
+    }
+
+    sqlx::query("UPDATE users SET password = $1 WHERE id = $2")
+        .bind(&password_hash)
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    // other links sent before are useless now
+    sqlx::query(
+        "
+			UPDATE password_reset_tokens
+			SET used_at = NOW()
+			WHERE user_id = $1 AND used_at IS NULL
+		",
+    )
+    .bind(&user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    // whoever knew the old password is logged out everywhere
+    revoke_all_sessions(&app_state, &user_id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::common::testing;
+
+    async fn reset(
+        app_state: &web::Data<AppState>,
+        token: &str,
+        new_password: &str,
+    ) -> Result<HttpResponse, ApiError> {
+        reset_password(
+            web::Json(ResetPasswordDto {
+                token: token.to_string(),
+                new_password: new_password.to_string(),
+            }),
+            app_state.clone(),
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn token_survives_a_rejected_password_and_is_single_use(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let token = generate_opaque_token();
+        sqlx::query(
+            "
+				INSERT INTO password_reset_tokens (id, user_id, token_hash, expires_at)
+				VALUES ($1, $2, $3, NOW() + INTERVAL '10 minutes')
+			",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(&user.id)
+        .bind(hash_token(&token))
+        .execute(&app_state.pool)
+        .await
+        .unwrap();
+
+        let rejected = reset(&app_state, &token, "alice-pw-2024").await;
+        assert!(matches!(rejected, Err(ApiError::Validation(_))));
+
+        reset(&app_state, &token, "Another-Good9").await.unwrap();
+        let reused = reset(&app_state, &token, "Another-Good10").await;
+        assert!(matches!(reused, Err(ApiError::Other(_))));
+    }
+}
@@ -0,0 +1,36 @@
+use sqlx::PgPool;
+
+use crate::{common::errors::api_error::ApiError, models::auth::UserRole};
+
+pub const TOKENS_REVOKE: &str = "tokens:revoke";
+pub const USERS_UNLOCK: &str = "users:unlock";
+pub const OAUTH_CLIENTS_MANAGE: &str = "oauth_clients:manage";
+pub const USERS_READ: &str = "users:read";
+pub const USERS_MANAGE: &str = "users:manage";
+pub const AUDIT_READ: &str = "audit:read";
+
+// Permissions of the role and, unless the account is limited to it,
+// of the roles the user holds on top of it
+pub async fn resolve(
+    pool: &PgPool,
+    user_id: &str,
+    role: UserRole,
+    with_extra_roles: bool,
+) -> Result<Vec<String>, ApiError> {
+    let permissions = sqlx::query_scalar::<_, String>(
+        "
+			SELECT DISTINCT permission
+			FROM role_permissions
+			WHERE role = $1
+				OR ($3 AND role IN (SELECT role FROM user_roles WHERE user_id = $2))
+			ORDER BY permission
+		",
+    )
+    .bind(role.as_str())
+    .bind(user_id)
+    .bind(with_extra_roles)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(permissions)
+}
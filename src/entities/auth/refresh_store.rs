@@ -0,0 +1,424 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        errors::api_error::ApiError,
+        tokens::{generate_opaque_token, hash_token},
+    },
+    models::refresh_token::RefreshToken,
+};
+
+pub struct IssuedRefreshToken {
+    pub token: String,
+    pub family_id: String,
+}
+
+pub struct RotatedRefreshToken {
+    pub user_id: String,
+    pub family_id: String,
+    pub scope: Option<String>,
+    pub auth_time: DateTime<Utc>,
+    pub token: String,
+}
+
+// first party sessions have neither a client nor a scope
+struct Grant<'a> {
+    client_id: Option<&'a str>,
+    scope: Option<&'a str>,
+}
+
+async fn insert_token(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: &str,
+    family_id: &str,
+    grant: &Grant<'_>,
+    auth_time: DateTime<Utc>,
+    ttl: i64,
+) -> Result<String, ApiError> {
+    let token = generate_opaque_token();
+
+    sqlx::query(
+        "
+			INSERT INTO refresh_tokens
+				(id, family_id, user_id, token_hash, expires_at, client_id, scope, auth_time)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(family_id)
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(Utc::now() + Duration::seconds(ttl))
+    .bind(grant.client_id)
+    .bind(grant.scope)
+    .bind(auth_time)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(token)
+}
+
+async fn start_family(
+    tx: &mut Transaction<'_, Postgres>,
+    family_id: String,
+    user_id: &str,
+    grant: Grant<'_>,
+    auth_time: DateTime<Utc>,
+    ttl: i64,
+) -> Result<IssuedRefreshToken, ApiError> {
+    let token = insert_token(tx, user_id, &family_id, &grant, auth_time, ttl).await?;
+
+    Ok(IssuedRefreshToken { token, family_id })
+}
+
+// starts the token family of a new session, used on register and login
+pub async fn issue(
+    pool: &PgPool,
+    session_id: &str,
+    user_id: &str,
+    ttl: i64,
+) -> Result<IssuedRefreshToken, ApiError> {
+    let mut tx = pool.begin().await?;
+    let issued = start_family(
+        &mut tx,
+        session_id.to_owned(),
+        user_id,
+        Grant {
+            client_id: None,
+            scope: None,
+        },
+        Utc::now(),
+        ttl,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(issued)
+}
+
+// starts a new token family owned by an OAuth client signed in at `auth_time`,
+// in the transaction of the grant
+pub async fn issue_for_client(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: &str,
+    client_id: &str,
+    scope: &str,
+    auth_time: DateTime<Utc>,
+    ttl: i64,
+) -> Result<IssuedRefreshToken, ApiError> {
+    start_family(
+        tx,
+        Uuid::new_v4().to_string(),
+        user_id,
+        Grant {
+            client_id: Some(client_id),
+            scope: Some(scope),
+        },
+        auth_time,
+        ttl,
+    )
+    .await
+}
+
+// looks a token up without consuming it
+pub async fn find_active(
+    pool: &PgPool,
+    presented: &str,
+    client_id: Option<&str>,
+) -> Result<Option<RefreshToken>, ApiError> {
+    let token = sqlx::query_as::<_, RefreshToken>(
+        "
+			SELECT id, family_id, user_id, client_id, scope, expires_at, rotated_at, revoked_at, auth_time
+			FROM refresh_tokens
+			WHERE token_hash = $1
+				AND rotated_at IS NULL
+				AND revoked_at IS NULL
+				AND expires_at > NOW()
+				AND client_id IS NOT DISTINCT FROM $2
+		",
+    )
+    .bind(hash_token(presented))
+    .bind(client_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(token)
+}
+
+// Exchanges a refresh token for a new one in the same family.
+// Presenting a token that was already rotated is treated as theft
+// and revokes every token in its family. Tokens only rotate for the
+// client they were issued to, `None` being our own frontend
+pub async fn rotate(
+    pool: &PgPool,
+    presented: &str,
+    client_id: Option<&str>,
+    ttl: i64,
+) -> Result<RotatedRefreshToken, ApiError> {
+    let token_hash = hash_token(presented);
+    let mut tx = pool.begin().await?;
+
+    let current = sqlx::query_as::<_, RefreshToken>(
+        "
+			UPDATE refresh_tokens
+			SET rotated_at = NOW()
+			WHERE token_hash = $1
+				AND rotated_at IS NULL
+				AND revoked_at IS NULL
+				AND expires_at > NOW()
+				AND client_id IS NOT DISTINCT FROM $2
+			RETURNING id, family_id, user_id, client_id, scope, expires_at, rotated_at, revoked_at, auth_time
+		",
+    )
+    .bind(&token_hash)
+    .bind(client_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(current) = current else {
+        tx.rollback().await?;
+
+        let known = sqlx::query_as::<_, RefreshToken>(
+            "
+				SELECT id, family_id, user_id, client_id, scope, expires_at, rotated_at, revoked_at, auth_time
+				FROM refresh_tokens
+				WHERE token_hash = $1
+			",
+        )
+        .bind(&token_hash)
+        .fetch_optional(pool)
+        .await?;
+
+        if let Some(known) = known
+            && known.rotated_at.is_some()
+            && known.revoked_at.is_none()
+        {
+            revoke_family(pool, &known.family_id).await?;
+            return Err(ApiError::Unauthorized(
+                "Refresh token reuse detected, session revoked".into(),
+            ));
+        }
+
+        return Err(ApiError::Unauthorized("Invalid Refresh Token".into()));
+    };
+
+    let grant = Grant {
+        client_id: current.client_id.as_deref(),
+        scope: current.scope.as_deref(),
+    };
+    let token = insert_token(
+        &mut tx,
+        &current.user_id,
+        &current.family_id,
+        &grant,
+        current.auth_time,
+        ttl,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(RotatedRefreshToken {
+        user_id: current.user_id,
+        family_id: current.family_id,
+        scope: current.scope,
+        auth_time: current.auth_time,
+        token,
+    })
+}
+
+pub async fn revoke_family(pool: &PgPool, family_id: &str) -> Result<(), ApiError> {
+    sqlx::query(
+        "
+			UPDATE refresh_tokens
+			SET revoked_at = NOW()
+			WHERE family_id = $1 AND revoked_at IS NULL
+		",
+    )
+    .bind(family_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// Revokes the family the presented token belongs to. Hands back the user and the
+// family, which is the session of a first-party sign in
+pub async fn revoke_by_token(
+    pool: &PgPool,
+    presented: &str,
+) -> Result<Option<(String, String)>, ApiError> {
+    let Some((user_id, family_id)) = sqlx::query_as::<_, (String, String)>(
+        "SELECT user_id, family_id FROM refresh_tokens WHERE token_hash = $1",
+    )
+    .bind(hash_token(presented))
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    revoke_family(pool, &family_id).await?;
+
+    Ok(Some((user_id, family_id)))
+}
+
+// ends what a client holds for a user, used when the consent is withdrawn
+pub async fn revoke_for_client(
+    pool: &PgPool,
+    user_id: &str,
+    client_id: &str,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "
+			UPDATE refresh_tokens
+			SET revoked_at = NOW()
+			WHERE user_id = $1 AND client_id = $2 AND revoked_at IS NULL
+		",
+    )
+    .bind(user_id)
+    .bind(client_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn revoke_all_for_user(pool: &PgPool, user_id: &str) -> Result<(), ApiError> {
+    sqlx::query(
+        "
+			UPDATE refresh_tokens
+			SET revoked_at = NOW()
+			WHERE user_id = $1 AND revoked_at IS NULL
+		",
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: i64 = 60 * 60;
+
+    async fn insert_user(pool: &PgPool) -> String {
+        let id = Uuid::new_v4().to_string();
+
+        sqlx::query("INSERT INTO users (id, username, email, password) VALUES ($1, $1, $1, '')")
+            .bind(&id)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        id
+    }
+
+    async fn insert_client(pool: &PgPool) -> String {
+        let id = Uuid::new_v4().to_string();
+
+        sqlx::query("INSERT INTO oauth_clients (id, name, client_type) VALUES ($1, $1, 'public')")
+            .bind(&id)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        id
+    }
+
+    #[sqlx::test]
+    async fn rotation_keeps_the_family(pool: PgPool) {
+        let user_id = insert_user(&pool).await;
+        let issued = issue(&pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+            .await
+            .unwrap();
+
+        let rotated = rotate(&pool, &issued.token, None, TTL).await.unwrap();
+        assert_eq!(rotated.user_id, user_id);
+        assert_eq!(rotated.family_id, issued.family_id);
+        assert_ne!(rotated.token, issued.token);
+
+        let rotated_again = rotate(&pool, &rotated.token, None, TTL).await.unwrap();
+        assert_eq!(rotated_again.family_id, issued.family_id);
+    }
+
+    #[sqlx::test]
+    async fn reused_token_revokes_the_family(pool: PgPool) {
+        let user_id = insert_user(&pool).await;
+        let issued = issue(&pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+            .await
+            .unwrap();
+        let rotated = rotate(&pool, &issued.token, None, TTL).await.unwrap();
+
+        assert!(matches!(
+            rotate(&pool, &issued.token, None, TTL).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+        // the token the thief or the owner got from the rotation is gone too
+        assert!(matches!(
+            rotate(&pool, &rotated.token, None, TTL).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn reuse_leaves_other_families_alone(pool: PgPool) {
+        let user_id = insert_user(&pool).await;
+        let stolen = issue(&pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+            .await
+            .unwrap();
+        let other_device = issue(&pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+            .await
+            .unwrap();
+
+        rotate(&pool, &stolen.token, None, TTL).await.unwrap();
+        assert!(rotate(&pool, &stolen.token, None, TTL).await.is_err());
+
+        assert!(rotate(&pool, &other_device.token, None, TTL).await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn unknown_token_is_rejected(pool: PgPool) {
+        assert!(matches!(
+            rotate(&pool, "not-a-token", None, TTL).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn tokens_rotate_only_for_their_client(pool: PgPool) {
+        let user_id = insert_user(&pool).await;
+        let client_id = insert_client(&pool).await;
+        let other_client_id = insert_client(&pool).await;
+        let first_party = issue(&pool, &Uuid::new_v4().to_string(), &user_id, TTL)
+            .await
+            .unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        let issued = issue_for_client(&mut tx, &user_id, &client_id, "read", Utc::now(), TTL)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        for (token, client) in [
+            (&issued.token, None),
+            (&issued.token, Some(other_client_id.as_str())),
+            (&first_party.token, Some(client_id.as_str())),
+        ] {
+            assert!(matches!(
+                rotate(&pool, token, client, TTL).await,
+                Err(ApiError::Unauthorized(message)) if message == "Invalid Refresh Token"
+            ));
+        }
+
+        // the rejected attempts didn't burn the tokens
+        let rotated = rotate(&pool, &issued.token, Some(&client_id), TTL)
+            .await
+            .unwrap();
+        assert_eq!(rotated.scope.as_deref(), Some("read"));
+        assert!(rotate(&pool, &first_party.token, None, TTL).await.is_ok());
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+    time::{Duration as StdDuration, Instant},
+};
+
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{common::errors::api_error::ApiError, models::auth::Claims};
+
+// how long the in-process copy is trusted before reloading it from database,
+// revocations made by other instances become visible after at most this delay
+const SYNC_INTERVAL: StdDuration = StdDuration::from_secs(30);
+
+#[derive(Default)]
+struct Denylist {
+    // jti -> expiration of the revoked token
+    tokens: HashMap<String, DateTime<Utc>>,
+    // user id -> every token issued before this moment is revoked
+    users: HashMap<String, DateTime<Utc>>,
+    // ids of revoked sessions, their tokens are revoked whenever issued
+    sessions: HashSet<String>,
+    synced_at: Option<Instant>,
+}
+
+#[derive(sqlx::FromRow)]
+struct RevokedToken {
+    jti: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct UserRevocation {
+    user_id: String,
+    revoked_before: DateTime<Utc>,
+}
+
+pub struct RevocationList {
+    cache: RwLock<Denylist>,
+    // seconds, no access token lives longer than this
+    access_ttl: i64,
+}
+
+impl RevocationList {
+    pub fn new(access_ttl: i64) -> Self {
+        Self {
+            cache: RwLock::default(),
+            access_ttl,
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        let cache = self.cache.read().unwrap();
+        cache
+            .synced_at
+            .is_none_or(|synced_at| synced_at.elapsed() >= SYNC_INTERVAL)
+    }
+
+    async fn sync(&self, pool: &PgPool) -> Result<(), ApiError> {
+        // entries for tokens that already expired on their own are useless
+        sqlx::query("DELETE FROM revoked_access_tokens WHERE expires_at <= NOW()")
+            .execute(pool)
+            .await?;
+
+        let tokens = sqlx::query_as::<_, RevokedToken>(
+            "
+				SELECT jti, expires_at
+				FROM revoked_access_tokens
+				WHERE expires_at > NOW()
+			",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        // cut-offs older than access token lifetime can't match any live token
+        let users = sqlx::query_as::<_, UserRevocation>(
+            "
+				SELECT user_id, revoked_before
+				FROM user_token_revocations
+				WHERE revoked_before > $1
+			",
+        )
+        .bind(Utc::now() - Duration::seconds(self.access_ttl))
+        .fetch_all(pool)
+        .await?;
+
+        // same for sessions, their last token expired by then
+        let sessions = sqlx::query_scalar::<_, String>(
+            "
+				SELECT id
+				FROM sessions
+				WHERE revoked_at > $1
+			",
+        )
+        .bind(Utc::now() - Duration::seconds(self.access_ttl))
+        .fetch_all(pool)
+        .await?;
+
+        let mut cache = self.cache.write().unwrap();
+        cache.tokens = tokens.into_iter().map(|t| (t.jti, t.expires_at)).collect();
+        cache.users = users
+            .into_iter()
+            .map(|u| (u.user_id, u.revoked_before))
+            .collect();
+        cache.sessions = sessions.into_iter().collect();
+        cache.synced_at = Some(Instant::now());
+
+        Ok(())
+    }
+
+    pub async fn is_revoked(&self, pool: &PgPool, claims: &Claims) -> Result<bool, ApiError> {
+        if self.is_stale() {
+            self.sync(pool).await?;
+        }
+
+        let cache = self.cache.read().unwrap();
+
+        if cache.tokens.contains_key(&claims.jti)
+            || claims
+                .sid
+                .as_ref()
+                .is_some_and(|sid| cache.sessions.contains(sid))
+        {
+            return Ok(true);
+        }
+
+        Ok(cache
+            .users
+            .get(&claims.sub)
+            .is_some_and(|revoked_before| issued_at(claims) < *revoked_before))
+    }
+
+    pub async fn revoke_token(
+        &self,
+        pool: &PgPool,
+        jti: &str,
+        user_id: Option<&str>,
+    ) -> Result<(), ApiError> {
+        // a token can't outlive access token lifetime, so the entry can be dropped after it
+        let expires_at = Utc::now() + Duration::seconds(self.access_ttl);
+
+        sqlx::query(
+            "
+				INSERT INTO revoked_access_tokens (jti, user_id, expires_at)
+				VALUES ($1, $2, $3)
+				ON CONFLICT (jti) DO NOTHING
+			",
+        )
+        .bind(jti)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(pool)
+        .await?;
+
+        self.cache
+            .write()
+            .unwrap()
+            .tokens
+            .insert(jti.to_owned(), expires_at);
+
+        Ok(())
+    }
+
+    pub async fn revoke_user(&self, pool: &PgPool, user_id: &str) -> Result<(), ApiError> {
+        // to the millisecond, like the issue time of the tokens it's compared with
+        let revoked_before = Utc::now().trunc_subsecs(3);
+
+        sqlx::query(
+            "
+				INSERT INTO user_token_revocations (user_id, revoked_before)
+				VALUES ($1, $2)
+				ON CONFLICT (user_id) DO UPDATE SET revoked_before = EXCLUDED.revoked_before
+			",
+        )
+        .bind(user_id)
+        .bind(revoked_before)
+        .execute(pool)
+        .await?;
+
+        self.cache
+            .write()
+            .unwrap()
+            .users
+            .insert(user_id.to_owned(), revoked_before);
+
+        Ok(())
+    }
+
+    // Ends one session of the user, false when it isn't theirs or already ended.
+    // Its refresh tokens are left to the caller
+    pub async fn revoke_session(
+        &self,
+        pool: &PgPool,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<bool, ApiError> {
+        let revoked = sqlx::query(
+            "
+				UPDATE sessions
+				SET revoked_at = NOW()
+				WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+			",
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if revoked {
+            self.cache
+                .write()
+                .unwrap()
+                .sessions
+                .insert(session_id.to_owned());
+        }
+
+        Ok(revoked)
+    }
+}
+
+// iat only has second precision, which can't tell a token issued just before a
+// revocation from one issued right after it in the same second. The v7 jti of
+// access tokens has the milliseconds, tokens without one count from the start
+// of their second and so are revoked along with that whole second
+fn issued_at(claims: &Claims) -> DateTime<Utc> {
+    Uuid::parse_str(&claims.jti)
+        .ok()
+        .and_then(|jti| jti.get_timestamp())
+        .and_then(|timestamp| {
+            let (secs, nanos) = timestamp.to_unix();
+            DateTime::from_timestamp(secs as i64, nanos)
+        })
+        .unwrap_or_else(|| DateTime::from_timestamp(claims.iat as i64, 0).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::models::auth::UserRole;
+
+    const ACCESS_TTL: i64 = 15 * 60;
+
+    fn claims(user_id: &str, jti: Uuid) -> Claims {
+        let now = Utc::now().timestamp() as usize;
+
+        Claims {
+            sub: user_id.into(),
+            role: UserRole::User,
+            is_premium: false,
+            email_verified: true,
+            jti: jti.to_string(),
+            iat: now,
+            exp: now + 15 * 60,
+            auth_time: Some(now),
+            client_id: None,
+            scope: None,
+            perms: Vec::new(),
+            sid: None,
+        }
+    }
+
+    #[sqlx::test]
+    async fn revoked_token_is_rejected(pool: PgPool) {
+        let revocations = RevocationList::new(ACCESS_TTL);
+        let revoked = claims("alice", Uuid::now_v7());
+        let other = claims("alice", Uuid::now_v7());
+
+        revocations
+            .revoke_token(&pool, &revoked.jti, Some("alice"))
+            .await
+            .unwrap();
+
+        assert!(revocations.is_revoked(&pool, &revoked).await.unwrap());
+        assert!(!revocations.is_revoked(&pool, &other).await.unwrap());
+    }
+
+    #[sqlx::test]
+    async fn user_revocation_covers_only_tokens_issued_before_it(pool: PgPool) {
+        let revocations = RevocationList::new(ACCESS_TTL);
+        let before = claims("alice", Uuid::now_v7());
+        let without_time = claims("alice", Uuid::new_v4());
+        let other_user = claims("bob", Uuid::now_v7());
+
+        std::thread::sleep(StdDuration::from_millis(2));
+        revocations.revoke_user(&pool, "alice").await.unwrap();
+        std::thread::sleep(StdDuration::from_millis(2));
+        let after = claims("alice", Uuid::now_v7());
+
+        assert!(revocations.is_revoked(&pool, &before).await.unwrap());
+        assert!(revocations.is_revoked(&pool, &without_time).await.unwrap());
+        assert!(!revocations.is_revoked(&pool, &after).await.unwrap());
+        assert!(!revocations.is_revoked(&pool, &other_user).await.unwrap());
+    }
+
+    #[sqlx::test]
+    async fn revocations_of_another_instance_show_up_on_the_next_sync(pool: PgPool) {
+        let revoking = RevocationList::new(ACCESS_TTL);
+        let checking = RevocationList::new(ACCESS_TTL);
+        let token = claims("alice", Uuid::now_v7());
+        let user_token = claims("bob", Uuid::now_v7());
+
+        // the first check syncs, the copy is trusted for SYNC_INTERVAL after it
+        assert!(!checking.is_revoked(&pool, &token).await.unwrap());
+
+        revoking
+            .revoke_token(&pool, &token.jti, Some("alice"))
+            .await
+            .unwrap();
+        revoking.revoke_user(&pool, "bob").await.unwrap();
+        assert!(!checking.is_revoked(&pool, &token).await.unwrap());
+
+        checking.cache.write().unwrap().synced_at = Some(Instant::now() - SYNC_INTERVAL);
+
+        assert!(checking.is_revoked(&pool, &token).await.unwrap());
+        assert!(checking.is_revoked(&pool, &user_token).await.unwrap());
+    }
+}
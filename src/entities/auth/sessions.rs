@@ -0,0 +1,339 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use serde_json::json;
+
+use crate::{
+    common::{
+        AppState,
+        audit::{self, AuditEvent, Outcome},
+        errors::api_error::ApiError,
+        request::client_ip,
+    },
+    entities::auth::{dto::SessionResponse, refresh_store},
+    models::{auth::Claims, session::Session},
+};
+
+// first match wins, so the ones claiming to be others come first
+const BROWSERS: [(&str, &str); 7] = [
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Firefox/", "Firefox"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+    ("curl/", "curl"),
+    ("okhttp/", "OkHttp"),
+];
+const PLATFORMS: [(&str, &str); 7] = [
+    ("Android", "Android"),
+    ("iPhone", "iOS"),
+    ("iPad", "iPadOS"),
+    ("Windows", "Windows"),
+    ("Mac OS X", "macOS"),
+    ("CrOS", "ChromeOS"),
+    ("Linux", "Linux"),
+];
+
+// a readable name like "Firefox on Linux", good enough to tell devices apart
+fn describe_device(user_agent: Option<&str>) -> String {
+    let Some(user_agent) = user_agent else {
+        return "Unknown device".to_string();
+    };
+    let find = |names: &[(&str, &'static str)]| {
+        names
+            .iter()
+            .find(|(marker, _)| user_agent.contains(marker))
+            .map(|(_, name)| *name)
+    };
+
+    match (find(&BROWSERS), find(&PLATFORMS)) {
+        (Some(browser), Some(platform)) => format!("{browser} on {platform}"),
+        (Some(name), None) | (None, Some(name)) => name.to_string(),
+        (None, None) => "Unknown device".to_string(),
+    }
+}
+
+// Records a session as used by `req`, creating it on the first sign in. Sessions started
+// before they were recorded show up once they're refreshed. The address and user agent
+// are those of the latest use
+pub async fn record_use(
+    app_state: &AppState,
+    req: &HttpRequest,
+    session_id: &str,
+    user_id: &str,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        "
+			INSERT INTO sessions (id, user_id, user_agent, ip)
+			VALUES ($1, $2, $3, $4)
+			ON CONFLICT (id) DO UPDATE
+			SET last_used_at = NOW(), ip = EXCLUDED.ip, user_agent = EXCLUDED.user_agent
+		",
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .bind(audit::user_agent(req))
+    .bind(client_ip(req, &app_state.config))
+    .execute(&app_state.pool)
+    .await?;
+
+    Ok(())
+}
+
+// sessions that can still be refreshed
+pub async fn list_sessions(
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let sessions = sqlx::query_as::<_, Session>(
+        "
+			SELECT s.id, s.user_agent, s.ip, s.created_at, s.last_used_at
+			FROM sessions s
+			WHERE s.user_id = $1
+				AND s.revoked_at IS NULL
+				AND EXISTS (
+					SELECT 1
+					FROM refresh_tokens t
+					WHERE t.family_id = s.id
+						AND t.rotated_at IS NULL
+						AND t.revoked_at IS NULL
+						AND t.expires_at > NOW()
+				)
+			ORDER BY s.last_used_at DESC
+		",
+    )
+    .bind(&claims.sub)
+    .fetch_all(&app_state.pool)
+    .await?;
+
+    let sessions: Vec<SessionResponse> = sessions
+        .into_iter()
+        .map(|session| SessionResponse {
+            device: describe_device(session.user_agent.as_deref()),
+            current: claims.sid.as_deref() == Some(session.id.as_str()),
+            id: session.id,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+// signs a device out, its access tokens stop working right away
+pub async fn revoke_session(
+    req: HttpRequest,
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let session_id = path.into_inner();
+
+    if !app_state
+        .revocations
+        .revoke_session(&app_state.pool, &claims.sub, &session_id)
+        .await?
+    {
+        return Err(ApiError::NotFound("Session not found".into()));
+    }
+    refresh_store::revoke_family(&app_state.pool, &session_id).await?;
+
+    let ip = client_ip(&req, &app_state.config);
+    app_state
+        .audit
+        .record(AuditEvent {
+            event_type: "auth.session_revoked",
+            actor_id: Some(&claims.sub),
+            target_id: Some(&claims.sub),
+            ip: Some(&ip),
+            user_agent: audit::user_agent(&req),
+            outcome: Outcome::Success,
+            metadata: json!({ "session_id": session_id }),
+        })
+        .await;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{cookie::Cookie, http::header, test::TestRequest};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        common::testing,
+        entities::auth::{jwt, logout},
+        models::user::User,
+    };
+
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:131.0) Gecko/20100101 Firefox/131.0";
+
+    // signs the user in from a device, handing back its refresh token and access token claims
+    async fn sign_in(app_state: &AppState, user: &User, user_agent: &str) -> (String, Claims) {
+        let req = TestRequest::default()
+            .insert_header((header::USER_AGENT, user_agent))
+            .to_http_request();
+        let tokens = jwt::generate_tokens(app_state, &req, user).await.unwrap();
+        let claims = jwt::verify_jwt(&app_state.keys, &tokens.access_token).unwrap();
+
+        (tokens.refresh_token, claims)
+    }
+
+    async fn revoke(
+        app_state: &web::Data<AppState>,
+        claims: &Claims,
+        session_id: &str,
+    ) -> Result<HttpResponse, ApiError> {
+        revoke_session(
+            TestRequest::default().to_http_request(),
+            web::Path::from(session_id.to_string()),
+            testing::with_claims(claims.clone()).await,
+            app_state.clone(),
+        )
+        .await
+    }
+
+    async fn devices(app_state: &web::Data<AppState>, claims: &Claims) -> Vec<(String, bool)> {
+        let response = list_sessions(
+            testing::with_claims(claims.clone()).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+
+        testing::json_body(response)
+            .await
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|session| {
+                let device = session["device"].as_str().unwrap().to_string();
+                (device, session["current"].as_bool().unwrap())
+            })
+            .collect()
+    }
+
+    #[sqlx::test]
+    async fn sessions_of_the_user_are_listed_newest_first(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let bob = testing::insert_user(&app_state, "bob", "bob@example.com").await;
+
+        let (_, laptop) = sign_in(&app_state, &alice, FIREFOX).await;
+        sign_in(&app_state, &alice, "curl/8.5.0").await;
+        sign_in(&app_state, &bob, FIREFOX).await;
+
+        assert_eq!(
+            devices(&app_state, &laptop).await,
+            [
+                ("curl".to_string(), false),
+                ("Firefox on Linux".to_string(), true)
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn session_shows_the_device_it_was_used_from_last(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let (_, claims) = sign_in(&app_state, &alice, "curl/8.5.0").await;
+
+        let req = TestRequest::default()
+            .insert_header((header::USER_AGENT, FIREFOX))
+            .to_http_request();
+        record_use(&app_state, &req, claims.sid.as_deref().unwrap(), &alice.id)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            devices(&app_state, &claims).await,
+            [("Firefox on Linux".to_string(), true)]
+        );
+    }
+
+    #[sqlx::test]
+    async fn revoking_the_current_session_ends_its_tokens(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let (refresh_token, claims) = sign_in(&app_state, &alice, FIREFOX).await;
+        let (_, other_device) = sign_in(&app_state, &alice, "curl/8.5.0").await;
+
+        revoke(&app_state, &claims, claims.sid.as_deref().unwrap())
+            .await
+            .unwrap();
+
+        assert!(
+            app_state
+                .revocations
+                .is_revoked(&app_state.pool, &claims)
+                .await
+                .unwrap()
+        );
+        let ttl = app_state.config.tokens.refresh_ttl;
+        assert!(matches!(
+            refresh_store::rotate(&app_state.pool, &refresh_token, None, ttl).await,
+            Err(ApiError::Unauthorized(_))
+        ));
+        assert_eq!(
+            devices(&app_state, &other_device).await,
+            [("curl".to_string(), true)]
+        );
+    }
+
+    #[sqlx::test]
+    async fn session_of_another_user_is_not_found(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let bob = testing::insert_user(&app_state, "bob", "bob@example.com").await;
+        let (_, alice_claims) = sign_in(&app_state, &alice, FIREFOX).await;
+        let (refresh_token, bob_claims) = sign_in(&app_state, &bob, FIREFOX).await;
+
+        assert!(matches!(
+            revoke(
+                &app_state,
+                &alice_claims,
+                bob_claims.sid.as_deref().unwrap()
+            )
+            .await,
+            Err(ApiError::NotFound(_))
+        ));
+
+        assert!(
+            !app_state
+                .revocations
+                .is_revoked(&app_state.pool, &bob_claims)
+                .await
+                .unwrap()
+        );
+        let ttl = app_state.config.tokens.refresh_ttl;
+        refresh_store::rotate(&app_state.pool, &refresh_token, None, ttl)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn logout_ends_the_session(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let alice = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let (refresh_token, claims) = sign_in(&app_state, &alice, FIREFOX).await;
+
+        let req = TestRequest::default()
+            .cookie(Cookie::new("refresh_token", refresh_token))
+            .to_http_request();
+        logout(req, app_state.clone()).await.unwrap();
+
+        assert!(
+            app_state
+                .revocations
+                .is_revoked(&app_state.pool, &claims)
+                .await
+                .unwrap()
+        );
+        let (_, other_device) = sign_in(&app_state, &alice, FIREFOX).await;
+        assert_eq!(
+            devices(&app_state, &other_device).await,
+            [("Firefox on Linux".to_string(), true)]
+        );
+    }
+}
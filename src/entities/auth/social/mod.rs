@@ -0,0 +1,1020 @@
+pub mod providers;
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    cookie::{Cookie, SameSite, time},
+    http::header,
+    web,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        AppState,
+        audit::Outcome,
+        errors::api_error::ApiError,
+        password_hasher::Verification,
+        request::client_ip,
+        tokens::{generate_opaque_token, hash_token},
+    },
+    entities::{
+        auth::{
+            dto::{
+                AuthResponse, ConfirmOAuthLinkDto, MfaChallengeResponse, OAuthCallbackQuery,
+                OAuthLinkRequiredResponse, OAuthLinkStartDto,
+            },
+            account::check_current_password,
+            email_verification::send_verification_email,
+            jwt, mfa, record_auth_event,
+            throttle::{self, ThrottleKey},
+        },
+        user::{find_user_by_id, find_user_with_password_by_id},
+    },
+    models::{
+        auth::{Claims, OAuthLinkClaims},
+        external_identity::ExternalIdentity,
+        user::{User, UserWithPassword},
+    },
+};
+use providers::ProviderIdentity;
+
+const STATE_TTL: i64 = 600;
+const LINK_TOKEN_TTL: i64 = 600;
+const LINK_PURPOSE: &str = "oauth_link";
+const STATE_COOKIE: &str = "oauth_state";
+
+#[derive(sqlx::FromRow)]
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    user_id: Option<String>,
+}
+
+fn redirect_uri(app_state: &AppState, provider: &str) -> String {
+    format!(
+        "{}/auth/oauth/{}/callback",
+        app_state.config.public_url, provider
+    )
+}
+
+// Binds the login to the browser that started it. Without it anyone could hand a victim
+// the callback of their own login and have them signed in to the attacker's account
+fn build_state_cookie(app_state: &AppState, state: &str) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, hash_token(state))
+        .http_only(true)
+        .secure(app_state.config.is_production())
+        .path("/auth/oauth")
+        // sent along with the top-level redirect back from the provider
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(STATE_TTL))
+        .finish()
+}
+
+// state, nonce and PKCE verifier stay on our side, only the state travels with the user
+async fn begin(
+    app_state: &AppState,
+    provider_name: &str,
+    user_id: Option<&str>,
+) -> Result<(String, Cookie<'static>), ApiError> {
+    let provider = app_state.oauth.get(provider_name)?;
+
+    let state = generate_opaque_token();
+    let nonce = generate_opaque_token();
+    let code_verifier = generate_opaque_token();
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+    sqlx::query("DELETE FROM oauth_states WHERE expires_at <= NOW()")
+        .execute(&app_state.pool)
+        .await?;
+
+    sqlx::query(
+        "
+			INSERT INTO oauth_states (id, provider, code_verifier, nonce, user_id, expires_at)
+			VALUES ($1, $2, $3, $4, $5, $6)
+		",
+    )
+    .bind(hash_token(&state))
+    .bind(provider_name)
+    .bind(&code_verifier)
+    .bind(&nonce)
+    .bind(user_id)
+    .bind(Utc::now() + Duration::seconds(STATE_TTL))
+    .execute(&app_state.pool)
+    .await?;
+
+    let url = app_state
+        .oauth
+        .authorization_url(
+            provider,
+            &redirect_uri(app_state, provider_name),
+            &state,
+            &nonce,
+            &code_challenge,
+        )
+        .await?;
+
+    Ok((url, build_state_cookie(app_state, &state)))
+}
+
+// the same answer as a password login, including the second factor
+async fn sign_in(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user: User,
+    provider: &str,
+) -> Result<HttpResponse, ApiError> {
+    if mfa::is_totp_enabled(app_state, &user.id).await? {
+        record_auth_event(
+            app_state,
+            req,
+            "auth.social_login",
+            Some(&user.id),
+            Outcome::Pending,
+            json!({ "provider": provider, "mfa_required": true }),
+        )
+        .await;
+
+        return Ok(HttpResponse::Ok().json(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token: mfa::create_mfa_challenge(app_state, &user.id)?,
+        }));
+    }
+
+    let tokens = match jwt::generate_tokens(app_state, req, &user).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            record_auth_event(
+                app_state,
+                req,
+                "auth.social_login",
+                Some(&user.id),
+                Outcome::Failure,
+                json!({ "provider": provider, "reason": e.to_string() }),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+
+    record_auth_event(
+        app_state,
+        req,
+        "auth.social_login",
+        Some(&user.id),
+        Outcome::Success,
+        json!({ "provider": provider, "mfa_required": false }),
+    )
+    .await;
+
+    Ok(HttpResponse::Ok()
+        .cookie(jwt::build_refresh_cookie(tokens.refresh_token, app_state))
+        .json(AuthResponse {
+            user,
+            access_token: tokens.access_token,
+        }))
+}
+
+async fn insert_identity(
+    app_state: &AppState,
+    user_id: &str,
+    provider: &str,
+    subject: &str,
+    email: Option<&str>,
+) -> Result<ExternalIdentity, ApiError> {
+    let identity = sqlx::query_as::<_, ExternalIdentity>(
+        "
+			INSERT INTO external_identities (id, user_id, provider, subject, email, last_login_at)
+			VALUES ($1, $2, $3, $4, $5, NOW())
+			RETURNING id, provider, subject, email, created_at, last_login_at
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(provider)
+    .bind(subject)
+    .bind(email)
+    .fetch_one(&app_state.pool)
+    .await?;
+
+    Ok(identity)
+}
+
+fn create_link_token(
+    app_state: &AppState,
+    user_id: &str,
+    provider: &str,
+    identity: &ProviderIdentity,
+    email: &str,
+) -> Result<String, ApiError> {
+    let now = Utc::now().timestamp();
+    let claims = OAuthLinkClaims {
+        sub: user_id.to_owned(),
+        purpose: LINK_PURPOSE.to_string(),
+        provider: provider.to_owned(),
+        subject: identity.subject.clone(),
+        email: email.to_owned(),
+        jti: Uuid::new_v4().to_string(),
+        iat: now as usize,
+        exp: (now + LINK_TOKEN_TTL) as usize,
+    };
+
+    app_state
+        .keys
+        .sign(&claims)
+        .map_err(|e| ApiError::Other(format!("Error when trying to generate link token {:?}", e)))
+}
+
+fn username_base(identity: &ProviderIdentity, email: &str) -> String {
+    let source = identity
+        .username
+        .as_deref()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+    let base: String = source
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+        .take(15)
+        .collect();
+
+    if base.len() < 3 {
+        "user".to_string()
+    } else {
+        base
+    }
+}
+
+async fn create_user(
+    app_state: &AppState,
+    provider: &str,
+    identity: &ProviderIdentity,
+    email: &str,
+) -> Result<User, ApiError> {
+    let base = username_base(identity, email);
+    let mut username = base.clone();
+    for _ in 0..5 {
+        let taken = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1)",
+        )
+        .bind(&username)
+        .fetch_one(&app_state.pool)
+        .await?;
+        if !taken {
+            break;
+        }
+        username = format!("{base}_{}", rand::random::<u16>() % 10000);
+    }
+
+    // nobody knows this password, one can be set later through a reset
+    let password_hash = app_state.passwords.hash(&generate_opaque_token()).await?;
+
+    let mut tx = app_state.pool.begin().await?;
+
+    let user = sqlx::query_as::<_, User>(
+        "
+			INSERT INTO users (id, username, email, password, email_verified_at)
+			VALUES ($1, $2, $3, $4, CASE WHEN $5 THEN NOW() END)
+			RETURNING id, username, email, role, email_verified_at, status, suspended_until,
+				display_name, avatar_url, bio, locale, timezone
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(&username)
+    .bind(email)
+    .bind(&password_hash)
+    .bind(identity.email_verified)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        "
+			INSERT INTO external_identities (id, user_id, provider, subject, email, last_login_at)
+			VALUES ($1, $2, $3, $4, $5, NOW())
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(&user.id)
+    .bind(provider)
+    .bind(&identity.subject)
+    .bind(email)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    if !user.is_email_verified()
+        && let Err(e) = send_verification_email(app_state, &user.id, &user.email).await
+    {
+        eprintln!("[EMAIL VERIFICATION]: {e}");
+    }
+
+    Ok(user)
+}
+
+// redirects the browser to the provider
+pub async fn authorize(
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (url, state_cookie) = begin(&app_state, &path, None).await?;
+
+    Ok(HttpResponse::Found()
+        .cookie(state_cookie)
+        .insert_header((header::LOCATION, url))
+        .finish())
+}
+
+// linking is requested with an access token, so the url is handed back instead of a redirect.
+// like passkeys, a linked provider outlives the token, so the password is asked again
+pub async fn start_link(
+    path: web::Path<String>,
+    dto: web::Json<OAuthLinkStartDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    check_current_password(&app_state, &claims.sub, &dto.current_password).await?;
+    let (url, state_cookie) = begin(&app_state, &path, Some(&claims.sub)).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(state_cookie)
+        .json(serde_json::json!({ "authorization_url": url })))
+}
+
+// what a callback comes to before anybody is signed in
+enum Callback {
+    SignIn(User),
+    Answer(HttpResponse),
+}
+
+async fn resolve_callback(
+    app_state: &AppState,
+    req: &HttpRequest,
+    provider_name: &str,
+    query: &OAuthCallbackQuery,
+) -> Result<Callback, ApiError> {
+    if let Some(error) = &query.error {
+        return Err(ApiError::Unauthorized(format!(
+            "Provider refused the login: {error}"
+        )));
+    }
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        return Err(ApiError::Other("Missing code or state".into()));
+    };
+
+    // checked before the state is used up, so a forged callback doesn't break the real login
+    let state_cookie = build_state_cookie(app_state, state);
+    if req
+        .cookie(STATE_COOKIE)
+        .is_none_or(|cookie| cookie.value() != state_cookie.value())
+    {
+        return Err(ApiError::Other("Invalid or expired OAuth state".into()));
+    }
+
+    // single use, a replayed callback finds nothing
+    let pending = sqlx::query_as::<_, PendingLogin>(
+        "
+			DELETE FROM oauth_states
+			WHERE id = $1 AND provider = $2 AND expires_at > NOW()
+			RETURNING code_verifier, nonce, user_id
+		",
+    )
+    .bind(hash_token(state))
+    .bind(provider_name)
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or_else(|| ApiError::Other("Invalid or expired OAuth state".into()))?;
+
+    let provider = app_state.oauth.get(provider_name)?;
+    let tokens = app_state
+        .oauth
+        .exchange_code(
+            provider,
+            code,
+            &redirect_uri(app_state, provider_name),
+            &pending.code_verifier,
+        )
+        .await?;
+    let identity = app_state
+        .oauth
+        .identity(provider, &tokens, &pending.nonce)
+        .await?;
+
+    let linked_user_id = sqlx::query_scalar::<_, String>(
+        "
+			UPDATE external_identities
+			SET last_login_at = NOW()
+			WHERE provider = $1 AND subject = $2
+			RETURNING user_id
+		",
+    )
+    .bind(provider_name)
+    .bind(&identity.subject)
+    .fetch_optional(&app_state.pool)
+    .await?;
+
+    match (linked_user_id, pending.user_id) {
+        // a signed in user linking the provider
+        (Some(linked), Some(requested)) if linked != requested => Err(ApiError::Other(format!(
+            "This {provider_name} account is already linked to another user"
+        ))),
+        (None, Some(requested)) => {
+            let linked = insert_identity(
+                app_state,
+                &requested,
+                provider_name,
+                &identity.subject,
+                identity.email.as_deref(),
+            )
+            .await?;
+            Ok(Callback::Answer(HttpResponse::Created().json(linked)))
+        }
+        (Some(_), Some(_)) => Err(ApiError::Other(format!(
+            "This {provider_name} account is already linked"
+        ))),
+
+        // a returning user
+        (Some(linked), None) => {
+            let user = find_user_by_id(&linked, &app_state.pool).await?;
+            Ok(Callback::SignIn(user))
+        }
+
+        // a first login with this provider
+        (None, None) => {
+            let email = identity.email.clone().ok_or_else(|| {
+                ApiError::Other(format!("{provider_name} did not share an email address"))
+            })?;
+
+            let existing = sqlx::query_scalar::<_, String>("SELECT id FROM users WHERE email = $1")
+                .bind(&email)
+                .fetch_optional(&app_state.pool)
+                .await?;
+
+            match existing {
+                // never linked on our own, the owner has to confirm with their password
+                Some(user_id) if identity.email_verified => {
+                    let link_token =
+                        create_link_token(app_state, &user_id, provider_name, &identity, &email)?;
+                    Ok(Callback::Answer(HttpResponse::Ok().json(
+                        OAuthLinkRequiredResponse {
+                            link_required: true,
+                            link_token,
+                            provider: provider_name.to_string(),
+                            email,
+                        },
+                    )))
+                }
+                Some(_) => Err(ApiError::Other(format!(
+                    "An account with this email already exists, sign in to link {provider_name}"
+                ))),
+                None => {
+                    let user = create_user(app_state, provider_name, &identity, &email).await?;
+                    Ok(Callback::SignIn(user))
+                }
+            }
+        }
+    }
+}
+
+// refused callbacks are audited without an account, a forged one has none to go with
+pub async fn callback(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<OAuthCallbackQuery>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let provider_name = path.into_inner();
+
+    let mut response = match resolve_callback(&app_state, &req, &provider_name, &query).await {
+        Ok(Callback::SignIn(user)) => sign_in(&app_state, &req, user, &provider_name).await?,
+        Ok(Callback::Answer(response)) => response,
+        Err(e) => {
+            record_auth_event(
+                &app_state,
+                &req,
+                "auth.social_login",
+                None,
+                Outcome::Failure,
+                json!({ "provider": provider_name, "reason": e.to_string() }),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+
+    // the state is used up, its cookie goes with it
+    let state = query.state.as_deref().unwrap_or_default();
+    response
+        .add_removal_cookie(&build_state_cookie(&app_state, state))
+        .map_err(|e| ApiError::InternalServer(e.to_string()))?;
+    Ok(response)
+}
+
+// the owner of the account proving it with their password
+async fn check_link_password(
+    app_state: &AppState,
+    ip: &str,
+    link: &OAuthLinkClaims,
+    password: &str,
+) -> Result<UserWithPassword, ApiError> {
+    let keys = [ThrottleKey::Account(&link.sub), ThrottleKey::Ip(ip)];
+    let attempt = throttle::count_attempt(app_state, &keys).await?;
+
+    let user = find_user_with_password_by_id(&link.sub, &app_state.pool).await?;
+    let verification = app_state.passwords.verify(password, &user.password).await?;
+    if verification == Verification::Invalid {
+        return Err(ApiError::Other("Incorrect Password".into()));
+    }
+    attempt.forgive(app_state).await?;
+    if user.email != link.email {
+        return Err(ApiError::Unauthorized(
+            "Invalid or expired link token".into(),
+        ));
+    }
+
+    Ok(user)
+}
+
+pub async fn confirm_link(
+    req: HttpRequest,
+    dto: web::Json<ConfirmOAuthLinkDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let link = app_state
+        .keys
+        .verify::<OAuthLinkClaims>(&dto.link_token)
+        .filter(|c| c.purpose == LINK_PURPOSE)
+        .ok_or_else(|| ApiError::Unauthorized("Invalid or expired link token".into()))?;
+
+    let ip = client_ip(&req, &app_state.config);
+    let user = match check_link_password(&app_state, &ip, &link, &dto.password).await {
+        Ok(user) => user,
+        Err(e) => {
+            record_auth_event(
+                &app_state,
+                &req,
+                "auth.social_login",
+                Some(&link.sub),
+                Outcome::Failure,
+                json!({ "provider": link.provider, "reason": e.to_string() }),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+
+    insert_identity(
+        &app_state,
+        &user.id,
+        &link.provider,
+        &link.subject,
+        Some(&link.email),
+    )
+    .await?;
+
+    sign_in(&app_state, &req, User::from(user), &link.provider).await
+}
+
+pub async fn list_identities(
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let identities = sqlx::query_as::<_, ExternalIdentity>(
+        "
+			SELECT id, provider, subject, email, created_at, last_login_at
+			FROM external_identities
+			WHERE user_id = $1
+			ORDER BY created_at
+		",
+    )
+    .bind(&claims.sub)
+    .fetch_all(&app_state.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(identities))
+}
+
+pub async fn unlink_identity(
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+
+    let deleted = sqlx::query("DELETE FROM external_identities WHERE id = $1 AND user_id = $2")
+        .bind(&id)
+        .bind(&claims.sub)
+        .execute(&app_state.pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!("Identity {} not found", id)));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex, mpsc},
+        thread,
+    };
+
+    use actix_web::{App, HttpServer, dev::ServerHandle, rt::System, test::TestRequest};
+    use reqwest::Url;
+    use serde_json::{Value, json};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        common::{
+            config::{OAuthProviderConfig, OAuthProviderKind},
+            testing,
+        },
+        entities::auth::keys::KeyStore,
+    };
+
+    const PROVIDER: &str = "mock";
+    const CLIENT_ID: &str = "rust-backend";
+
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/tests/fixtures/oauth/{name}", env!("CARGO_MANIFEST_DIR"));
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    // signs like the provider would, with the key published as `kid`
+    fn signer(kid: &str, key: &str) -> KeyStore {
+        let public_keys = [(kid.to_string(), fixture(&format!("{key}.pub.pem")))];
+        KeyStore::asymmetric(kid, &fixture(&format!("{key}.pem")), &public_keys).unwrap()
+    }
+
+    // A local OpenID provider with discovery, a JWKS and a token endpoint
+    // answering every code with the ID token the test put in `id_token`
+    struct MockProvider {
+        issuer: String,
+        keys: KeyStore,
+        id_token: Arc<Mutex<String>>,
+        server: ServerHandle,
+    }
+
+    impl MockProvider {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let keys = signer("provider-1", "provider");
+            let id_token = Arc::new(Mutex::new(String::new()));
+
+            let discovery = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{issuer}/authorize"),
+                "token_endpoint": format!("{issuer}/token"),
+                "jwks_uri": format!("{issuer}/jwks"),
+            });
+            let jwks = serde_json::to_value(keys.jwks()).unwrap();
+            let tokens = id_token.clone();
+
+            // a server of its own, the test runtime has no actix system to run it on
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                System::new().block_on(async move {
+                    let server = HttpServer::new(move || {
+                        let (discovery, jwks, tokens) =
+                            (discovery.clone(), jwks.clone(), tokens.clone());
+                        App::new()
+                            .route(
+                                "/.well-known/openid-configuration",
+                                web::get().to(move || {
+                                    let discovery = discovery.clone();
+                                    async move { HttpResponse::Ok().json(discovery) }
+                                }),
+                            )
+                            .route(
+                                "/jwks",
+                                web::get().to(move || {
+                                    let jwks = jwks.clone();
+                                    async move { HttpResponse::Ok().json(jwks) }
+                                }),
+                            )
+                            .route(
+                                "/token",
+                                web::post().to(move || {
+                                    let id_token = tokens.lock().unwrap().clone();
+                                    async move {
+                                        HttpResponse::Ok().json(json!({
+                                            "access_token": "provider-access-token",
+                                            "token_type": "Bearer",
+                                            "id_token": id_token,
+                                        }))
+                                    }
+                                }),
+                            )
+                    })
+                    .workers(1)
+                    .listen(listener)
+                    .unwrap()
+                    .run();
+                    sender.send(server.handle()).unwrap();
+                    server.await
+                })
+            });
+            let server = receiver.recv().unwrap();
+
+            Self {
+                issuer,
+                keys,
+                id_token,
+                server,
+            }
+        }
+
+        fn config(&self) -> OAuthProviderConfig {
+            OAuthProviderConfig {
+                name: PROVIDER.into(),
+                kind: OAuthProviderKind::Oidc,
+                client_id: CLIENT_ID.into(),
+                client_secret: None,
+                issuer: Some(self.issuer.clone()),
+                authorization_endpoint: None,
+                token_endpoint: None,
+                jwks_uri: None,
+                userinfo_endpoint: None,
+                scopes: vec!["openid".into(), "email".into()],
+            }
+        }
+
+        fn claims(&self, subject: &str, email: &str, nonce: &str) -> Value {
+            let now = Utc::now().timestamp();
+            json!({
+                "iss": self.issuer,
+                "aud": CLIENT_ID,
+                "sub": subject,
+                "iat": now,
+                "exp": now + 300,
+                "nonce": nonce,
+                "email": email,
+                "email_verified": true,
+            })
+        }
+
+        // the ID token the next code is exchanged for
+        fn answer_with(&self, keys: &KeyStore, claims: &Value) {
+            *self.id_token.lock().unwrap() = keys.sign(claims).unwrap();
+        }
+    }
+
+    impl Drop for MockProvider {
+        fn drop(&mut self) {
+            // the stop command is sent right away, waiting for it is optional
+            drop(self.server.stop(false));
+        }
+    }
+
+    fn app_state(provider: &MockProvider, pool: PgPool) -> web::Data<AppState> {
+        let mut config = testing::config();
+        config.oauth_providers = vec![provider.config()];
+
+        web::Data::new(testing::app_state(pool, config))
+    }
+
+    // starts a login and reads state and nonce off the redirect to the provider
+    async fn begin_login(app_state: &web::Data<AppState>) -> (String, String) {
+        let response = authorize(web::Path::from(PROVIDER.to_string()), app_state.clone())
+            .await
+            .unwrap();
+        let location = response.headers().get(header::LOCATION).unwrap();
+        let url = Url::parse(location.to_str().unwrap()).unwrap();
+
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        let state = param("state");
+
+        let state_cookie = response
+            .cookies()
+            .find(|cookie| cookie.name() == STATE_COOKIE)
+            .unwrap();
+        assert_eq!(state_cookie.value(), hash_token(&state));
+        assert_eq!(state_cookie.http_only(), Some(true));
+        assert_eq!(state_cookie.same_site(), Some(SameSite::Lax));
+
+        (state, param("nonce"))
+    }
+
+    // the browser sends back the cookie `authorize` set for the state
+    async fn return_from_provider(
+        app_state: &web::Data<AppState>,
+        state: &str,
+    ) -> Result<HttpResponse, ApiError> {
+        return_to_browser(app_state, state, Some(&hash_token(state))).await
+    }
+
+    async fn return_to_browser(
+        app_state: &web::Data<AppState>,
+        state: &str,
+        state_cookie: Option<&str>,
+    ) -> Result<HttpResponse, ApiError> {
+        let mut req = TestRequest::default();
+        if let Some(value) = state_cookie {
+            req = req.cookie(Cookie::new(STATE_COOKIE, value.to_owned()));
+        }
+
+        callback(
+            req.to_http_request(),
+            web::Path::from(PROVIDER.to_string()),
+            web::Query(OAuthCallbackQuery {
+                code: Some("provider-code".into()),
+                state: Some(state.to_string()),
+                error: None,
+            }),
+            app_state.clone(),
+        )
+        .await
+    }
+
+    async fn count_identities(app_state: &AppState) -> i64 {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM external_identities")
+            .fetch_one(&app_state.pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn unknown_state_is_rejected(pool: PgPool) {
+        let provider = MockProvider::start();
+        let app_state = app_state(&provider, pool);
+
+        let (_, nonce) = begin_login(&app_state).await;
+        provider.answer_with(
+            &provider.keys,
+            &provider.claims("subject-1", "new@example.com", &nonce),
+        );
+        let rejected = return_from_provider(&app_state, "forged-state").await;
+
+        assert!(
+            matches!(rejected, Err(ApiError::Other(message)) if message.contains("OAuth state"))
+        );
+    }
+
+    #[sqlx::test]
+    async fn state_returned_to_another_browser_is_rejected(pool: PgPool) {
+        let provider = MockProvider::start();
+        let app_state = app_state(&provider, pool);
+
+        let (other_state, _) = begin_login(&app_state).await;
+        let (state, nonce) = begin_login(&app_state).await;
+        provider.answer_with(
+            &provider.keys,
+            &provider.claims("subject-1", "new@example.com", &nonce),
+        );
+
+        for state_cookie in [None, Some(hash_token(&other_state))] {
+            let rejected = return_to_browser(&app_state, &state, state_cookie.as_deref()).await;
+            assert!(
+                matches!(rejected, Err(ApiError::Other(message)) if message.contains("OAuth state"))
+            );
+        }
+
+        // the login itself goes on in the browser that started it, and the cookie is dropped
+        let response = return_from_provider(&app_state, &state).await.unwrap();
+        let removal = response
+            .cookies()
+            .find(|cookie| cookie.name() == STATE_COOKIE)
+            .unwrap();
+        assert_eq!(removal.value(), "");
+        assert_eq!(count_identities(&app_state).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn nonce_of_another_login_is_rejected(pool: PgPool) {
+        let provider = MockProvider::start();
+        let app_state = app_state(&provider, pool);
+
+        let (_, other_nonce) = begin_login(&app_state).await;
+        let (state, _) = begin_login(&app_state).await;
+        provider.answer_with(
+            &provider.keys,
+            &provider.claims("subject-1", "new@example.com", &other_nonce),
+        );
+        let rejected = return_from_provider(&app_state, &state).await;
+
+        assert!(
+            matches!(rejected, Err(ApiError::Unauthorized(message)) if message.ends_with("nonce mismatch"))
+        );
+        assert_eq!(count_identities(&app_state).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn id_token_signed_by_an_unknown_key_is_rejected(pool: PgPool) {
+        let provider = MockProvider::start();
+        let app_state = app_state(&provider, pool);
+
+        // a kid the provider never published, and a forged signature under its own kid
+        for (rogue, reason) in [
+            (signer("rogue-1", "rogue"), "signed by an unknown key"),
+            (signer("provider-1", "rogue"), "InvalidSignature"),
+        ] {
+            let (state, nonce) = begin_login(&app_state).await;
+            provider.answer_with(
+                &rogue,
+                &provider.claims("subject-1", "new@example.com", &nonce),
+            );
+            let rejected = return_from_provider(&app_state, &state).await;
+
+            assert!(
+                matches!(rejected, Err(ApiError::Unauthorized(message)) if message.ends_with(reason))
+            );
+        }
+        assert_eq!(count_identities(&app_state).await, 0);
+        assert_eq!(
+            testing::audit_outcomes(&app_state.pool, "auth.social_login").await,
+            ["failure", "failure"]
+        );
+    }
+
+    #[sqlx::test]
+    async fn linked_identity_signs_in(pool: PgPool) {
+        let provider = MockProvider::start();
+        let app_state = app_state(&provider, pool);
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        insert_identity(&app_state, &user.id, PROVIDER, "subject-1", None)
+            .await
+            .unwrap();
+
+        let (state, nonce) = begin_login(&app_state).await;
+        provider.answer_with(
+            &provider.keys,
+            &provider.claims("subject-1", "alice@example.com", &nonce),
+        );
+        let response = return_from_provider(&app_state, &state).await.unwrap();
+
+        let body = testing::json_body(response).await;
+        assert_eq!(body["user"]["id"], user.id.as_str());
+        assert!(body["access_token"].is_string());
+        assert_eq!(
+            testing::audit_outcomes(&app_state.pool, "auth.social_login").await,
+            ["success"]
+        );
+    }
+
+    #[sqlx::test]
+    async fn verified_email_of_an_existing_account_needs_its_password(pool: PgPool) {
+        let provider = MockProvider::start();
+        let app_state = app_state(&provider, pool);
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let (state, nonce) = begin_login(&app_state).await;
+        provider.answer_with(
+            &provider.keys,
+            &provider.claims("subject-1", "alice@example.com", &nonce),
+        );
+        let response = return_from_provider(&app_state, &state).await.unwrap();
+
+        let body = testing::json_body(response).await;
+        assert_eq!(body["link_required"], true);
+        assert!(body.get("access_token").is_none());
+        let link = app_state
+            .keys
+            .verify::<OAuthLinkClaims>(body["link_token"].as_str().unwrap())
+            .unwrap();
+        assert_eq!((link.sub, link.subject), (user.id, "subject-1".to_string()));
+        assert_eq!(count_identities(&app_state).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn linking_needs_the_current_password(pool: PgPool) {
+        let provider = MockProvider::start();
+        let app_state = app_state(&provider, pool);
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let start = async |current_password: &str| {
+            start_link(
+                web::Path::from(PROVIDER.to_string()),
+                web::Json(OAuthLinkStartDto {
+                    current_password: current_password.to_string(),
+                }),
+                testing::signed_in(&user).await,
+                app_state.clone(),
+            )
+            .await
+        };
+
+        let rejected = start("not-the-password").await;
+        assert!(
+            matches!(rejected, Err(ApiError::Other(message)) if message == "Incorrect Password")
+        );
+        let pending = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM oauth_states")
+            .fetch_one(&app_state.pool)
+            .await
+            .unwrap();
+        assert_eq!(pending, 0);
+
+        let response = start("secret12").await.unwrap();
+        let body = testing::json_body(response).await;
+        assert!(body["authorization_url"].is_string());
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{Jwk, JwkSet},
+};
+use reqwest::{Client, Url, header};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::common::{
+    config::{OAuthProviderConfig, OAuthProviderKind},
+    errors::api_error::ApiError,
+};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// providers move their endpoints rarely, but it shouldn't take a restart to follow
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
+// asymmetric only, a provider can't share a secret with every client
+const ID_TOKEN_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::EdDSA,
+];
+
+#[derive(Clone, Deserialize)]
+struct Endpoints {
+    issuer: Option<String>,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: Option<String>,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ProviderTokens {
+    pub access_token: String,
+    pub id_token: Option<String>,
+}
+
+// who the provider says the user is
+pub struct ProviderIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    // some providers send it as a string
+    email_verified: Option<Value>,
+    preferred_username: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    id: i64,
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+pub struct OAuthProvider {
+    pub config: OAuthProviderConfig,
+    // discovered again once DISCOVERY_TTL is over, or when a token names an unknown kid
+    endpoints: RwLock<Option<(Endpoints, Instant)>>,
+    // refetched when a token is signed by an unknown kid
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+pub struct OAuthProviders {
+    http: Client,
+    providers: HashMap<String, OAuthProvider>,
+}
+
+fn provider_error(provider: &str, e: impl std::fmt::Display) -> ApiError {
+    ApiError::InternalServer(format!("{provider} provider error: {e}"))
+}
+
+fn invalid_id_token(reason: &str) -> ApiError {
+    ApiError::Unauthorized(format!("Invalid ID token: {reason}"))
+}
+
+impl OAuthProviders {
+    pub fn new(configs: &[OAuthProviderConfig]) -> Result<Self, String> {
+        let http = Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .user_agent("rust-backend")
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let providers = configs
+            .iter()
+            .map(|config| {
+                (
+                    config.name.clone(),
+                    OAuthProvider {
+                        config: config.clone(),
+                        endpoints: RwLock::default(),
+                        jwks: RwLock::default(),
+                    },
+                )
+            })
+            .collect();
+
+        Ok(Self { http, providers })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&OAuthProvider, ApiError> {
+        self.providers
+            .get(name)
+            .ok_or_else(|| ApiError::NotFound(format!("Provider {} not found", name)))
+    }
+
+    async fn endpoints(&self, provider: &OAuthProvider) -> Result<Endpoints, ApiError> {
+        if let Some((endpoints, fetched_at)) = provider.endpoints.read().unwrap().clone()
+            && fetched_at.elapsed() < DISCOVERY_TTL
+        {
+            return Ok(endpoints);
+        }
+
+        let config = &provider.config;
+        let mut endpoints = match (&config.kind, &config.issuer) {
+            (OAuthProviderKind::Oidc, Some(issuer)) => {
+                let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+                let discovered: Endpoints = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| provider_error(&config.name, e))?
+                    .json()
+                    .await
+                    .map_err(|e| provider_error(&config.name, e))?;
+
+                if discovered.issuer.as_deref() != Some(issuer.as_str()) {
+                    return Err(provider_error(
+                        &config.name,
+                        "discovery document belongs to another issuer",
+                    ));
+                }
+                discovered
+            }
+            // the config loader made sure these are set
+            _ => Endpoints {
+                issuer: config.issuer.clone(),
+                authorization_endpoint: config.authorization_endpoint.clone().unwrap_or_default(),
+                token_endpoint: config.token_endpoint.clone().unwrap_or_default(),
+                jwks_uri: None,
+                userinfo_endpoint: None,
+            },
+        };
+
+        // explicit endpoints win over discovered ones
+        if let Some(url) = &config.authorization_endpoint {
+            endpoints.authorization_endpoint = url.clone();
+        }
+        if let Some(url) = &config.token_endpoint {
+            endpoints.token_endpoint = url.clone();
+        }
+        if config.jwks_uri.is_some() {
+            endpoints.jwks_uri = config.jwks_uri.clone();
+        }
+        if config.userinfo_endpoint.is_some() {
+            endpoints.userinfo_endpoint = config.userinfo_endpoint.clone();
+        }
+
+        *provider.endpoints.write().unwrap() = Some((endpoints.clone(), Instant::now()));
+        Ok(endpoints)
+    }
+
+    pub async fn authorization_url(
+        &self,
+        provider: &OAuthProvider,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String, ApiError> {
+        let endpoints = self.endpoints(provider).await?;
+        let mut url = Url::parse(&endpoints.authorization_endpoint)
+            .map_err(|e| provider_error(&provider.config.name, e))?;
+
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.config.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &provider.config.scopes.join(" "))
+            .append_pair("state", state)
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        if provider.config.kind == OAuthProviderKind::Oidc {
+            url.query_pairs_mut().append_pair("nonce", nonce);
+        }
+
+        Ok(url.into())
+    }
+
+    pub async fn exchange_code(
+        &self,
+        provider: &OAuthProvider,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<ProviderTokens, ApiError> {
+        let endpoints = self.endpoints(provider).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", provider.config.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &provider.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let response = self
+            .http
+            .post(&endpoints.token_endpoint)
+            .header(header::ACCEPT, "application/json")
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| provider_error(&provider.config.name, e))?;
+
+        // a rejected code is the caller's problem, not ours
+        if response.status().is_client_error() {
+            return Err(ApiError::Unauthorized(
+                "Authorization code was rejected by the provider".into(),
+            ));
+        }
+
+        response
+            .error_for_status()
+            .map_err(|e| provider_error(&provider.config.name, e))?
+            .json()
+            .await
+            .map_err(|e| ApiError::Unauthorized(format!("Unexpected token response: {e}")))
+    }
+
+    pub async fn identity(
+        &self,
+        provider: &OAuthProvider,
+        tokens: &ProviderTokens,
+        nonce: &str,
+    ) -> Result<ProviderIdentity, ApiError> {
+        match provider.config.kind {
+            OAuthProviderKind::Oidc => {
+                let id_token = tokens
+                    .id_token
+                    .as_deref()
+                    .ok_or_else(|| invalid_id_token("missing from the token response"))?;
+                self.verify_id_token(provider, id_token, nonce).await
+            }
+            OAuthProviderKind::Github => self.github_identity(provider, &tokens.access_token).await,
+        }
+    }
+
+    async fn find_jwk(&self, provider: &OAuthProvider, kid: &str) -> Result<Jwk, ApiError> {
+        let cached = provider
+            .jwks
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|jwks| jwks.find(kid).cloned());
+        if let Some(jwk) = cached {
+            return Ok(jwk);
+        }
+
+        // unknown kid usually means the provider rotated its keys, maybe to a new jwks_uri
+        provider.endpoints.write().unwrap().take();
+        let jwks_uri = self
+            .endpoints(provider)
+            .await?
+            .jwks_uri
+            .ok_or_else(|| provider_error(&provider.config.name, "no jwks_uri"))?;
+        let jwks: JwkSet = self
+            .http
+            .get(jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| provider_error(&provider.config.name, e))?
+            .json()
+            .await
+            .map_err(|e| provider_error(&provider.config.name, e))?;
+
+        let jwk = jwks.find(kid).cloned();
+        *provider.jwks.write().unwrap() = Some(jwks);
+        jwk.ok_or_else(|| invalid_id_token("signed by an unknown key"))
+    }
+
+    async fn verify_id_token(
+        &self,
+        provider: &OAuthProvider,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<ProviderIdentity, ApiError> {
+        let header = decode_header(id_token).map_err(|_| invalid_id_token("malformed"))?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(invalid_id_token("unsupported algorithm"));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| invalid_id_token("no kid in the header"))?;
+
+        let jwk = self.find_jwk(provider, &kid).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid_id_token("unusable key"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&provider.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        if let Some(issuer) = self.endpoints(provider).await?.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| invalid_id_token(&e.to_string()))?
+            .claims;
+
+        // binds the token to the login attempt that asked for it
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid_id_token("nonce mismatch"));
+        }
+
+        let email_verified = match claims.email_verified {
+            Some(Value::Bool(verified)) => verified,
+            Some(Value::String(verified)) => verified == "true",
+            _ => false,
+        };
+
+        Ok(ProviderIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified,
+            username: claims.preferred_username.or(claims.name),
+        })
+    }
+
+    async fn github_identity(
+        &self,
+        provider: &OAuthProvider,
+        access_token: &str,
+    ) -> Result<ProviderIdentity, ApiError> {
+        let api = self
+            .endpoints(provider)
+            .await?
+            .userinfo_endpoint
+            .ok_or_else(|| provider_error(&provider.config.name, "no api url"))?;
+        let api = api.trim_end_matches('/');
+
+        let user: GithubUser = self
+            .http
+            .get(format!("{api}/user"))
+            .bearer_auth(access_token)
+            .header(header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| provider_error(&provider.config.name, e))?
+            .json()
+            .await
+            .map_err(|e| provider_error(&provider.config.name, e))?;
+
+        // the profile email is whatever the user typed, only this list says what is verified
+        let emails: Vec<GithubEmail> = self
+            .http
+            .get(format!("{api}/user/emails"))
+            .bearer_auth(access_token)
+            .header(header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| provider_error(&provider.config.name, e))?
+            .json()
+            .await
+            .map_err(|e| provider_error(&provider.config.name, e))?;
+        let primary = emails.into_iter().find(|email| email.primary);
+
+        Ok(ProviderIdentity {
+            subject: user.id.to_string(),
+            email_verified: primary.as_ref().is_some_and(|email| email.verified),
+            email: primary.map(|email| email.email),
+            username: Some(user.login),
+        })
+    }
+}
@@ -0,0 +1,320 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::common::{AppState, config::LoginThrottleConfig, errors::api_error::ApiError};
+
+const ACCOUNT: &str = "account";
+const IP: &str = "ip";
+// how often rows that count for nothing anymore are deleted
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Unknown identifiers are counted like accounts, so locking out
+// tells nothing about whether the account exists
+pub enum ThrottleKey<'a> {
+    Account(&'a str),
+    Ip(&'a str),
+}
+
+impl ThrottleKey<'_> {
+    fn parts(&self) -> (&'static str, &str) {
+        match self {
+            ThrottleKey::Account(key) => (ACCOUNT, key),
+            ThrottleKey::Ip(key) => (IP, key),
+        }
+    }
+
+    // only accounts back off, an ip may be shared by many users
+    fn delay(&self, config: &LoginThrottleConfig, failures: u32) -> i64 {
+        let lockout_after = match self {
+            ThrottleKey::Account(_) => config.account_lockout_after,
+            ThrottleKey::Ip(_) => config.ip_lockout_after,
+        };
+
+        if failures >= lockout_after {
+            return config.lockout_duration;
+        }
+        match self {
+            ThrottleKey::Account(_) if failures > config.backoff_after => {
+                let exponent = (failures - config.backoff_after - 1).min(30);
+                config
+                    .backoff_base
+                    .saturating_mul(1 << exponent)
+                    .min(config.lockout_duration)
+            }
+            _ => 0,
+        }
+    }
+}
+
+// identifiers differing only in case or spacing are the same account
+pub fn normalize_identifier(identifier: &str) -> String {
+    identifier.trim().to_lowercase()
+}
+
+// a key as it was counted, to take the attempt back
+struct Counted {
+    kind: &'static str,
+    key: String,
+    blocked_until_before: Option<DateTime<Utc>>,
+    blocked_until: Option<DateTime<Utc>>,
+}
+
+// An attempt already counted as a failure
+#[must_use]
+pub struct Attempt {
+    counted: Vec<Counted>,
+}
+
+// Counts the attempt as failed before the credentials are checked and fails with the
+// longest wait among the keys. Checking and counting hold the rows locked, so
+// parallel guesses can't all pass the check before any of them is recorded
+pub async fn count_attempt(
+    app_state: &AppState,
+    keys: &[ThrottleKey<'_>],
+) -> Result<Attempt, ApiError> {
+    let config = &app_state.config.login_throttle;
+    let mut tx = app_state.pool.begin().await?;
+
+    let mut blocked_until: Option<DateTime<Utc>> = None;
+    let mut before = Vec::with_capacity(keys.len());
+    for key in keys {
+        let (kind, key) = key.parts();
+        let until = sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+            "
+				INSERT INTO login_throttles (kind, key)
+				VALUES ($1, $2)
+				ON CONFLICT (kind, key) DO UPDATE
+				SET kind = EXCLUDED.kind
+				RETURNING blocked_until
+			",
+        )
+        .bind(kind)
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        blocked_until = blocked_until.max(until);
+        before.push(until);
+    }
+
+    // nothing is counted while blocked, the rows created above are rolled back
+    let now = Utc::now();
+    if let Some(until) = blocked_until.filter(|until| *until > now) {
+        let retry_after = ((until - now).num_milliseconds() as u64).div_ceil(1000);
+        return Err(ApiError::TooManyRequests {
+            message: "Too many failed login attempts, try again later".into(),
+            retry_after,
+        });
+    }
+
+    let mut counted = Vec::with_capacity(keys.len());
+    for (throttle_key, blocked_until_before) in keys.iter().zip(before) {
+        let (kind, key) = throttle_key.parts();
+
+        // the count starts over once the last failure is out of the window
+        let failures = sqlx::query_scalar::<_, i32>(
+            "
+				UPDATE login_throttles
+				SET failures = CASE
+						WHEN last_failure_at < NOW() - make_interval(secs => $3)
+						THEN 1
+						ELSE failures + 1
+					END,
+					last_failure_at = NOW()
+				WHERE kind = $1 AND key = $2
+				RETURNING failures
+			",
+        )
+        .bind(kind)
+        .bind(key)
+        .bind(config.failure_window as f64)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let delay = throttle_key.delay(config, failures.max(0) as u32);
+        let blocked_until = if delay > 0 {
+            sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+                "
+					UPDATE login_throttles
+					SET blocked_until = NOW() + make_interval(secs => $3)
+					WHERE kind = $1 AND key = $2
+					RETURNING blocked_until
+				",
+            )
+            .bind(kind)
+            .bind(key)
+            .bind(delay as f64)
+            .fetch_one(&mut *tx)
+            .await?
+        } else {
+            blocked_until_before
+        };
+
+        counted.push(Counted {
+            kind,
+            key: key.to_owned(),
+            blocked_until_before,
+            blocked_until,
+        });
+    }
+
+    tx.commit().await?;
+
+    Ok(Attempt { counted })
+}
+
+impl Attempt {
+    // Takes the attempt back once it turned out not to be a failed guess.
+    // A block it set is lifted unless a later failure replaced it
+    pub async fn forgive(self, app_state: &AppState) -> Result<(), ApiError> {
+        for counted in &self.counted {
+            sqlx::query(
+                "
+					UPDATE login_throttles
+					SET failures = GREATEST(failures - 1, 0),
+						blocked_until = CASE
+							WHEN blocked_until IS NOT DISTINCT FROM $3 THEN $4
+							ELSE blocked_until
+						END
+					WHERE kind = $1 AND key = $2
+				",
+            )
+            .bind(counted.kind)
+            .bind(&counted.key)
+            .bind(counted.blocked_until)
+            .bind(counted.blocked_until_before)
+            .execute(&app_state.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// a successful login only clears the account, the ip keeps its count
+pub async fn clear_account(app_state: &AppState, key: &str) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM login_throttles WHERE kind = $1 AND key = $2")
+        .bind(ACCOUNT)
+        .bind(key)
+        .execute(&app_state.pool)
+        .await?;
+
+    Ok(())
+}
+
+// Deletes the rows whose failures are out of the window and which block nothing,
+// they would start over at the next failure anyway
+pub async fn sweep(app_state: &AppState) -> Result<u64, ApiError> {
+    let deleted = sqlx::query(
+        "
+			DELETE FROM login_throttles
+			WHERE last_failure_at < NOW() - make_interval(secs => $1)
+				AND (blocked_until IS NULL OR blocked_until <= NOW())
+		",
+    )
+    .bind(app_state.config.login_throttle.failure_window as f64)
+    .execute(&app_state.pool)
+    .await?;
+
+    Ok(deleted.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future::join_all;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::common::testing;
+
+    async fn failures(app_state: &AppState, kind: &str, key: &str) -> i32 {
+        sqlx::query_scalar::<_, i32>(
+            "SELECT failures FROM login_throttles WHERE kind = $1 AND key = $2",
+        )
+        .bind(kind)
+        .bind(key)
+        .fetch_one(&app_state.pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn parallel_attempts_stop_at_the_backoff(pool: PgPool) {
+        let app_state = testing::app_state(pool, testing::config());
+        let keys = [ThrottleKey::Account("alice"), ThrottleKey::Ip("10.0.0.1")];
+
+        let attempts = join_all((0..10).map(|_| count_attempt(&app_state, &keys))).await;
+        let passed = attempts.iter().filter(|attempt| attempt.is_ok()).count();
+
+        // the attempt after `backoff_after` failures is the last one let through
+        let backoff_after = app_state.config.login_throttle.backoff_after;
+        assert_eq!(passed, backoff_after as usize + 1);
+        assert!(
+            attempts
+                .iter()
+                .any(|attempt| matches!(attempt, Err(ApiError::TooManyRequests { .. })))
+        );
+        assert_eq!(failures(&app_state, ACCOUNT, "alice").await, passed as i32);
+    }
+
+    #[sqlx::test]
+    async fn forgiven_attempt_lifts_the_block_it_set(pool: PgPool) {
+        let app_state = testing::app_state(pool, testing::config());
+        let keys = [ThrottleKey::Account("alice"), ThrottleKey::Ip("10.0.0.1")];
+        let backoff_after = app_state.config.login_throttle.backoff_after;
+
+        for _ in 0..backoff_after {
+            let _ = count_attempt(&app_state, &keys).await.unwrap();
+        }
+        let blocking = count_attempt(&app_state, &keys).await.unwrap();
+        assert!(count_attempt(&app_state, &keys).await.is_err());
+
+        blocking.forgive(&app_state).await.unwrap();
+
+        assert_eq!(
+            failures(&app_state, ACCOUNT, "alice").await,
+            backoff_after as i32
+        );
+        assert_eq!(
+            failures(&app_state, IP, "10.0.0.1").await,
+            backoff_after as i32
+        );
+        let _ = count_attempt(&app_state, &keys).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn sweep_keeps_recent_failures_and_blocks(pool: PgPool) {
+        let app_state = testing::app_state(pool, testing::config());
+        let window = app_state.config.login_throttle.failure_window as f64;
+
+        for (key, last_failure_ago, blocked_for) in [
+            ("forgotten", window + 60.0, None),
+            ("block-over", window + 60.0, Some(-60.0)),
+            ("recent", window - 60.0, None),
+            ("still-blocked", window + 60.0, Some(60.0)),
+        ] {
+            sqlx::query(
+                "
+					INSERT INTO login_throttles (kind, key, failures, last_failure_at, blocked_until)
+					VALUES ($1, $2, 3, NOW() - make_interval(secs => $3), NOW() + make_interval(secs => $4))
+				",
+            )
+            .bind(IP)
+            .bind(key)
+            .bind(last_failure_ago)
+            .bind(blocked_for)
+            .execute(&app_state.pool)
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(sweep(&app_state).await.unwrap(), 2);
+
+        let kept = sqlx::query_scalar::<_, String>("SELECT key FROM login_throttles ORDER BY key")
+            .fetch_all(&app_state.pool)
+            .await
+            .unwrap();
+        assert_eq!(kept, ["recent", "still-blocked"]);
+    }
+}
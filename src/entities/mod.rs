@@ -1,3 +1,5 @@
+pub mod admin;
 pub mod user;
 pub mod post;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod oauth;
\ No newline at end of file
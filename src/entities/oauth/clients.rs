@@ -0,0 +1,294 @@
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    common::{
+        AppState,
+        errors::api_error::ApiError,
+        tokens::{generate_opaque_token, hash_token},
+    },
+    entities::{
+        admin::audit_action,
+        oauth::{
+            GRANT_AUTHORIZATION_CODE, GRANT_CLIENT_CREDENTIALS, GRANT_REFRESH_TOKEN,
+            dto::{RegisterClientDto, RegisteredClientResponse, TokenRequest},
+            error::OAuthError,
+        },
+    },
+    models::{
+        auth::Claims,
+        oauth_client::{ClientType, OAuthClient},
+    },
+};
+
+const CLIENT_COLUMNS: &str =
+    "id, name, client_type, secret_hash, redirect_uris, scopes, grant_types, created_at";
+
+pub async fn find_client(
+    app_state: &AppState,
+    client_id: &str,
+) -> Result<Option<OAuthClient>, ApiError> {
+    let client = sqlx::query_as::<_, OAuthClient>(&format!(
+        "SELECT {CLIENT_COLUMNS} FROM oauth_clients WHERE id = $1"
+    ))
+    .bind(client_id)
+    .fetch_optional(&app_state.pool)
+    .await?;
+
+    Ok(client)
+}
+
+// loopback redirects may use plain http, anything else reachable over the network may not
+fn validate_redirect_uri(uri: &str) -> Result<(), String> {
+    let url = Url::parse(uri).map_err(|e| format!("Invalid redirect uri {uri}: {e}"))?;
+
+    if url.fragment().is_some() {
+        return Err(format!("Redirect uri {uri} must not contain a fragment"));
+    }
+    let loopback = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    if url.scheme() == "http" && !loopback {
+        return Err(format!("Redirect uri {uri} must use https"));
+    }
+
+    Ok(())
+}
+
+fn validate_registration(dto: &RegisterClientDto) -> Result<(), String> {
+    for grant_type in &dto.grant_types {
+        match grant_type.as_str() {
+            GRANT_AUTHORIZATION_CODE | GRANT_REFRESH_TOKEN => (),
+            GRANT_CLIENT_CREDENTIALS if dto.client_type == ClientType::Confidential => (),
+            GRANT_CLIENT_CREDENTIALS => {
+                return Err("Public clients can't use client_credentials".to_string());
+            }
+            other => return Err(format!("Unsupported grant type {other}")),
+        }
+    }
+
+    let uses_redirects = dto
+        .grant_types
+        .iter()
+        .any(|grant_type| grant_type == GRANT_AUTHORIZATION_CODE);
+    if uses_redirects && dto.redirect_uris.is_empty() {
+        return Err("authorization_code needs at least one redirect uri".to_string());
+    }
+    for uri in &dto.redirect_uris {
+        validate_redirect_uri(uri)?;
+    }
+
+    for scope in &dto.scopes {
+        let is_valid = !scope.is_empty()
+            && scope
+                .chars()
+                .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\');
+        if !is_valid {
+            return Err(format!("Invalid scope `{scope}`"));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn register_client(
+    req: HttpRequest,
+    dto: web::Json<RegisterClientDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    dto.validate().map_err(ApiError::Validation)?;
+    validate_registration(&dto).map_err(ApiError::Other)?;
+
+    let client_secret = (dto.client_type == ClientType::Confidential).then(generate_opaque_token);
+
+    let mut tx = app_state.pool.begin().await?;
+    let client = sqlx::query_as::<_, OAuthClient>(&format!(
+        "
+			INSERT INTO oauth_clients (id, name, client_type, secret_hash, redirect_uris, scopes, grant_types)
+			VALUES ($1, $2, $3, $4, $5, $6, $7)
+			RETURNING {CLIENT_COLUMNS}
+		"
+    ))
+    .bind(Uuid::new_v4().to_string())
+    .bind(&dto.name)
+    .bind(dto.client_type)
+    .bind(client_secret.as_deref().map(hash_token))
+    .bind(&dto.redirect_uris)
+    .bind(&dto.scopes)
+    .bind(&dto.grant_types)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.oauth_client.registered",
+        None,
+        json!({
+            "client_id": client.id,
+            "name": client.name,
+            "client_type": client.client_type,
+            "redirect_uris": client.redirect_uris,
+            "scopes": client.scopes,
+            "grant_types": client.grant_types,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(RegisteredClientResponse {
+        client,
+        client_secret,
+    }))
+}
+
+pub async fn list_clients(app_state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let clients = sqlx::query_as::<_, OAuthClient>(&format!(
+        "SELECT {CLIENT_COLUMNS} FROM oauth_clients ORDER BY created_at"
+    ))
+    .fetch_all(&app_state.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(clients))
+}
+
+// codes, consents and refresh tokens of the client go with it
+pub async fn delete_client(
+    req: HttpRequest,
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let client_id = path.into_inner();
+
+    let mut tx = app_state.pool.begin().await?;
+    let deleted = sqlx::query("DELETE FROM oauth_clients WHERE id = $1")
+        .bind(&client_id)
+        .execute(&mut *tx)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!(
+            "Client {} not found",
+            client_id
+        )));
+    }
+
+    audit_action(
+        &mut *tx,
+        &app_state,
+        &req,
+        &claims,
+        "admin.oauth_client.deleted",
+        None,
+        json!({ "client_id": client_id }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn basic_credentials(req: &HttpRequest) -> Result<Option<(String, String)>, OAuthError> {
+    let Some(value) = req.headers().get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let invalid = || OAuthError::invalid_client("Invalid Authorization header");
+
+    let encoded = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Basic "))
+        .ok_or_else(invalid)?;
+    let decoded = STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(invalid)?;
+    let (id, secret) = decoded.split_once(':').ok_or_else(invalid)?;
+
+    // both parts are form-urlencoded before they are joined (RFC 6749 2.3.1)
+    let form_urldecode = |value: &str| {
+        percent_decode_str(&value.replace('+', " "))
+            .decode_utf8()
+            .map(|value| value.into_owned())
+            .map_err(|_| invalid())
+    };
+
+    Ok(Some((form_urldecode(id)?, form_urldecode(secret)?)))
+}
+
+// client_secret_basic, client_secret_post, or just the client_id for public clients
+pub async fn authenticate_client(
+    app_state: &AppState,
+    req: &HttpRequest,
+    form: &TokenRequest,
+) -> Result<OAuthClient, OAuthError> {
+    let (client_id, secret) = match basic_credentials(req)? {
+        Some(_) if form.client_secret.is_some() => {
+            return Err(OAuthError::invalid_request(
+                "Only one client authentication method may be used",
+            ));
+        }
+        Some((id, secret)) => (id, Some(secret)),
+        None => (
+            form.client_id
+                .clone()
+                .ok_or_else(|| OAuthError::invalid_client("Client authentication failed"))?,
+            form.client_secret.clone(),
+        ),
+    };
+
+    let client = find_client(app_state, &client_id)
+        .await?
+        .ok_or_else(|| OAuthError::invalid_client("Client authentication failed"))?;
+
+    let authenticated = match (&client.client_type, &client.secret_hash, &secret) {
+        (ClientType::Confidential, Some(hash), Some(secret)) => *hash == hash_token(secret),
+        (ClientType::Public, _, None) => true,
+        _ => false,
+    };
+    if !authenticated {
+        return Err(OAuthError::invalid_client("Client authentication failed"));
+    }
+
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn with_basic_auth(credentials: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((
+                header::AUTHORIZATION,
+                format!("Basic {}", STANDARD.encode(credentials)),
+            ))
+            .to_http_request()
+    }
+
+    #[test]
+    fn basic_credentials_are_form_urldecoded() {
+        let credentials = basic_credentials(&with_basic_auth("my%3Aclient:a+s%C3%A9cret%2B%25"));
+
+        assert_eq!(
+            credentials.unwrap(),
+            Some(("my:client".to_string(), "a s\u{e9}cret+%".to_string()))
+        );
+    }
+
+    #[test]
+    fn malformed_basic_credentials_are_rejected() {
+        for credentials in ["no-separator", "client:%FF"] {
+            assert!(basic_credentials(&with_basic_auth(credentials)).is_err());
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use validator::Validate;
+
+use crate::models::oauth_client::{ClientType, OAuthClient};
+
+#[derive(serde::Deserialize, Validate)]
+pub struct RegisterClientDto {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1 to 100 chars long"))]
+    pub name: String,
+
+    pub client_type: ClientType,
+
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+
+    #[serde(default)]
+    pub scopes: Vec<String>,
+
+    #[validate(length(min = 1, message = "At least one grant type is required"))]
+    pub grant_types: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct RegisteredClientResponse {
+    pub client: OAuthClient,
+    // shown once, only its hash is stored
+    pub client_secret: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AuthorizationRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    // OpenID Connect, echoed back in the id_token
+    pub nonce: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AuthorizationDecisionDto {
+    #[serde(flatten)]
+    pub request: AuthorizationRequest,
+    // absent means "only if consent was given before"
+    pub approve: Option<bool>,
+}
+
+#[derive(serde::Serialize)]
+pub struct AuthorizationRedirect {
+    pub redirect_to: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct ConsentRequiredResponse {
+    pub consent_required: bool,
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+    // only when the openid scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct OpenIdConfiguration {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub response_types_supported: Vec<&'static str>,
+    pub grant_types_supported: Vec<&'static str>,
+    pub subject_types_supported: Vec<&'static str>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<&'static str>,
+    pub claims_supported: Vec<&'static str>,
+    pub token_endpoint_auth_methods_supported: Vec<&'static str>,
+    pub code_challenge_methods_supported: Vec<&'static str>,
+}
+
+// claims are only present when their scope was granted
+#[derive(serde::Serialize)]
+pub struct UserInfoResponse {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
@@ -0,0 +1,108 @@
+use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use std::fmt;
+
+use crate::common::errors::api_error::ApiError;
+
+// Errors of the token endpoint, shaped as RFC 6749 expects
+// so that client libraries can read them
+#[derive(Debug)]
+pub struct OAuthError {
+    pub error: &'static str,
+    pub description: String,
+}
+
+#[derive(serde::Serialize)]
+struct OAuthErrorBody<'a> {
+    error: &'a str,
+    error_description: &'a str,
+}
+
+impl OAuthError {
+    pub fn new(error: &'static str, description: impl Into<String>) -> Self {
+        Self {
+            error,
+            description: description.into(),
+        }
+    }
+
+    pub fn invalid_request(description: impl Into<String>) -> Self {
+        Self::new("invalid_request", description)
+    }
+
+    pub fn invalid_client(description: impl Into<String>) -> Self {
+        Self::new("invalid_client", description)
+    }
+
+    pub fn invalid_grant(description: impl Into<String>) -> Self {
+        Self::new("invalid_grant", description)
+    }
+
+    pub fn unauthorized_client(description: impl Into<String>) -> Self {
+        Self::new("unauthorized_client", description)
+    }
+
+    pub fn invalid_scope(description: impl Into<String>) -> Self {
+        Self::new("invalid_scope", description)
+    }
+
+    // bearer token errors of the userinfo endpoint, RFC 6750
+    pub fn invalid_token(description: impl Into<String>) -> Self {
+        Self::new("invalid_token", description)
+    }
+
+    pub fn insufficient_scope(description: impl Into<String>) -> Self {
+        Self::new("insufficient_scope", description)
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.description)
+    }
+}
+
+impl ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        match self.error {
+            "invalid_client" | "invalid_token" => StatusCode::UNAUTHORIZED,
+            "insufficient_scope" => StatusCode::FORBIDDEN,
+            "server_error" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self.error {
+            "invalid_client" => {
+                response.insert_header(("WWW-Authenticate", "Basic realm=\"oauth\""));
+            }
+            "invalid_token" | "insufficient_scope" => {
+                response.insert_header((
+                    "WWW-Authenticate",
+                    format!("Bearer error=\"{}\"", self.error),
+                ));
+            }
+            _ => (),
+        }
+
+        response
+            .insert_header(("Cache-Control", "no-store"))
+            .json(OAuthErrorBody {
+                error: self.error,
+                error_description: &self.description,
+            })
+    }
+}
+
+impl From<ApiError> for OAuthError {
+    fn from(error: ApiError) -> Self {
+        Self::new("server_error", error.to_string())
+    }
+}
+
+impl From<sqlx::Error> for OAuthError {
+    fn from(error: sqlx::Error) -> Self {
+        ApiError::from(error).into()
+    }
+}
@@ -0,0 +1,1009 @@
+pub mod clients;
+pub mod dto;
+pub mod error;
+pub mod oidc;
+
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    common::{
+        AppState,
+        audit::Outcome,
+        errors::api_error::ApiError,
+        tokens::{generate_opaque_token, hash_token},
+    },
+    entities::{
+        auth::{account_status, jwt, record_auth_event, refresh_store},
+        oauth::{
+            clients::{authenticate_client, find_client},
+            dto::{
+                AuthorizationDecisionDto, AuthorizationRedirect, AuthorizationRequest,
+                ConsentRequiredResponse, TokenRequest, TokenResponse,
+            },
+            error::OAuthError,
+            oidc::SCOPE_OPENID,
+        },
+        user::find_user_by_id,
+    },
+    models::{
+        auth::Claims,
+        oauth_client::{ClientType, OAuthClient, OAuthConsent},
+        user::User,
+    },
+};
+
+pub const GRANT_AUTHORIZATION_CODE: &str = "authorization_code";
+pub const GRANT_REFRESH_TOKEN: &str = "refresh_token";
+pub const GRANT_CLIENT_CREDENTIALS: &str = "client_credentials";
+
+const CODE_TTL: i64 = 120;
+
+enum AuthorizeFailure {
+    // the client or its redirect uri can't be trusted, nothing is sent to it
+    Fatal(OAuthError),
+    // reported back to the client through its redirect uri
+    Redirect(String),
+}
+
+impl From<ApiError> for AuthorizeFailure {
+    fn from(error: ApiError) -> Self {
+        AuthorizeFailure::Fatal(error.into())
+    }
+}
+
+struct ValidatedAuthorization {
+    client: OAuthClient,
+    redirect_uri: String,
+    scopes: Vec<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct AuthorizationCode {
+    id: String,
+    client_id: String,
+    user_id: String,
+    redirect_uri: String,
+    scope: String,
+    code_challenge: Option<String>,
+    nonce: Option<String>,
+    auth_time: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+fn redirect_with(redirect_uri: &str, params: &[(&str, &str)], state: Option<&str>) -> String {
+    // redirect uris were validated on registration
+    let Ok(mut url) = Url::parse(redirect_uri) else {
+        return redirect_uri.to_string();
+    };
+
+    {
+        let mut query = url.query_pairs_mut();
+        for (key, value) in params {
+            query.append_pair(key, value);
+        }
+        if let Some(state) = state {
+            query.append_pair("state", state);
+        }
+    }
+
+    url.into()
+}
+
+fn error_redirect(
+    redirect_uri: &str,
+    error: &str,
+    description: &str,
+    state: Option<&str>,
+) -> AuthorizeFailure {
+    AuthorizeFailure::Redirect(redirect_with(
+        redirect_uri,
+        &[("error", error), ("error_description", description)],
+        state,
+    ))
+}
+
+// a missing scope means everything the client is allowed to ask for
+fn resolve_scopes(requested: Option<&str>, allowed: &[String]) -> Result<Vec<String>, String> {
+    let Some(requested) = requested.filter(|scope| !scope.trim().is_empty()) else {
+        return Ok(allowed.to_vec());
+    };
+
+    let mut scopes: Vec<String> = Vec::new();
+    for scope in requested.split_whitespace() {
+        if !allowed.iter().any(|allowed| allowed == scope) {
+            return Err(format!("Scope {scope} is not allowed for this client"));
+        }
+        if !scopes.iter().any(|known| known == scope) {
+            scopes.push(scope.to_string());
+        }
+    }
+
+    Ok(scopes)
+}
+
+async fn validate_request(
+    app_state: &AppState,
+    request: &AuthorizationRequest,
+) -> Result<ValidatedAuthorization, AuthorizeFailure> {
+    let client = find_client(app_state, &request.client_id)
+        .await?
+        .ok_or_else(|| AuthorizeFailure::Fatal(OAuthError::invalid_client("Unknown client")))?;
+
+    // exact match only, a client with several uris has to say which one
+    let redirect_uri = match &request.redirect_uri {
+        Some(uri) if client.redirect_uris.contains(uri) => uri.clone(),
+        None if client.redirect_uris.len() == 1 => client.redirect_uris[0].clone(),
+        _ => {
+            return Err(AuthorizeFailure::Fatal(OAuthError::invalid_request(
+                "redirect_uri is not registered for this client",
+            )));
+        }
+    };
+    let state = request.state.as_deref();
+
+    if request.response_type != "code" {
+        return Err(error_redirect(
+            &redirect_uri,
+            "unsupported_response_type",
+            "Only the code response type is supported",
+            state,
+        ));
+    }
+    if !client.allows_grant(GRANT_AUTHORIZATION_CODE) {
+        return Err(error_redirect(
+            &redirect_uri,
+            "unauthorized_client",
+            "Client may not use the authorization code grant",
+            state,
+        ));
+    }
+
+    match (
+        &request.code_challenge,
+        request.code_challenge_method.as_deref(),
+    ) {
+        (None, _) if client.client_type == ClientType::Public => {
+            return Err(error_redirect(
+                &redirect_uri,
+                "invalid_request",
+                "Public clients must use PKCE",
+                state,
+            ));
+        }
+        (Some(_), Some(method)) if method != "S256" => {
+            return Err(error_redirect(
+                &redirect_uri,
+                "invalid_request",
+                "Only the S256 code challenge method is supported",
+                state,
+            ));
+        }
+        (Some(_), None) => {
+            return Err(error_redirect(
+                &redirect_uri,
+                "invalid_request",
+                "code_challenge_method must be S256",
+                state,
+            ));
+        }
+        _ => (),
+    }
+
+    let scopes = resolve_scopes(request.scope.as_deref(), &client.scopes)
+        .map_err(|e| error_redirect(&redirect_uri, "invalid_scope", &e, state))?;
+    if scopes.iter().any(|scope| scope == SCOPE_OPENID) && !oidc::is_available(app_state) {
+        return Err(error_redirect(
+            &redirect_uri,
+            "invalid_scope",
+            "OpenID Connect is not available",
+            state,
+        ));
+    }
+
+    Ok(ValidatedAuthorization {
+        client,
+        redirect_uri,
+        scopes,
+    })
+}
+
+// The browser arrives here from the client. Login and consent happen on the frontend,
+// which then comes back to POST /oauth/authorize with the same parameters
+pub async fn authorize(
+    req: HttpRequest,
+    request: web::Query<AuthorizationRequest>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, OAuthError> {
+    let location = match validate_request(&app_state, &request).await {
+        Ok(_) => format!(
+            "{}/oauth/authorize?{}",
+            app_state.config.frontend_url,
+            req.query_string()
+        ),
+        Err(AuthorizeFailure::Redirect(url)) => url,
+        Err(AuthorizeFailure::Fatal(e)) => return Err(e),
+    };
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish())
+}
+
+pub async fn decide(
+    dto: web::Json<AuthorizationDecisionDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, OAuthError> {
+    let request = &dto.request;
+    let state = request.state.as_deref();
+
+    let authorization = match validate_request(&app_state, request).await {
+        Ok(authorization) => authorization,
+        Err(AuthorizeFailure::Redirect(redirect_to)) => {
+            return Ok(HttpResponse::Ok().json(AuthorizationRedirect { redirect_to }));
+        }
+        Err(AuthorizeFailure::Fatal(e)) => return Err(e),
+    };
+    let client = &authorization.client;
+
+    if dto.approve == Some(false) {
+        return Ok(HttpResponse::Ok().json(AuthorizationRedirect {
+            redirect_to: redirect_with(
+                &authorization.redirect_uri,
+                &[
+                    ("error", "access_denied"),
+                    ("error_description", "The user denied the request"),
+                ],
+                state,
+            ),
+        }));
+    }
+
+    let granted = sqlx::query_scalar::<_, Vec<String>>(
+        "SELECT scopes FROM oauth_consents WHERE user_id = $1 AND client_id = $2",
+    )
+    .bind(&claims.sub)
+    .bind(&client.id)
+    .fetch_optional(&app_state.pool)
+    .await?
+    .unwrap_or_default();
+    let is_covered = authorization
+        .scopes
+        .iter()
+        .all(|scope| granted.contains(scope));
+
+    if dto.approve.is_none() && !is_covered {
+        return Ok(HttpResponse::Ok().json(ConsentRequiredResponse {
+            consent_required: true,
+            client_id: client.id.clone(),
+            client_name: client.name.clone(),
+            scopes: authorization.scopes,
+        }));
+    }
+
+    // consent only grows, withdrawing it is a separate action
+    if !is_covered {
+        sqlx::query(
+            "
+				INSERT INTO oauth_consents (user_id, client_id, scopes)
+				VALUES ($1, $2, $3)
+				ON CONFLICT (user_id, client_id) DO UPDATE
+				SET scopes = ARRAY(SELECT DISTINCT unnest(oauth_consents.scopes || EXCLUDED.scopes)),
+					updated_at = NOW()
+			",
+        )
+        .bind(&claims.sub)
+        .bind(&client.id)
+        .bind(&authorization.scopes)
+        .execute(&app_state.pool)
+        .await?;
+    }
+
+    // tokens from before auth_time was tracked only know when they were refreshed
+    let auth_time = DateTime::from_timestamp(claims.auth_time.unwrap_or(claims.iat) as i64, 0)
+        .unwrap_or_else(Utc::now);
+
+    let code = generate_opaque_token();
+    sqlx::query(
+        "
+			INSERT INTO oauth_authorization_codes
+				(id, code_hash, client_id, user_id, redirect_uri, scope, code_challenge, nonce,
+				auth_time, expires_at)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+		",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(hash_token(&code))
+    .bind(&client.id)
+    .bind(&claims.sub)
+    .bind(&authorization.redirect_uri)
+    .bind(authorization.scopes.join(" "))
+    .bind(&request.code_challenge)
+    .bind(&request.nonce)
+    .bind(auth_time)
+    .bind(Utc::now() + Duration::seconds(CODE_TTL))
+    .execute(&app_state.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(AuthorizationRedirect {
+        redirect_to: redirect_with(&authorization.redirect_uri, &[("code", &code)], state),
+    }))
+}
+
+fn verify_pkce(challenge: &str, verifier: Option<&str>) -> Result<(), OAuthError> {
+    let verifier =
+        verifier.ok_or_else(|| OAuthError::invalid_request("code_verifier is required"))?;
+
+    let is_well_formed = (43..=128).contains(&verifier.len())
+        && verifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~'));
+    let computed = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    if !is_well_formed || computed != challenge {
+        return Err(OAuthError::invalid_grant("PKCE verification failed"));
+    }
+
+    Ok(())
+}
+
+fn token_response(
+    app_state: &AppState,
+    access_token: String,
+    refresh_token: Option<String>,
+    scope: String,
+    id_token: Option<String>,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((header::PRAGMA, "no-cache"))
+        .json(TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: app_state.config.tokens.access_ttl,
+            refresh_token,
+            scope,
+            id_token,
+        })
+}
+
+// a grant of a suspended or banned account is no good until it's active again
+async fn find_grant_user(app_state: &AppState, user_id: &str) -> Result<User, OAuthError> {
+    let user = match find_user_by_id(user_id, &app_state.pool).await {
+        Ok(user) => user,
+        Err(ApiError::NotFound(_)) => {
+            return Err(OAuthError::invalid_grant("User no longer exists"));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match account_status::ensure_active(user.status, user.suspended_until) {
+        Ok(()) => Ok(user),
+        Err(ApiError::Forbidden(reason)) => Err(OAuthError::invalid_grant(reason)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn exchange_code(
+    app_state: &AppState,
+    req: &HttpRequest,
+    client: &OAuthClient,
+    form: &TokenRequest,
+) -> Result<HttpResponse, OAuthError> {
+    let presented = form
+        .code
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("code is required"))?;
+    let code_hash = hash_token(presented);
+
+    // burnt before anything else, so a code can't be redeemed twice concurrently
+    let code = sqlx::query_as::<_, AuthorizationCode>(
+        "
+			UPDATE oauth_authorization_codes
+			SET used_at = NOW()
+			WHERE code_hash = $1 AND used_at IS NULL
+			RETURNING id, client_id, user_id, redirect_uri, scope, code_challenge, nonce, auth_time,
+				expires_at
+		",
+    )
+    .bind(&code_hash)
+    .fetch_optional(&app_state.pool)
+    .await?;
+
+    let Some(code) = code else {
+        // a replayed code may have leaked, whatever it was exchanged for is revoked
+        let family_id = sqlx::query_scalar::<_, Option<String>>(
+            "SELECT refresh_family_id FROM oauth_authorization_codes WHERE code_hash = $1",
+        )
+        .bind(&code_hash)
+        .fetch_optional(&app_state.pool)
+        .await?
+        .flatten();
+        if let Some(family_id) = family_id {
+            refresh_store::revoke_family(&app_state.pool, &family_id).await?;
+        }
+
+        return Err(OAuthError::invalid_grant("Invalid authorization code"));
+    };
+
+    if code.client_id != client.id || code.expires_at <= Utc::now() {
+        return Err(OAuthError::invalid_grant("Invalid authorization code"));
+    }
+    let redirect_uri_matches = match &form.redirect_uri {
+        Some(uri) => *uri == code.redirect_uri,
+        None => client.redirect_uris.len() == 1,
+    };
+    if !redirect_uri_matches {
+        return Err(OAuthError::invalid_grant("redirect_uri does not match"));
+    }
+    if let Some(challenge) = &code.code_challenge {
+        verify_pkce(challenge, form.code_verifier.as_deref())?;
+    }
+
+    let user = find_grant_user(app_state, &code.user_id).await?;
+    let access_token =
+        jwt::create_client_access_token(app_state, &client.id, Some(&user), &code.scope)?;
+
+    let refresh_token = if client.allows_grant(GRANT_REFRESH_TOKEN) {
+        // together, or a replayed code could miss the family it has to revoke
+        let mut tx = app_state.pool.begin().await?;
+        let issued = refresh_store::issue_for_client(
+            &mut tx,
+            &user.id,
+            &client.id,
+            &code.scope,
+            code.auth_time,
+            app_state.config.tokens.refresh_ttl,
+        )
+        .await?;
+
+        sqlx::query("UPDATE oauth_authorization_codes SET refresh_family_id = $1 WHERE id = $2")
+            .bind(&issued.family_id)
+            .bind(&code.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Some(issued.token)
+    } else {
+        None
+    };
+
+    let id_token = if oidc::has_scope(&code.scope, SCOPE_OPENID) {
+        Some(oidc::create_id_token(
+            app_state,
+            &client.id,
+            &user.id,
+            code.auth_time,
+            code.nonce.as_deref(),
+            &access_token,
+        )?)
+    } else {
+        None
+    };
+
+    record_grant(
+        app_state,
+        req,
+        client,
+        GRANT_AUTHORIZATION_CODE,
+        Some(&user.id),
+        &code.scope,
+    )
+    .await;
+    Ok(token_response(
+        app_state,
+        access_token,
+        refresh_token,
+        code.scope,
+        id_token,
+    ))
+}
+
+async fn refresh(
+    app_state: &AppState,
+    req: &HttpRequest,
+    client: &OAuthClient,
+    form: &TokenRequest,
+) -> Result<HttpResponse, OAuthError> {
+    let presented = form
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| OAuthError::invalid_request("refresh_token is required"))?;
+
+    // the new access token may be narrower than the original grant, never wider.
+    // Checked before rotating so a rejected request doesn't burn the token
+    let scope =
+        match refresh_store::find_active(&app_state.pool, presented, Some(&client.id)).await? {
+            Some(current) => {
+                let granted: Vec<String> = current
+                    .scope
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect();
+                resolve_scopes(form.scope.as_deref(), &granted)
+                    .map_err(OAuthError::invalid_scope)?
+                    .join(" ")
+            }
+            // rotate reports why the token can't be used
+            None => String::new(),
+        };
+
+    let rotated = refresh_store::rotate(
+        &app_state.pool,
+        presented,
+        Some(&client.id),
+        app_state.config.tokens.refresh_ttl,
+    )
+    .await
+    .map_err(|e| match e {
+        ApiError::Unauthorized(message) => OAuthError::invalid_grant(message),
+        e => e.into(),
+    })?;
+
+    let user = find_grant_user(app_state, &rotated.user_id).await?;
+    let access_token = jwt::create_client_access_token(app_state, &client.id, Some(&user), &scope)?;
+
+    // a refreshed id_token has no nonce, the client didn't send one
+    let id_token = if oidc::has_scope(&scope, SCOPE_OPENID) {
+        Some(oidc::create_id_token(
+            app_state,
+            &client.id,
+            &user.id,
+            rotated.auth_time,
+            None,
+            &access_token,
+        )?)
+    } else {
+        None
+    };
+
+    record_grant(
+        app_state,
+        req,
+        client,
+        GRANT_REFRESH_TOKEN,
+        Some(&user.id),
+        &scope,
+    )
+    .await;
+    Ok(token_response(
+        app_state,
+        access_token,
+        Some(rotated.token),
+        scope,
+        id_token,
+    ))
+}
+
+async fn client_credentials(
+    app_state: &AppState,
+    req: &HttpRequest,
+    client: &OAuthClient,
+    form: &TokenRequest,
+) -> Result<HttpResponse, OAuthError> {
+    if client.client_type != ClientType::Confidential {
+        return Err(OAuthError::unauthorized_client(
+            "Public clients can't use client_credentials",
+        ));
+    }
+
+    let scope = resolve_scopes(form.scope.as_deref(), &client.scopes)
+        .map_err(OAuthError::invalid_scope)?
+        .join(" ");
+    let access_token = jwt::create_client_access_token(app_state, &client.id, None, &scope)?;
+
+    record_grant(
+        app_state,
+        req,
+        client,
+        GRANT_CLIENT_CREDENTIALS,
+        None,
+        &scope,
+    )
+    .await;
+    Ok(token_response(app_state, access_token, None, scope, None))
+}
+
+// tokens handed to a client, for a user or, without one, for the client itself
+async fn record_grant(
+    app_state: &AppState,
+    req: &HttpRequest,
+    client: &OAuthClient,
+    grant_type: &str,
+    user_id: Option<&str>,
+    scope: &str,
+) {
+    record_auth_event(
+        app_state,
+        req,
+        "oauth.token",
+        user_id,
+        Outcome::Success,
+        json!({ "client_id": client.id, "grant_type": grant_type, "scope": scope }),
+    )
+    .await;
+}
+
+async fn grant(
+    app_state: &AppState,
+    req: &HttpRequest,
+    client: &OAuthClient,
+    form: &TokenRequest,
+) -> Result<HttpResponse, OAuthError> {
+    let grant_type = form.grant_type.as_str();
+    if !matches!(
+        grant_type,
+        GRANT_AUTHORIZATION_CODE | GRANT_REFRESH_TOKEN | GRANT_CLIENT_CREDENTIALS
+    ) {
+        return Err(OAuthError::new(
+            "unsupported_grant_type",
+            format!("Grant type {grant_type} is not supported"),
+        ));
+    }
+    if !client.allows_grant(grant_type) {
+        return Err(OAuthError::unauthorized_client(format!(
+            "Client may not use the {grant_type} grant"
+        )));
+    }
+
+    match grant_type {
+        GRANT_AUTHORIZATION_CODE => exchange_code(app_state, req, client, form).await,
+        GRANT_REFRESH_TOKEN => refresh(app_state, req, client, form).await,
+        _ => client_credentials(app_state, req, client, form).await,
+    }
+}
+
+// refusals are audited against the client, the user of a refused grant is unknown
+pub async fn token(
+    req: HttpRequest,
+    form: web::Form<TokenRequest>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, OAuthError> {
+    let (client_id, granted) = match authenticate_client(&app_state, &req, &form).await {
+        Ok(client) => {
+            let granted = grant(&app_state, &req, &client, &form).await;
+            (Some(client.id), granted)
+        }
+        Err(e) => (form.client_id.clone(), Err(e)),
+    };
+
+    if let Err(e) = &granted {
+        record_auth_event(
+            &app_state,
+            &req,
+            "oauth.token",
+            None,
+            Outcome::Failure,
+            json!({
+                "client_id": client_id,
+                "grant_type": form.grant_type,
+                "error": e.error,
+                "reason": e.description,
+            }),
+        )
+        .await;
+    }
+
+    granted
+}
+
+pub async fn list_consents(
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let consents = sqlx::query_as::<_, OAuthConsent>(
+        "
+			SELECT c.client_id, oc.name AS client_name, c.scopes, c.created_at, c.updated_at
+			FROM oauth_consents c
+			JOIN oauth_clients oc ON oc.id = c.client_id
+			WHERE c.user_id = $1
+			ORDER BY c.created_at
+		",
+    )
+    .bind(&claims.sub)
+    .fetch_all(&app_state.pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(consents))
+}
+
+// the client loses its refresh tokens, access tokens it holds expire on their own
+pub async fn revoke_consent(
+    path: web::Path<String>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let client_id = path.into_inner();
+
+    let deleted = sqlx::query("DELETE FROM oauth_consents WHERE user_id = $1 AND client_id = $2")
+        .bind(&claims.sub)
+        .bind(&client_id)
+        .execute(&app_state.pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!(
+            "Consent for {} not found",
+            client_id
+        )));
+    }
+
+    refresh_store::revoke_for_client(&app_state.pool, &claims.sub, &client_id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{common::testing, entities::oauth::dto::AuthorizationDecisionDto};
+
+    const REDIRECT_URI: &str = "https://client.example.com/callback";
+    const OTHER_REDIRECT_URI: &str = "https://client.example.com/other";
+    const CODE_VERIFIER: &str = "a-code-verifier-long-enough-to-be-accepted-by-pkce";
+
+    // registered straight into the table, so grants registration refuses can be set up too
+    async fn insert_client(
+        app_state: &AppState,
+        client_type: ClientType,
+        grant_types: &[&str],
+    ) -> (String, Option<String>) {
+        let id = Uuid::new_v4().to_string();
+        let secret = (client_type == ClientType::Confidential).then(generate_opaque_token);
+
+        sqlx::query(
+            "
+				INSERT INTO oauth_clients (id, name, client_type, secret_hash, redirect_uris, scopes, grant_types)
+				VALUES ($1, $1, $2, $3, $4, $5, $6)
+			",
+        )
+        .bind(&id)
+        .bind(client_type)
+        .bind(secret.as_deref().map(hash_token))
+        .bind([REDIRECT_URI, OTHER_REDIRECT_URI])
+        .bind(["read", "write"])
+        .bind(grant_types)
+        .execute(&app_state.pool)
+        .await
+        .unwrap();
+
+        (id, secret)
+    }
+
+    // what the frontend posts once the user approved, the code comes back on the redirect
+    async fn authorize_code(
+        app_state: &web::Data<AppState>,
+        user: &User,
+        client_id: &str,
+        scope: &str,
+    ) -> String {
+        let dto = AuthorizationDecisionDto {
+            request: AuthorizationRequest {
+                response_type: "code".into(),
+                client_id: client_id.into(),
+                redirect_uri: Some(REDIRECT_URI.into()),
+                scope: Some(scope.into()),
+                state: None,
+                code_challenge: Some(URL_SAFE_NO_PAD.encode(Sha256::digest(CODE_VERIFIER))),
+                code_challenge_method: Some("S256".into()),
+                nonce: None,
+            },
+            approve: Some(true),
+        };
+        let response = decide(
+            web::Json(dto),
+            testing::signed_in(user).await,
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+
+        let body = testing::json_body(response).await;
+        let url = Url::parse(body["redirect_to"].as_str().unwrap()).unwrap();
+        url.query_pairs()
+            .find(|(key, _)| key == "code")
+            .map(|(_, value)| value.into_owned())
+            .unwrap()
+    }
+
+    fn token_request(grant_type: &str, client_id: &str) -> TokenRequest {
+        TokenRequest {
+            grant_type: grant_type.into(),
+            code: None,
+            redirect_uri: None,
+            code_verifier: None,
+            refresh_token: None,
+            scope: None,
+            client_id: Some(client_id.into()),
+            client_secret: None,
+        }
+    }
+
+    fn code_request(client_id: &str, code: &str) -> TokenRequest {
+        TokenRequest {
+            code: Some(code.into()),
+            redirect_uri: Some(REDIRECT_URI.into()),
+            code_verifier: Some(CODE_VERIFIER.into()),
+            ..token_request(GRANT_AUTHORIZATION_CODE, client_id)
+        }
+    }
+
+    async fn request_token(
+        app_state: &web::Data<AppState>,
+        form: TokenRequest,
+    ) -> Result<HttpResponse, OAuthError> {
+        token(
+            TestRequest::default().to_http_request(),
+            web::Form(form),
+            app_state.clone(),
+        )
+        .await
+    }
+
+    async fn signed_in_client(
+        pool: PgPool,
+        grant_types: &[&str],
+    ) -> (web::Data<AppState>, User, String) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        let (client_id, _) = insert_client(&app_state, ClientType::Public, grant_types).await;
+
+        (app_state, user, client_id)
+    }
+
+    fn assert_error<T>(result: Result<T, OAuthError>, error: &str) {
+        match result {
+            Err(e) => assert_eq!(e.error, error, "{e}"),
+            Ok(_) => panic!("expected {error}"),
+        }
+    }
+
+    #[sqlx::test]
+    async fn wrong_code_verifier_is_rejected(pool: PgPool) {
+        let (app_state, user, client_id) =
+            signed_in_client(pool, &[GRANT_AUTHORIZATION_CODE]).await;
+        let code = authorize_code(&app_state, &user, &client_id, "read").await;
+
+        let form = TokenRequest {
+            code_verifier: Some(format!("{CODE_VERIFIER}-tampered")),
+            ..code_request(&client_id, &code)
+        };
+        assert_error(request_token(&app_state, form).await, "invalid_grant");
+    }
+
+    #[sqlx::test]
+    async fn replayed_code_revokes_what_it_was_exchanged_for(pool: PgPool) {
+        let (app_state, user, client_id) =
+            signed_in_client(pool, &[GRANT_AUTHORIZATION_CODE, GRANT_REFRESH_TOKEN]).await;
+        let code = authorize_code(&app_state, &user, &client_id, "read").await;
+
+        let response = request_token(&app_state, code_request(&client_id, &code))
+            .await
+            .unwrap();
+        let body = testing::json_body(response).await;
+        assert_eq!(body["scope"], "read");
+
+        assert_error(
+            request_token(&app_state, code_request(&client_id, &code)).await,
+            "invalid_grant",
+        );
+        let form = TokenRequest {
+            refresh_token: body["refresh_token"].as_str().map(str::to_string),
+            ..token_request(GRANT_REFRESH_TOKEN, &client_id)
+        };
+        assert_error(request_token(&app_state, form).await, "invalid_grant");
+    }
+
+    #[sqlx::test]
+    async fn redirect_uri_has_to_match_the_authorization(pool: PgPool) {
+        let (app_state, user, client_id) =
+            signed_in_client(pool, &[GRANT_AUTHORIZATION_CODE]).await;
+
+        // registered for the client, but not the one the code was issued for
+        let code = authorize_code(&app_state, &user, &client_id, "read").await;
+        let form = TokenRequest {
+            redirect_uri: Some(OTHER_REDIRECT_URI.into()),
+            ..code_request(&client_id, &code)
+        };
+        assert_error(request_token(&app_state, form).await, "invalid_grant");
+
+        // the client has several uris, it has to say which one
+        let code = authorize_code(&app_state, &user, &client_id, "read").await;
+        let form = TokenRequest {
+            redirect_uri: None,
+            ..code_request(&client_id, &code)
+        };
+        assert_error(request_token(&app_state, form).await, "invalid_grant");
+    }
+
+    #[sqlx::test]
+    async fn refresh_can_narrow_the_scope_but_not_widen_it(pool: PgPool) {
+        let (app_state, user, client_id) =
+            signed_in_client(pool, &[GRANT_AUTHORIZATION_CODE, GRANT_REFRESH_TOKEN]).await;
+        let code = authorize_code(&app_state, &user, &client_id, "read").await;
+        let response = request_token(&app_state, code_request(&client_id, &code))
+            .await
+            .unwrap();
+        let refresh_token = testing::json_body(response).await["refresh_token"]
+            .as_str()
+            .map(str::to_string);
+
+        let refresh = |scope: &str| TokenRequest {
+            refresh_token: refresh_token.clone(),
+            scope: Some(scope.into()),
+            ..token_request(GRANT_REFRESH_TOKEN, &client_id)
+        };
+        // write is allowed for the client, but the user never granted it
+        assert_error(
+            request_token(&app_state, refresh("read write")).await,
+            "invalid_scope",
+        );
+
+        // the rejected request didn't burn the token
+        let response = request_token(&app_state, refresh("read")).await.unwrap();
+        assert_eq!(testing::json_body(response).await["scope"], "read");
+    }
+
+    #[sqlx::test]
+    async fn refresh_token_of_another_client_is_rejected(pool: PgPool) {
+        let grants = [GRANT_AUTHORIZATION_CODE, GRANT_REFRESH_TOKEN];
+        let (app_state, user, client_id) = signed_in_client(pool, &grants).await;
+        let (other_client_id, _) = insert_client(&app_state, ClientType::Public, &grants).await;
+        let code = authorize_code(&app_state, &user, &client_id, "read").await;
+        let response = request_token(&app_state, code_request(&client_id, &code))
+            .await
+            .unwrap();
+        let refresh_token = testing::json_body(response).await["refresh_token"]
+            .as_str()
+            .map(str::to_string);
+
+        let form = TokenRequest {
+            refresh_token: refresh_token.clone(),
+            ..token_request(GRANT_REFRESH_TOKEN, &other_client_id)
+        };
+        assert_error(request_token(&app_state, form).await, "invalid_grant");
+
+        let form = TokenRequest {
+            refresh_token,
+            ..token_request(GRANT_REFRESH_TOKEN, &client_id)
+        };
+        assert!(request_token(&app_state, form).await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn public_client_cannot_use_client_credentials(pool: PgPool) {
+        let (app_state, _, client_id) = signed_in_client(pool, &[GRANT_CLIENT_CREDENTIALS]).await;
+
+        assert_error(
+            request_token(
+                &app_state,
+                token_request(GRANT_CLIENT_CREDENTIALS, &client_id),
+            )
+            .await,
+            "unauthorized_client",
+        );
+
+        let (client_id, secret) = insert_client(
+            &app_state,
+            ClientType::Confidential,
+            &[GRANT_CLIENT_CREDENTIALS],
+        )
+        .await;
+        let form = TokenRequest {
+            client_secret: secret,
+            ..token_request(GRANT_CLIENT_CREDENTIALS, &client_id)
+        };
+        let response = request_token(&app_state, form).await.unwrap();
+        assert_eq!(testing::json_body(response).await["scope"], "read write");
+    }
+}
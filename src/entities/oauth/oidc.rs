@@ -0,0 +1,323 @@
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::Algorithm;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{
+    common::{AppState, errors::api_error::ApiError},
+    entities::{
+        auth::jwt::verify_jwt,
+        oauth::{
+            GRANT_AUTHORIZATION_CODE, GRANT_CLIENT_CREDENTIALS, GRANT_REFRESH_TOKEN,
+            dto::{OpenIdConfiguration, UserInfoResponse},
+            error::OAuthError,
+        },
+        user::find_user_by_id,
+    },
+    models::{auth::IdTokenClaims, user::User},
+};
+
+pub const SCOPE_OPENID: &str = "openid";
+pub const SCOPE_PROFILE: &str = "profile";
+pub const SCOPE_EMAIL: &str = "email";
+
+// Id tokens are verified by clients with the published keys. A shared HS256 secret
+// can't be published, and client secrets are only stored hashed
+pub fn is_available(app_state: &AppState) -> bool {
+    app_state.keys.algorithm() != Algorithm::HS256
+}
+
+pub fn has_scope(scope: &str, wanted: &str) -> bool {
+    scope.split_whitespace().any(|scope| scope == wanted)
+}
+
+// left half of the access token hash, with the hash of the signing algorithm
+fn access_token_hash(algorithm: Algorithm, access_token: &str) -> String {
+    let digest = match algorithm {
+        Algorithm::EdDSA => Sha512::digest(access_token.as_bytes()).to_vec(),
+        _ => Sha256::digest(access_token.as_bytes()).to_vec(),
+    };
+
+    URL_SAFE_NO_PAD.encode(&digest[..digest.len() / 2])
+}
+
+pub fn create_id_token(
+    app_state: &AppState,
+    client_id: &str,
+    user_id: &str,
+    auth_time: DateTime<Utc>,
+    nonce: Option<&str>,
+    access_token: &str,
+) -> Result<String, ApiError> {
+    let now = Utc::now().timestamp();
+    let claims = IdTokenClaims {
+        iss: app_state.config.public_url.clone(),
+        sub: user_id.to_owned(),
+        aud: client_id.to_owned(),
+        iat: now as usize,
+        exp: (now + app_state.config.tokens.access_ttl) as usize,
+        auth_time: auth_time.timestamp() as usize,
+        nonce: nonce.map(str::to_string),
+        at_hash: access_token_hash(app_state.keys.algorithm(), access_token),
+    };
+
+    app_state
+        .keys
+        .sign(&claims)
+        .map_err(|e| ApiError::Other(format!("Error when trying to generate id token {:?}", e)))
+}
+
+pub async fn discovery(app_state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if !is_available(&app_state) {
+        return Err(ApiError::NotFound(
+            "OpenID Connect needs an asymmetric JWT signing key".into(),
+        ));
+    }
+
+    let issuer = &app_state.config.public_url;
+
+    Ok(HttpResponse::Ok().json(OpenIdConfiguration {
+        issuer: issuer.clone(),
+        authorization_endpoint: format!("{issuer}/oauth/authorize"),
+        token_endpoint: format!("{issuer}/oauth/token"),
+        userinfo_endpoint: format!("{issuer}/userinfo"),
+        jwks_uri: format!("{issuer}/.well-known/jwks.json"),
+        response_types_supported: vec!["code"],
+        grant_types_supported: vec![
+            GRANT_AUTHORIZATION_CODE,
+            GRANT_REFRESH_TOKEN,
+            GRANT_CLIENT_CREDENTIALS,
+        ],
+        subject_types_supported: vec!["public"],
+        id_token_signing_alg_values_supported: vec![format!("{:?}", app_state.keys.algorithm())],
+        scopes_supported: vec![SCOPE_OPENID, SCOPE_PROFILE, SCOPE_EMAIL],
+        claims_supported: vec![
+            "iss",
+            "sub",
+            "aud",
+            "iat",
+            "exp",
+            "auth_time",
+            "nonce",
+            "at_hash",
+            "preferred_username",
+            "email",
+            "email_verified",
+        ],
+        token_endpoint_auth_methods_supported: vec![
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ],
+        code_challenge_methods_supported: vec!["S256"],
+    }))
+}
+
+fn user_info(user: User, scope: &str) -> UserInfoResponse {
+    let profile = has_scope(scope, SCOPE_PROFILE);
+    let email = has_scope(scope, SCOPE_EMAIL);
+
+    UserInfoResponse {
+        sub: user.id.clone(),
+        preferred_username: profile.then(|| user.username.clone()),
+        email_verified: email.then(|| user.is_email_verified()),
+        email: email.then_some(user.email),
+    }
+}
+
+// takes the access token a client got for the user, our own tokens have no scope
+pub async fn userinfo(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, OAuthError> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| OAuthError::invalid_token("Bearer token missing"))?;
+
+    let claims = verify_jwt(&app_state.keys, token)
+        .ok_or_else(|| OAuthError::invalid_token("Invalid or expired token"))?;
+    if app_state
+        .revocations
+        .is_revoked(&app_state.pool, &claims)
+        .await?
+    {
+        return Err(OAuthError::invalid_token("Token has been revoked"));
+    }
+
+    let scope = match (&claims.client_id, &claims.scope) {
+        (Some(_), Some(scope)) if has_scope(scope, SCOPE_OPENID) => scope,
+        _ => {
+            return Err(OAuthError::insufficient_scope(
+                "Token was not granted the openid scope",
+            ));
+        }
+    };
+
+    // client credentials tokens are on behalf of the client itself
+    let user = match find_user_by_id(&claims.sub, &app_state.pool).await {
+        Ok(user) => user,
+        Err(ApiError::NotFound(_)) => {
+            return Err(OAuthError::invalid_token("Token is not bound to a user"));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(user_info(user, scope)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        common::testing,
+        entities::auth::{jwt, keys::KeyStore},
+    };
+
+    const CLIENT_ID: &str = "client";
+
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/tests/fixtures/oauth/{name}", env!("CARGO_MANIFEST_DIR"));
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    // OIDC needs a key clients can verify with, `key` is RSA or Ed25519
+    fn app_state(pool: PgPool, key: &str) -> web::Data<AppState> {
+        let mut app_state = testing::app_state(pool, testing::config());
+        let public_keys = [("oidc".to_string(), fixture(&format!("{key}.pub.pem")))];
+        app_state.keys =
+            KeyStore::asymmetric("oidc", &fixture(&format!("{key}.pem")), &public_keys).unwrap();
+        web::Data::new(app_state)
+    }
+
+    // the claims as a client reads them, the signature is KeyStore's concern
+    fn payload(token: &str) -> Value {
+        let payload = token.split('.').nth(1).unwrap();
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap()
+    }
+
+    async fn userinfo_for(
+        app_state: &web::Data<AppState>,
+        token: &str,
+    ) -> Result<Value, OAuthError> {
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .to_http_request();
+        let response = userinfo(req, app_state.clone()).await?;
+
+        Ok(testing::json_body(response).await)
+    }
+
+    #[sqlx::test]
+    async fn id_token_carries_nonce_auth_time_and_at_hash(pool: PgPool) {
+        let app_state = app_state(pool, "rsa");
+        let auth_time = DateTime::from_timestamp(1_790_000_000, 0).unwrap();
+
+        let id_token = create_id_token(
+            &app_state,
+            CLIENT_ID,
+            "user-1",
+            auth_time,
+            Some("nonce-1"),
+            "an-access-token",
+        )
+        .unwrap();
+
+        let claims = payload(&id_token);
+        assert_eq!(claims["iss"], "http://127.0.0.1:8080");
+        assert_eq!(claims["aud"], CLIENT_ID);
+        assert_eq!(claims["sub"], "user-1");
+        assert_eq!(claims["nonce"], "nonce-1");
+        assert_eq!(claims["auth_time"], 1_790_000_000);
+        // left half of the SHA-256 of the access token, base64url without padding
+        let digest = Sha256::digest(b"an-access-token");
+        assert_eq!(claims["at_hash"], URL_SAFE_NO_PAD.encode(&digest[..16]));
+
+        let refreshed =
+            create_id_token(&app_state, CLIENT_ID, "user-1", auth_time, None, "other").unwrap();
+        assert!(payload(&refreshed).get("nonce").is_none());
+    }
+
+    #[sqlx::test]
+    async fn at_hash_of_an_ed25519_key_uses_sha_512(pool: PgPool) {
+        let app_state = app_state(pool, "provider");
+
+        let id_token = create_id_token(
+            &app_state,
+            CLIENT_ID,
+            "user-1",
+            Utc::now(),
+            None,
+            "an-access-token",
+        )
+        .unwrap();
+
+        let digest = Sha512::digest(b"an-access-token");
+        assert_eq!(
+            payload(&id_token)["at_hash"],
+            URL_SAFE_NO_PAD.encode(&digest[..32])
+        );
+    }
+
+    #[sqlx::test]
+    async fn userinfo_shares_what_the_scopes_cover(pool: PgPool) {
+        let app_state = app_state(pool, "rsa");
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        for (scope, username, email) in [
+            ("openid", None, None),
+            ("openid profile", Some("alice"), None),
+            ("openid email", None, Some("alice@example.com")),
+            (
+                "openid profile email",
+                Some("alice"),
+                Some("alice@example.com"),
+            ),
+        ] {
+            let token =
+                jwt::create_client_access_token(&app_state, CLIENT_ID, Some(&user), scope).unwrap();
+            let info = userinfo_for(&app_state, &token).await.unwrap();
+
+            assert_eq!(info["sub"], user.id.as_str(), "{scope}");
+            assert_eq!(info["preferred_username"].as_str(), username, "{scope}");
+            assert_eq!(info["email"].as_str(), email, "{scope}");
+            assert_eq!(
+                info["email_verified"].as_bool(),
+                email.map(|_| true),
+                "{scope}"
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn userinfo_needs_the_openid_scope(pool: PgPool) {
+        let app_state = app_state(pool, "rsa");
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let without_openid =
+            jwt::create_client_access_token(&app_state, CLIENT_ID, Some(&user), "profile email")
+                .unwrap();
+        // our own tokens have no scope at all
+        let session = jwt::SessionRef {
+            id: "session",
+            auth_time: Utc::now(),
+        };
+        let first_party = jwt::create_access_token(&app_state, &user, &session)
+            .await
+            .unwrap();
+
+        for token in [without_openid, first_party] {
+            let rejected = userinfo_for(&app_state, &token).await;
+            assert!(matches!(rejected, Err(e) if e.error == "insufficient_scope"));
+        }
+    }
+}
@@ -1,4 +1,79 @@
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer};
+use validator::{Validate, ValidationError};
 
 pub struct CheckUserExistsDto {
 	pub username_or_email: String,
-}
\ No newline at end of file
+}
+
+// tells a field sent as null, Some(None), from a missing one, None
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// only links a browser can load as an image
+fn validate_web_url(url: &str) -> Result<(), ValidationError> {
+	if url.starts_with("https://") || url.starts_with("http://") {
+		return Ok(());
+	}
+
+	Err(ValidationError::new("web_url").with_message("Avatar must be an http(s) url".into()))
+}
+
+// BCP 47 shaped, like `en`, `pt-BR` or `zh-Hant-TW`
+fn validate_locale(locale: &str) -> Result<(), ValidationError> {
+	let mut subtags = locale.split('-');
+	let language = subtags.next().unwrap_or_default();
+
+	let valid = locale.len() <= 35
+		&& (2..=3).contains(&language.len())
+		&& language.chars().all(|c| c.is_ascii_alphabetic())
+		&& subtags.all(|subtag| {
+			(1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+		});
+	if valid {
+		return Ok(());
+	}
+
+	Err(ValidationError::new("locale").with_message("Locale must be a language tag like en-US".into()))
+}
+
+fn validate_timezone(timezone: &str) -> Result<(), ValidationError> {
+	match timezone.parse::<Tz>() {
+		Ok(_) => Ok(()),
+		Err(_) => Err(ValidationError::new("timezone")
+			.with_message("Timezone must be an IANA name like Europe/Berlin".into())),
+	}
+}
+
+// Fields left out stay as they are, null clears them
+#[derive(serde::Deserialize, Validate)]
+pub struct UpdateProfileDto {
+	#[serde(default, deserialize_with = "nullable")]
+	#[validate(length(min = 1, max = 50, message = "Display name must be between 1 and 50 characters long"))]
+	pub display_name: Option<Option<String>>,
+
+	#[serde(default, deserialize_with = "nullable")]
+	#[validate(
+		url(message = "Avatar must be a valid url"),
+		length(max = 2048, message = "Avatar url must be at most 2048 characters long"),
+		custom(function = "validate_web_url")
+	)]
+	pub avatar_url: Option<Option<String>>,
+
+	#[serde(default, deserialize_with = "nullable")]
+	#[validate(length(max = 500, message = "Bio must be at most 500 characters long"))]
+	pub bio: Option<Option<String>>,
+
+	#[serde(default, deserialize_with = "nullable")]
+	#[validate(custom(function = "validate_locale"))]
+	pub locale: Option<Option<String>>,
+
+	#[serde(default, deserialize_with = "nullable")]
+	#[validate(custom(function = "validate_timezone"))]
+	pub timezone: Option<Option<String>>,
+}
@@ -1,16 +1,25 @@
 pub mod dto;
 
-use dto::CheckUserExistsDto;
+use actix_web::{HttpResponse, web};
+use dto::{CheckUserExistsDto, UpdateProfileDto};
 use sqlx::PgPool;
+use validator::Validate;
 
-use crate::{common::errors::api_error::ApiError, models::user::UserWithPassword};
+use crate::{
+    common::{AppState, errors::api_error::ApiError},
+    models::{
+        auth::Claims,
+        user::{User, UserWithPassword},
+    },
+};
 
 pub async fn check_user_exists(
     dto: CheckUserExistsDto,
     pool: &PgPool,
 ) -> Result<UserWithPassword, ApiError> {
     let query = r#"
-		SELECT id, username, email, password, role, created_at
+		SELECT id, username, email, password, role, email_verified_at, status, suspended_until,
+			display_name, avatar_url, bio, locale, timezone, created_at
 		FROM users 
 		WHERE username = $1 OR email = $1
 		LIMIT 1
@@ -29,3 +38,235 @@ pub async fn check_user_exists(
         Err(e) => Err(ApiError::InternalServer(e.to_string())),
     }
 }
+
+pub async fn find_user_by_id(id: &str, pool: &PgPool) -> Result<User, ApiError> {
+    let query = r#"
+		SELECT id, username, email, role, email_verified_at, status, suspended_until,
+			display_name, avatar_url, bio, locale, timezone
+		FROM users
+		WHERE id = $1
+	"#;
+
+    match sqlx::query_as::<_, User>(query)
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(user) => Ok(user),
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::NotFound(format!("User {} not found", id))),
+        Err(e) => Err(ApiError::InternalServer(e.to_string())),
+    }
+}
+
+pub async fn find_user_with_password_by_id(
+    id: &str,
+    pool: &PgPool,
+) -> Result<UserWithPassword, ApiError> {
+    let query = r#"
+		SELECT id, username, email, password, role, email_verified_at, status, suspended_until,
+			display_name, avatar_url, bio, locale, timezone
+		FROM users
+		WHERE id = $1
+	"#;
+
+    match sqlx::query_as::<_, UserWithPassword>(query)
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(user) => Ok(user),
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::NotFound(format!("User {} not found", id))),
+        Err(e) => Err(ApiError::InternalServer(e.to_string())),
+    }
+}
+
+pub async fn get_me(
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user = find_user_by_id(&claims.sub, &app_state.pool).await?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn update_me(
+    dto: web::Json<UpdateProfileDto>,
+    claims: web::ReqData<Claims>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    dto.validate().map_err(ApiError::Validation)?;
+    let dto = dto.into_inner();
+
+    // every field comes as a flag whether to set it and the value to set
+    let user = sqlx::query_as::<_, User>(
+        r#"
+		UPDATE users
+		SET display_name = CASE WHEN $2 THEN $3 ELSE display_name END,
+			avatar_url = CASE WHEN $4 THEN $5 ELSE avatar_url END,
+			bio = CASE WHEN $6 THEN $7 ELSE bio END,
+			locale = CASE WHEN $8 THEN $9 ELSE locale END,
+			timezone = CASE WHEN $10 THEN $11 ELSE timezone END
+		WHERE id = $1
+		RETURNING id, username, email, role, email_verified_at, status, suspended_until,
+			display_name, avatar_url, bio, locale, timezone
+	"#,
+    )
+    .bind(&claims.sub)
+    .bind(dto.display_name.is_some())
+    .bind(dto.display_name.flatten())
+    .bind(dto.avatar_url.is_some())
+    .bind(dto.avatar_url.flatten())
+    .bind(dto.bio.is_some())
+    .bind(dto.bio.flatten())
+    .bind(dto.locale.is_some())
+    .bind(dto.locale.flatten())
+    .bind(dto.timezone.is_some())
+    .bind(dto.timezone.flatten())
+    .fetch_optional(&app_state.pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("User {} not found", claims.sub)))?;
+
+    Ok(HttpResponse::Ok().json(user))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{ResponseError, http::StatusCode, test::TestRequest};
+    use serde_json::{Value, json};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        common::testing,
+        entities::auth::{dto::LoginDto, login},
+    };
+
+    // the body of a PATCH /me as it comes over the wire
+    fn patch(body: Value) -> web::Json<UpdateProfileDto> {
+        web::Json(serde_json::from_value(body).unwrap())
+    }
+
+    async fn update(
+        app_state: &web::Data<AppState>,
+        user: &User,
+        body: Value,
+    ) -> Result<Value, ApiError> {
+        let response = update_me(
+            patch(body),
+            testing::signed_in(user).await,
+            app_state.clone(),
+        )
+        .await?;
+        Ok(testing::json_body(response).await)
+    }
+
+    #[sqlx::test]
+    async fn me_is_the_signed_in_user(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        testing::insert_user(&app_state, "bob", "bob@example.com").await;
+
+        let response = get_me(testing::signed_in(&user).await, app_state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = testing::json_body(response).await;
+        assert_eq!(body["id"], user.id);
+        assert_eq!(body["username"], "alice");
+        assert_eq!(body["display_name"], Value::Null);
+        assert!(body.get("password").is_none());
+    }
+
+    #[sqlx::test]
+    async fn missing_fields_are_kept_and_null_clears(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        let body = update(
+            &app_state,
+            &user,
+            json!({
+                "display_name": "Alice",
+                "avatar_url": "https://example.com/alice.png",
+                "bio": "Reads a lot",
+                "locale": "pt-BR",
+                "timezone": "Europe/Berlin",
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(body["display_name"], "Alice");
+        assert_eq!(body["timezone"], "Europe/Berlin");
+
+        let body = update(&app_state, &user, json!({ "bio": null, "locale": "en" }))
+            .await
+            .unwrap();
+        assert_eq!(body["bio"], Value::Null);
+        assert_eq!(body["locale"], "en");
+
+        let stored = find_user_by_id(&user.id, &app_state.pool).await.unwrap();
+        assert_eq!(stored.display_name.as_deref(), Some("Alice"));
+        assert_eq!(
+            stored.avatar_url.as_deref(),
+            Some("https://example.com/alice.png")
+        );
+        assert_eq!(stored.bio, None);
+        assert_eq!(stored.locale.as_deref(), Some("en"));
+        assert_eq!(stored.timezone.as_deref(), Some("Europe/Berlin"));
+    }
+
+    #[sqlx::test]
+    async fn invalid_profile_fields_are_rejected(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+
+        for body in [
+            json!({ "locale": "english please" }),
+            json!({ "locale": "e" }),
+            json!({ "timezone": "Mars/Olympus_Mons" }),
+            json!({ "avatar_url": "javascript:alert(1)" }),
+            json!({ "avatar_url": "ftp://example.com/alice.png" }),
+        ] {
+            let error = update(&app_state, &user, body.clone()).await.unwrap_err();
+            assert!(matches!(error, ApiError::Validation(_)), "{body}");
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        }
+
+        let stored = find_user_by_id(&user.id, &app_state.pool).await.unwrap();
+        assert_eq!(stored.locale, None);
+        assert_eq!(stored.timezone, None);
+        assert_eq!(stored.avatar_url, None);
+    }
+
+    #[sqlx::test]
+    async fn sign_in_answers_with_the_profile(pool: PgPool) {
+        let app_state = web::Data::new(testing::app_state(pool, testing::config()));
+        let user = testing::insert_user(&app_state, "alice", "alice@example.com").await;
+        update(
+            &app_state,
+            &user,
+            json!({ "display_name": "Alice", "timezone": "America/Sao_Paulo" }),
+        )
+        .await
+        .unwrap();
+
+        let response = login(
+            TestRequest::default().to_http_request(),
+            web::Json(LoginDto {
+                username_or_email: "alice".into(),
+                password: "secret12".into(),
+            }),
+            app_state.clone(),
+        )
+        .await
+        .unwrap();
+
+        let body = testing::json_body(response).await;
+        assert_eq!(body["user"]["display_name"], "Alice");
+        assert_eq!(body["user"]["timezone"], "America/Sao_Paulo");
+        for field in ["avatar_url", "bio", "locale"] {
+            assert_eq!(body["user"][field], Value::Null, "{field}");
+        }
+    }
+}
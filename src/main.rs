@@ -1,16 +1,55 @@
 use actix_web::{
-    App, HttpResponse, HttpServer,
+    App, HttpServer, rt,
     web::{self},
 };
 use dotenv::dotenv;
 use rust_backend::{
     common::{
         AppState,
+        audit::AuditLogger,
+        config::Config,
+        mailer::create_mailer,
+        password_hasher::create_password_hashers,
+        rate_limit::create_rate_limit_store,
         database::{create_db_pool, run_migrations},
     },
     entities::{
-        auth::{guards::role_guard::RoleGuard, login, middlewares::jwt_auth::JwtAuth, register},
+        admin::{
+            ban_user, change_role, delete_user, force_password_reset, get_user, list_audit_events,
+            list_users, revoke_token, revoke_user_tokens, suspend_user, unlock_user,
+            unsuspend_user,
+        },
+        auth::{
+            account::{change_email, change_password},
+            account_status::AccountStatusCache,
+            email_verification::{resend_verification_email, verify_email},
+            guards::{permission_guard::RequirePermission, role_guard::RoleGuard},
+            jwt::{jwks, refresh_token},
+            keys::KeyStore,
+            login, logout, logout_all,
+            mfa::{confirm_totp, disable_totp, enroll_totp, verify_mfa},
+            middlewares::{jwt_auth::JwtAuth, rate_limit::RateLimit},
+            passkeys::{
+                create_webauthn, delete_credential, finish_login, finish_registration,
+                list_credentials, start_login, start_registration,
+            },
+            password_reset::{forgot_password, reset_password},
+            permissions,
+            register,
+            revocation::RevocationList,
+            sessions::{list_sessions, revoke_session},
+            social::{
+                authorize, callback, confirm_link, list_identities, providers::OAuthProviders,
+                start_link, unlink_identity,
+            },
+            throttle,
+        },
+        oauth::{
+            self,
+            clients::{delete_client, list_clients, register_client},
+        },
         post::{get_book, get_secret_book},
+        user::{get_me, update_me},
     },
     models::auth::UserRole,
 };
@@ -61,9 +100,9 @@ use std::io::Result as IoResult;
 //     HttpResponse::Ok().json(&*books)
 // }
 
-async fn test_handler() -> HttpResponse {
-    HttpResponse::Ok().body("Pong!")
-}
+// async fn test_handler() -> HttpResponse {
+//     HttpResponse::Ok().body("Pong!")
+// }
 
 // #[get("/")]
 // async fn hello_world() -> impl Responder {
@@ -74,28 +113,324 @@ async fn test_handler() -> HttpResponse {
 async fn main() -> IoResult<()> {
     dotenv().ok();
 
+    // Config, loaded before anything else so a misconfigured deploy never starts
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+
+    // JWT keys
+    let keys = KeyStore::from_config(&config.jwt).unwrap_or_else(|e| panic!("[JWT KEYS]: {e}"));
+
+    // Mail delivery
+    let mailer = create_mailer(&config.mail).unwrap_or_else(|e| panic!("[MAILER]: {e}"));
+
+    // Password hashing
+    let passwords = create_password_hashers(&config.password_hashing)
+        .unwrap_or_else(|e| panic!("[PASSWORD HASHING]: {e}"));
+
+    // Passkeys
+    let webauthn =
+        create_webauthn(&config.webauthn).unwrap_or_else(|e| panic!("[WEBAUTHN]: {e}"));
+
+    // Social login providers
+    let oauth =
+        OAuthProviders::new(&config.oauth_providers).unwrap_or_else(|e| panic!("[OAUTH]: {e}"));
+
     // Database Init
-    let pg_pool =
-        create_db_pool(&std::env::var("DATABASE_URL").expect("Database Url must be set")).await;
+    let pg_pool = create_db_pool(&config.database_url).await;
     run_migrations(&pg_pool).await;
 
-    // Is production?
-    let is_prod = std::env::var("APP_ENV").expect("Database Url must be set") == "production";
+    // Rate limit buckets
+    let rate_limits = create_rate_limit_store(config.rate_limit_store, &pg_pool);
+
+    let bind_address = config.bind_address.clone();
 
     // Setting app data
     let app_data = web::Data::new(AppState {
+        audit: AuditLogger::new(pg_pool.clone()),
         pool: pg_pool,
-        is_production: is_prod,
+        revocations: RevocationList::new(config.tokens.access_ttl),
+        account_statuses: AccountStatusCache::new(config.account_status.cache_ttl),
+        config,
+        keys,
+        mailer,
+        webauthn,
+        oauth,
+        rate_limits,
+        passwords,
+    });
+
+    // Login throttle rows of unknown identifiers and ips are never cleared by a login
+    let sweeper = app_data.clone();
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(throttle::SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = throttle::sweep(&sweeper).await {
+                eprintln!("[LOGIN THROTTLE]: {e}");
+            }
+        }
     });
 
     HttpServer::new(move || {
         App::new()
             .app_data(app_data.clone())
+            .route("/.well-known/jwks.json", web::get().to(jwks))
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(oauth::oidc::discovery),
+            )
+            .service(
+                web::resource("/userinfo")
+                    .route(web::get().to(oauth::oidc::userinfo))
+                    .route(web::post().to(oauth::oidc::userinfo)),
+            )
             // .service(
             // web::scope("")
-            .route("/register", web::post().to(register))
-            .route("/login", web::post().to(login))
+            .route(
+                "/register",
+                web::post()
+                    .to(register)
+                    .wrap(RateLimit::per_ip("register", 5, 60 * 60)),
+            )
+            .route(
+                "/login",
+                web::post()
+                    .to(login)
+                    .wrap(RateLimit::per_ip("login", 20, 60)),
+            )
             // )
+            .service(
+                web::scope("/auth")
+                    .route("/refresh", web::post().to(refresh_token))
+                    .route("/logout", web::post().to(logout))
+                    .route("/verify-email", web::post().to(verify_email))
+                    .route(
+                        "/verify-email/resend",
+                        web::post()
+                            .to(resend_verification_email)
+                            .wrap(RateLimit::per_ip("verify_email_resend", 5, 15 * 60)),
+                    )
+                    .route(
+                        "/password/forgot",
+                        web::post()
+                            .to(forgot_password)
+                            .wrap(RateLimit::per_ip("password_forgot", 5, 15 * 60)),
+                    )
+                    .route(
+                        "/password/reset",
+                        web::post()
+                            .to(reset_password)
+                            .wrap(RateLimit::per_ip("password_reset", 10, 15 * 60)),
+                    )
+                    .route(
+                        "/mfa/verify",
+                        web::post()
+                            .to(verify_mfa)
+                            .wrap(RateLimit::per_ip("mfa_verify", 10, 60)),
+                    )
+                    .route(
+                        "/passkeys/login/start",
+                        web::post()
+                            .to(start_login)
+                            .wrap(RateLimit::per_ip("passkeys_login", 20, 60)),
+                    )
+                    .route("/passkeys/login/finish", web::post().to(finish_login))
+                    .route("/oauth/link/confirm", web::post().to(confirm_link))
+                    .route("/oauth/{provider}/authorize", web::get().to(authorize))
+                    .route("/oauth/{provider}/callback", web::get().to(callback))
+                    // guarded one by one, a scope would answer unknown paths with 401
+                    .route("/logout-all", web::post().to(logout_all).wrap(JwtAuth))
+                    // both share the budget of the user, JwtAuth runs first to name them
+                    .route(
+                        "/password/change",
+                        web::post()
+                            .to(change_password)
+                            .wrap(RateLimit::per_user("account_change", 5, 15 * 60))
+                            .wrap(JwtAuth),
+                    )
+                    .route(
+                        "/email/change",
+                        web::post()
+                            .to(change_email)
+                            .wrap(RateLimit::per_user("account_change", 5, 15 * 60))
+                            .wrap(JwtAuth),
+                    )
+                    .route(
+                        "/mfa/totp/enroll",
+                        web::post().to(enroll_totp).wrap(JwtAuth),
+                    )
+                    // codes are guessed here too, both share the budget of the user
+                    .route(
+                        "/mfa/totp/confirm",
+                        web::post()
+                            .to(confirm_totp)
+                            .wrap(RateLimit::per_user("mfa_manage", 10, 15 * 60))
+                            .wrap(JwtAuth),
+                    )
+                    .route(
+                        "/mfa/totp/disable",
+                        web::post()
+                            .to(disable_totp)
+                            .wrap(RateLimit::per_user("mfa_manage", 10, 15 * 60))
+                            .wrap(JwtAuth),
+                    )
+                    .route("/passkeys", web::get().to(list_credentials).wrap(JwtAuth))
+                    .route(
+                        "/passkeys/{id}",
+                        web::delete().to(delete_credential).wrap(JwtAuth),
+                    )
+                    .route(
+                        "/passkeys/register/start",
+                        web::post().to(start_registration).wrap(JwtAuth),
+                    )
+                    .route(
+                        "/passkeys/register/finish",
+                        web::post().to(finish_registration).wrap(JwtAuth),
+                    )
+                    .route(
+                        "/oauth/identities",
+                        web::get().to(list_identities).wrap(JwtAuth),
+                    )
+                    .route(
+                        "/oauth/identities/{id}",
+                        web::delete().to(unlink_identity).wrap(JwtAuth),
+                    )
+                    .route(
+                        "/oauth/{provider}/link",
+                        web::post().to(start_link).wrap(JwtAuth),
+                    ),
+            )
+            .service(
+                web::scope("/me")
+                    .wrap(JwtAuth)
+                    .route("", web::get().to(get_me))
+                    .route("", web::patch().to(update_me))
+                    .route("/sessions", web::get().to(list_sessions))
+                    .route("/sessions/{id}", web::delete().to(revoke_session)),
+            )
+            // admins only, and each route also needs its own permission
+            .service(
+                web::scope("/admin")
+                    .wrap(RoleGuard {
+                        required_role: UserRole::Admin,
+                    })
+                    .wrap(JwtAuth)
+                    .route(
+                        "/tokens/revoke",
+                        web::post()
+                            .to(revoke_token)
+                            .wrap(RequirePermission(permissions::TOKENS_REVOKE)),
+                    )
+                    .route(
+                        "/users",
+                        web::get()
+                            .to(list_users)
+                            .wrap(RequirePermission(permissions::USERS_READ)),
+                    )
+                    .route(
+                        "/users/{id}",
+                        web::get()
+                            .to(get_user)
+                            .wrap(RequirePermission(permissions::USERS_READ)),
+                    )
+                    .route(
+                        "/users/{id}",
+                        web::delete()
+                            .to(delete_user)
+                            .wrap(RequirePermission(permissions::USERS_MANAGE)),
+                    )
+                    .route(
+                        "/users/{id}/role",
+                        web::put()
+                            .to(change_role)
+                            .wrap(RequirePermission(permissions::USERS_MANAGE)),
+                    )
+                    .route(
+                        "/users/{id}/suspend",
+                        web::post()
+                            .to(suspend_user)
+                            .wrap(RequirePermission(permissions::USERS_MANAGE)),
+                    )
+                    .route(
+                        "/users/{id}/ban",
+                        web::post()
+                            .to(ban_user)
+                            .wrap(RequirePermission(permissions::USERS_MANAGE)),
+                    )
+                    .route(
+                        "/users/{id}/unsuspend",
+                        web::post()
+                            .to(unsuspend_user)
+                            .wrap(RequirePermission(permissions::USERS_MANAGE)),
+                    )
+                    .route(
+                        "/users/{id}/force-password-reset",
+                        web::post()
+                            .to(force_password_reset)
+                            .wrap(RequirePermission(permissions::USERS_MANAGE)),
+                    )
+                    .route(
+                        "/users/{id}/revoke-tokens",
+                        web::post()
+                            .to(revoke_user_tokens)
+                            .wrap(RequirePermission(permissions::TOKENS_REVOKE)),
+                    )
+                    .route(
+                        "/users/{id}/unlock",
+                        web::post()
+                            .to(unlock_user)
+                            .wrap(RequirePermission(permissions::USERS_UNLOCK)),
+                    )
+                    .route(
+                        "/audit-events",
+                        web::get()
+                            .to(list_audit_events)
+                            .wrap(RequirePermission(permissions::AUDIT_READ)),
+                    )
+                    .route(
+                        "/oauth/clients",
+                        web::post()
+                            .to(register_client)
+                            .wrap(RequirePermission(permissions::OAUTH_CLIENTS_MANAGE)),
+                    )
+                    .route(
+                        "/oauth/clients",
+                        web::get()
+                            .to(list_clients)
+                            .wrap(RequirePermission(permissions::OAUTH_CLIENTS_MANAGE)),
+                    )
+                    .route(
+                        "/oauth/clients/{id}",
+                        web::delete()
+                            .to(delete_client)
+                            .wrap(RequirePermission(permissions::OAUTH_CLIENTS_MANAGE)),
+                    ),
+            )
+            .service(
+                web::scope("/oauth")
+                    .service(
+                        web::resource("/authorize")
+                            .route(web::get().to(oauth::authorize))
+                            .route(web::post().to(oauth::decide).wrap(JwtAuth)),
+                    )
+                    .route(
+                        "/token",
+                        web::post()
+                            .to(oauth::token)
+                            .wrap(RateLimit::per_ip("oauth_token", 60, 60)),
+                    )
+                    .service(
+                        web::scope("")
+                            .wrap(JwtAuth)
+                            .route("/consents", web::get().to(oauth::list_consents))
+                            .route(
+                                "/consents/{client_id}",
+                                web::delete().to(oauth::revoke_consent),
+                            ),
+                    ),
+            )
             .service(
                 web::scope("")
                     .wrap(JwtAuth)
@@ -106,7 +441,7 @@ async fn main() -> IoResult<()> {
                     ),
             )
     })
-    .bind("127.0.0.1:8080")?
+    .bind(bind_address)?
     .run()
     .await
 }
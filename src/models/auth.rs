@@ -18,12 +18,82 @@ impl UserRole {
 	pub fn is_user(&self) -> bool {
 		matches!(self, UserRole::User)
 	}
+
+	// name of the role in the roles table
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			UserRole::User => "USER",
+			UserRole::Admin => "ADMIN",
+		}
+	}
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Claims {
 	pub sub: String,
 	pub role: UserRole,
 	pub is_premium: bool,
+	pub email_verified: bool,
+	pub jti: String,
+	pub iat: usize,
+	pub exp: usize,
+	// when the user signed in, kept across refreshes
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub auth_time: Option<usize>,
+	// only on tokens issued to OAuth clients
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub client_id: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub scope: Option<String>,
+	// permissions of every role of the user when the token was issued
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub perms: Vec<String>,
+	// session the token was issued for, none on tokens of OAuth clients
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub sid: Option<String>,
+}
+
+impl Claims {
+	pub fn has_permission(&self, permission: &str) -> bool {
+		self.perms.iter().any(|perm| perm == permission)
+	}
+}
+
+// proof of a valid password while the second factor is still pending,
+// never accepted where Claims are expected
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MfaChallengeClaims {
+	pub sub: String,
+	pub purpose: String,
+	pub jti: String,
+	pub iat: usize,
 	pub exp: usize,
-}
\ No newline at end of file
+}
+
+// an external identity waiting for the owner of the matching account to accept it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OAuthLinkClaims {
+	pub sub: String,
+	pub purpose: String,
+	pub provider: String,
+	pub subject: String,
+	pub email: String,
+	pub jti: String,
+	pub iat: usize,
+	pub exp: usize,
+}
+
+// OpenID Connect id_token, tells a client who signed in. Has no role,
+// so it never decodes as Claims
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdTokenClaims {
+	pub iss: String,
+	pub sub: String,
+	pub aud: String,
+	pub iat: usize,
+	pub exp: usize,
+	pub auth_time: usize,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub nonce: Option<String>,
+	pub at_hash: String,
+}
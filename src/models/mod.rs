@@ -1,3 +1,8 @@
 
 pub mod auth;
+pub mod credential;
+pub mod external_identity;
+pub mod oauth_client;
+pub mod refresh_token;
+pub mod session;
 pub mod user;
\ No newline at end of file
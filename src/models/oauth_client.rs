@@ -0,0 +1,42 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::Type;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Type)]
+#[sqlx(type_name = "oauth_client_type", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ClientType {
+    // can keep a secret, e.g. a backend
+    Confidential,
+    // can't, e.g. a SPA or a mobile app, relies on PKCE
+    Public,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct OAuthClient {
+    #[serde(rename = "client_id")]
+    pub id: String,
+    pub name: String,
+    pub client_type: ClientType,
+    #[serde(skip_serializing)]
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OAuthClient {
+    pub fn allows_grant(&self, grant_type: &str) -> bool {
+        self.grant_types.iter().any(|allowed| allowed == grant_type)
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct OAuthConsent {
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
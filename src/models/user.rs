@@ -1,11 +1,38 @@
+use chrono::{DateTime, Utc};
+
 use crate::models::auth::UserRole;
 
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "account_status", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AccountStatus {
+    Active,
+    // until `suspended_until`, or until lifted when it's not set
+    Suspended,
+    Banned,
+    // signed up, but the email address has to be verified before logging in
+    PendingVerification,
+}
+
 #[derive(sqlx::FromRow, serde::Deserialize, serde::Serialize)]
 pub struct User {
     pub id: String,
     pub username: String,
     pub email: String,
     pub role: UserRole,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub status: AccountStatus,
+    pub suspended_until: Option<DateTime<Utc>>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+}
+
+impl User {
+    pub fn is_email_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
 }
 
 impl From<UserWithPassword> for User {
@@ -15,6 +42,14 @@ impl From<UserWithPassword> for User {
             username: value.username,
             email: value.email,
             role: value.role,
+            email_verified_at: value.email_verified_at,
+            status: value.status,
+            suspended_until: value.suspended_until,
+            display_name: value.display_name,
+            avatar_url: value.avatar_url,
+            bio: value.bio,
+            locale: value.locale,
+            timezone: value.timezone,
         }
     }
 }
@@ -26,4 +61,12 @@ pub struct UserWithPassword {
     pub password: String,
     pub email: String,
     pub role: UserRole,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub status: AccountStatus,
+    pub suspended_until: Option<DateTime<Utc>>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
 }